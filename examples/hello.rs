@@ -1,7 +1,5 @@
 extern crate pb;
 
-use std::env;
-
 fn main() {
     let msg = pb::PushMsg {
         title: Some("Hello, world!".into()),
@@ -9,8 +7,9 @@ fn main() {
         target: pb::TargetIden::CurrentUser,
         data: pb::PushData::Note,
         source_device_iden: None,
+        guid: None,
     };
 
-    let mut api = pb::PbAPI::new(&*env::var("PB_API_KEY").ok().expect("missing PB_API_KEY environment variable"));
+    let mut api = pb::PbAPI::from_default_profile().unwrap();
     api.send(&msg).unwrap();
 }
@@ -1,19 +1,33 @@
+use std::cmp;
 use std::error;
 use std::convert::From;
 use std::fmt;
-use std::io::Read;
+use std::io::{self, Read};
+use std::fs::File;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use std::sync::{Arc, Mutex};
 
 use hyper::client::Client;
+use hyper::method::Method;
 use hyper::header::{ContentType, Authorization, Basic};
-use hyper::error::Result as HttpResult;
+use hyper::status::StatusCode;
 use hyper::error::Error as HttpError;
 
-use rustc_serialize::json;
+use rustc_serialize::{json, Encodable};
+use url::Url;
+use uuid::Uuid;
 
-use objects::{Cursor, Timestamp, Error, PbObj, Iden, FromEnvelope, Envelope};
-use messages::PbMsg;
+use objects::{Cursor, Timestamp, Error, PbObj, Listable, Updatable, Deletable, PushIden, Envelope, Account, Device, Push, PushData, ListItem, FileInfo, Text};
+use messages::{PbMsg, DeviceMsg, PushMsg, TextMsg, TargetIden};
+use events::EventStream;
+use cassette::{Cassette, CassetteError, Interaction, Mode};
+use config::{Config, ConfigError};
+use store::StoreError;
 
 static BASE_URL: &'static str = "https://api.pushbullet.com/v2/";
+static STREAM_URL: &'static str = "wss://stream.pushbullet.com/websocket/";
 
 macro_rules! qs {
     [$($name:ident -> $value:expr),*] => {
@@ -23,21 +37,59 @@ macro_rules! qs {
 
 pub struct PbAPI {
     api_key: String,
-    client: Client
+    base_url: String,
+    stream_url: String,
+    client: Client,
+    retry: Option<RetryPolicy>,
+    cassette: Option<Arc<Mutex<Cassette>>>
 }
 
+/// Resend policy for failed requests. When enabled, pushes are tagged
+/// with a random `guid` so that retried sends are not duplicated.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    pub attempts: usize,
+    pub delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(attempts: usize, delay: Duration) -> RetryPolicy {
+        RetryPolicy {
+            attempts: attempts,
+            delay: delay
+        }
+    }
+}
+
+// Allowance for clock difference with server when looking up objects sent by us
+static CLOCK_SKEW: u64 = 60;
+/// Concurrent requests made by `send_to_many`.
+static SEND_WORKERS: usize = 8;
+static LIST_UPDATE_ATTEMPTS: usize = 3;
+
 #[derive(Debug)]
 pub enum PbError {
     Http(HttpError),
+    /// Reading or writing local files.
+    Io(io::Error),
     Pb(Error),
     Js(json::DecoderError),
-    Fmt(json::EncoderError)
+    Fmt(json::EncoderError),
+    Cassette(CassetteError),
+    Config(ConfigError),
+    Store(StoreError),
+    Conflict,
+    NotList
 }
 
 impl From<HttpError> for PbError {
     fn from(e: HttpError) -> PbError { PbError::Http(e) }
 }
 
+impl From<io::Error> for PbError {
+    fn from(e: io::Error) -> PbError { PbError::Io(e) }
+}
+
 impl From<Error> for PbError {
     fn from(e: Error) -> PbError { PbError::Pb(e) }
 }
@@ -50,22 +102,65 @@ impl From<json::EncoderError> for PbError {
     fn from(e: json::EncoderError) -> PbError { PbError::Fmt(e) }
 }
 
+impl From<CassetteError> for PbError {
+    fn from(e: CassetteError) -> PbError { PbError::Cassette(e) }
+}
+
+impl From<ConfigError> for PbError {
+    fn from(e: ConfigError) -> PbError { PbError::Config(e) }
+}
+
+impl From<StoreError> for PbError {
+    fn from(e: StoreError) -> PbError { PbError::Store(e) }
+}
+
+impl PbError {
+    /// Whether the request was rejected for exceeding API rate limit.
+    pub fn is_rate_limited(&self) -> bool {
+        match *self {
+            PbError::Pb(ref e) => e.typ() == "ratelimited",
+            _ => false
+        }
+    }
+
+    /// Whether the request may succeed if repeated: connection failures
+    /// and internal server errors.
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            PbError::Http(_) => true,
+            PbError::Pb(ref e) => e.typ() == "server_error",
+            _ => false
+        }
+    }
+}
+
 impl error::Error for PbError {
     fn description(&self) -> &str {
         match *self {
             PbError::Http(ref e) => e.description(),
+            PbError::Io(ref e) => e.description(),
             PbError::Pb(ref e) => e.description(),
             PbError::Fmt(ref e) => e.description(),
-            PbError::Js(ref e) => e.description()
+            PbError::Js(ref e) => e.description(),
+            PbError::Cassette(ref e) => e.description(),
+            PbError::Config(ref e) => e.description(),
+            PbError::Store(ref e) => e.description(),
+            PbError::Conflict => "object was modified concurrently",
+            PbError::NotList => "push is not a list"
         }
     }
 
     fn cause<'a>(&'a self) -> Option<&'a error::Error> {
         match *self {
             PbError::Http(ref e) => Some(e as &error::Error),
+            PbError::Io(ref e) => Some(e as &error::Error),
             PbError::Pb(ref e) => Some(e as &error::Error),
             PbError::Fmt(ref e) => Some(e as &error::Error),
-            PbError::Js(ref e) => Some(e as &error::Error)
+            PbError::Js(ref e) => Some(e as &error::Error),
+            PbError::Cassette(ref e) => Some(e as &error::Error),
+            PbError::Config(ref e) => Some(e as &error::Error),
+            PbError::Store(ref e) => Some(e as &error::Error),
+            PbError::Conflict | PbError::NotList => None
         }
     }
 }
@@ -74,14 +169,63 @@ impl fmt::Display for PbError {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
         match *self {
             PbError::Http(ref e) => e.fmt(fmt),
+            PbError::Io(ref e) => e.fmt(fmt),
             PbError::Pb(ref e) => e.fmt(fmt),
             PbError::Fmt(ref e) => e.fmt(fmt),
-            PbError::Js(ref e) => e.fmt(fmt)
+            PbError::Js(ref e) => e.fmt(fmt),
+            PbError::Cassette(ref e) => e.fmt(fmt),
+            PbError::Config(ref e) => e.fmt(fmt),
+            PbError::Store(ref e) => e.fmt(fmt),
+            PbError::Conflict | PbError::NotList => fmt.write_str(error::Error::description(self))
         }
     }
 }
 
 
+#[derive(RustcEncodable)]
+struct Dismissal {
+    dismissed: bool,
+}
+
+#[derive(RustcEncodable)]
+struct ListItemsUpdate<'a> {
+    items: &'a [ListItem],
+}
+
+#[derive(RustcEncodable)]
+struct UploadRequest<'a> {
+    file_name: &'a str,
+    file_type: &'a str,
+}
+
+#[derive(RustcDecodable)]
+struct UploadTicket {
+    file_name: String,
+    file_type: String,
+    file_url: Url,
+    upload_url: Url,
+}
+
+fn guess_mime_type(file_name: &str) -> &'static str {
+    let ext = file_name.rsplit('.').next().unwrap_or("").to_lowercase();
+    match &*ext {
+        "txt" | "log" => "text/plain",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "svg" => "image/svg+xml",
+        "mp3" => "audio/mpeg",
+        "mp4" => "video/mp4",
+        _ => "application/octet-stream"
+    }
+}
+
 pub type PbResult<R> = Result<R, PbError>;
 pub type PbVec<I> = (Vec<I>, Option<Cursor>);
 
@@ -90,115 +234,690 @@ impl PbAPI {
     pub fn new(api_key: &str) -> PbAPI {
         PbAPI {
             api_key: api_key.to_string(),
-            client: Client::new()
+            base_url: BASE_URL.to_string(),
+            stream_url: STREAM_URL.to_string(),
+            client: Client::new(),
+            retry: None,
+            cassette: None
         }
     }
 
-    fn get(&mut self, path: &str, params: &[(&str, &str)]) -> HttpResult<String> {
-        let url = format!("{}{}?{}", BASE_URL, path, params.iter().filter(|v| v.1 != "").map(|&(k, v)| format!("{}={}&", k, v)).fold(String::new(), |acc, item| acc + &*item));
-        let mut response = try!(self.client
-            .get(&*url)
-            .header(Authorization(Basic { username: self.api_key.clone(), password: None }))
-            .send());
-        let mut content = String::new();
-        try!(response.read_to_string(&mut content));
+    /// Creates client for a named profile from config file, see `Config`.
+    pub fn from_profile(name: &str) -> PbResult<PbAPI> {
+        Ok(try!(try!(Config::load()).api(Some(name))))
+    }
+
+    /// Creates client for the default profile, or from `PB_API_KEY` if there's no config.
+    pub fn from_default_profile() -> PbResult<PbAPI> {
+        Ok(try!(try!(Config::load()).api(None)))
+    }
+
+    pub fn base_url(mut self, base_url: &str) -> PbAPI {
+        self.base_url = if base_url.ends_with("/") { base_url.to_string() } else { format!("{}/", base_url) };
+        self
+    }
+
+    pub fn stream_url(mut self, stream_url: &str) -> PbAPI {
+        self.stream_url = if stream_url.ends_with("/") { stream_url.to_string() } else { format!("{}/", stream_url) };
+        self
+    }
+
+    /// Connects to realtime event stream.
+    pub fn stream(&self) -> PbResult<EventStream> {
+        EventStream::connect_url(&*format!("{}{}", self.stream_url, self.api_key))
+    }
+
+    /// Makes an independent client with the same settings, e.g. to use from another thread.
+    pub fn fork(&self) -> PbAPI {
+        PbAPI {
+            api_key: self.api_key.clone(),
+            base_url: self.base_url.clone(),
+            stream_url: self.stream_url.clone(),
+            client: Client::new(),
+            retry: self.retry,
+            cassette: self.cassette.clone()
+        }
+    }
+
+    pub fn retry(mut self, policy: RetryPolicy) -> PbAPI {
+        self.retry = Some(policy);
+        self
+    }
+
+    /// Records all requests into given cassette, or serves them from it in replay mode.
+    pub fn cassette(mut self, cassette: Cassette) -> PbAPI {
+        self.cassette = Some(Arc::new(Mutex::new(cassette)));
+        self
+    }
+
+    fn get(&mut self, path: &str, params: &[(&str, &str)]) -> PbResult<String> {
+        let query = params.iter().filter(|v| v.1 != "").map(|&(k, v)| format!("{}={}", k, v)).collect::<Vec<_>>().join("&");
+        self.request(Method::Get, path, &*query, None)
+    }
+
+    fn post(&mut self, path: &str, content: &str) -> PbResult<String> {
+        self.request(Method::Post, path, "", Some(content))
+    }
+
+    fn delete(&mut self, path: &str) -> PbResult<()> {
+        let resp = try!(self.request(Method::Delete, path, "", None));
+        match json::decode::<Envelope>(&*resp) {
+            Ok(Envelope { error: Some(err), .. }) => Err(From::from(err)),
+            _ => Ok(())
+        }
+    }
+
+    fn request(&mut self, method: Method, path: &str, query: &str, body: Option<&str>) -> PbResult<String> {
+        let replay = self.cassette.as_ref().map_or(false, |c| c.lock().unwrap().mode() == Mode::Replay);
+        let (status, content) = if replay {
+            let cassette = self.cassette.as_ref().unwrap();
+            let (status, content) = try!(cassette.lock().unwrap().play(&*method.to_string(), path, query));
+            (StatusCode::from_u16(status), content)
+        } else {
+            try!(self.perform(method, path, query, body))
+        };
+
+        if status == StatusCode::TooManyRequests {
+            return Err(From::from(Error::new("ratelimited", "too many requests, try again later")));
+        }
+        // error pages of proxies in front of the API aren't JSON
+        if status.is_server_error() && json::Json::from_str(&*content).is_err() {
+            return Err(From::from(Error::new("server_error", &*format!("server error: {}", status))));
+        }
         Ok(content)
     }
 
-    fn post(&mut self, path: &str, content: &str) -> HttpResult<String> {
-        let url = format!("{}{}", BASE_URL, path);
-        let mut response = try!(self.client
-            .post(&*url)
-            .header(Authorization(Basic { username: self.api_key.clone(), password: None }))
-            .header(ContentType("application/json".parse().unwrap()))
-            .body(content)
-            .send());
+    /// Makes an HTTP request, recording it if there's a cassette.
+    fn perform(&mut self, method: Method, path: &str, query: &str, body: Option<&str>) -> PbResult<(StatusCode, String)> {
+        let url = if query.is_empty() { format!("{}{}", self.base_url, path) } else { format!("{}{}?{}", self.base_url, path, query) };
+        let mut request = self.client
+            .request(method.clone(), &*url)
+            .header(Authorization(Basic { username: self.api_key.clone(), password: None }));
+        if let Some(body) = body {
+            request = request
+                .header(ContentType("application/json".parse().unwrap()))
+                .body(body);
+        }
+        let mut response = try!(request.send());
         let mut content = String::new();
-        try!(response.read_to_string(&mut content));
-        Ok(content)
+        try!(response.read_to_string(&mut content).map_err(HttpError::from));
+
+        if let Some(ref cassette) = self.cassette {
+            try!(cassette.lock().unwrap().push(Interaction {
+                method: method.to_string(),
+                path: path.to_string(),
+                query: query.to_string(),
+                body: body.map(|b| b.to_string()),
+                status: response.status.to_u16(),
+                response: content.clone(),
+            }, &*self.api_key));
+        }
+        Ok((response.status, content))
     }
 
-    fn delete(&mut self, path: &str) -> HttpResult<()> {
-        let url = format!("{}{}", BASE_URL, path);
-        self.client
-            .delete(&*url)
-            .header(Authorization(Basic { username: self.api_key.clone(), password: None }))
-            .send()
-            .map(|_| ())
+    pub fn me(&mut self) -> PbResult<Account> {
+        let result = try!(self.get("users/me", &[]));
+        Ok(try!(json::decode(&*result)))
+    }
+
+    /// Downloads contents of an uploaded file, e.g. `file_url` of a file push.
+    pub fn download<P: AsRef<Path>>(&mut self, url: &Url, dest: P) -> PbResult<u64> {
+        let mut response = try!(self.client.get(url.clone()).send());
+        if !response.status.is_success() {
+            return Err(PbError::Pb(Error::new("download_failed", &*format!("{}: {}", url, response.status))));
+        }
+        let mut file = try!(File::create(dest));
+        // reading the response fails far more often than writing the file, so it's retried
+        Ok(try!(io::copy(&mut response, &mut file).map_err(HttpError::from)))
+    }
+
+    /// Uploads a file, so that it can be sent with `PushMsg::file`.
+    pub fn upload<P: AsRef<Path>>(&mut self, path: P) -> PbResult<FileInfo> {
+        let path = path.as_ref();
+        let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("file").to_string();
+
+        let mut content = Vec::new();
+        try!(try!(File::open(path)).read_to_end(&mut content));
+
+        let request = try!(json::encode(&UploadRequest { file_name: &*file_name, file_type: guess_mime_type(&*file_name) }));
+        let resp = try!(self.post("upload-request", &*request));
+        let ticket: UploadTicket = match json::decode(&*resp) {
+            Ok(t) => t,
+            Err(e) => return Err(match json::decode::<Envelope>(&*resp) {
+                Ok(Envelope { error: Some(err), .. }) => From::from(err),
+                _ => From::from(e)
+            })
+        };
+
+        let boundary = format!("------------------------{}", Uuid::new_v4().simple());
+        let mut body = format!("--{}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{}\"\r\nContent-Type: {}\r\n\r\n",
+                               boundary, ticket.file_name, ticket.file_type).into_bytes();
+        body.extend(content);
+        body.extend(format!("\r\n--{}--\r\n", boundary).into_bytes());
+
+        let response = try!(self.client
+            .post(ticket.upload_url)
+            .header(ContentType(format!("multipart/form-data; boundary={}", boundary).parse().unwrap()))
+            .body(&*body)
+            .send());
+        if !response.status.is_success() {
+            return Err(From::from(Error::new("upload_failed", &*format!("File upload failed: {}", response.status))));
+        }
+
+        Ok(FileInfo {
+            file_name: ticket.file_name,
+            file_type: ticket.file_type,
+            file_url: ticket.file_url,
+            image_url: None,
+        })
     }
 
     pub fn send<T: PbMsg>(&mut self, msg: &T) -> PbResult<T::Obj> {
-        let resp = try!(self.post(T::Obj::root_uri(), &*try!(json::encode(msg))));
+        let policy = match self.retry {
+            Some(policy) => policy,
+            None => return self._send::<T::Obj>(T::Obj::root_uri(), &*try!(json::encode(msg)))
+        };
+
+        let tagged = match msg.guid() {
+            Some(_) => None,
+            None => msg.with_guid(Uuid::new_v4().hyphenated().to_string())
+        };
+        let msg = tagged.as_ref().unwrap_or(msg);
+        let body = try!(json::encode(msg));
+        let started = Timestamp::now() - Duration::from_secs(CLOCK_SKEW);
+
+        let mut attempt = 1;
+        loop {
+            let result = self._send::<T::Obj>(T::Obj::root_uri(), &*body);
+            match result {
+                Err(ref e) if e.is_transient() => (),
+                _ => return result
+            }
+            thread::sleep(policy.delay);
+
+            // previous request could have reached the server before failing,
+            // also checked after the last attempt, so that its error isn't a false alarm
+            if let Some(guid) = msg.guid() {
+                if let Ok(Some(obj)) = self.find_by_guid::<T::Obj>(guid, started) {
+                    return Ok(obj);
+                }
+            }
+            if attempt >= policy.attempts {
+                return result;
+            }
+            attempt += 1;
+        }
+    }
+
+    pub fn find_by_guid<R: Listable>(&mut self, guid: &str, since: Timestamp) -> PbResult<Option<R>> {
+        let (mut objs, mut cursor) = try!(self.load_since::<R>(since));
+        loop {
+            if let Some(idx) = objs.iter().position(|o| o.guid() == Some(guid)) {
+                return Ok(Some(objs.swap_remove(idx)));
+            }
+            match cursor {
+                Some(c) => {
+                    let (next, c) = try!(self.load_page::<R>(None, Some(since), false, Some(c)));
+                    objs = next;
+                    cursor = c;
+                },
+                None => return Ok(None)
+            }
+        }
+    }
+
+    /// Sends `msg` to every distinct target with a few concurrent workers,
+    /// returning results in order of targets.
+    pub fn send_to_many(&mut self, msg: &PushMsg, targets: &[TargetIden]) -> Vec<(TargetIden, PbResult<Push>)> {
+        let mut unique: Vec<TargetIden> = Vec::with_capacity(targets.len());
+        for target in targets {
+            if !unique.iter().any(|t| t.is_same(target)) {
+                unique.push(target.clone());
+            }
+        }
+
+        let bodies = unique.iter().map(|t| json::encode(&msg.clone().target(t.clone())).map_err(PbError::from)).collect::<Vec<_>>();
+        let queue = Arc::new(Mutex::new(bodies.into_iter().enumerate().rev().collect::<Vec<_>>()));
+        let results = Arc::new(Mutex::new((0..unique.len()).map(|_| None).collect::<Vec<Option<PbResult<Push>>>>()));
+
+        let workers: Vec<_> = (0..cmp::min(SEND_WORKERS, unique.len())).map(|_| {
+            let mut api = self.fork();
+            let queue = queue.clone();
+            let results = results.clone();
+            thread::spawn(move || loop {
+                let (idx, body) = match queue.lock().unwrap().pop() {
+                    Some(item) => item,
+                    None => break
+                };
+                let result = body.and_then(|body| api._send::<Push>(Push::root_uri(), &*body));
+                results.lock().unwrap()[idx] = Some(result);
+            })
+        }).collect();
+
+        // a panicked worker leaves its push without a result, reported as an error
+        for worker in workers {
+            let _ = worker.join();
+        }
+        let mut results = match results.lock() {
+            Ok(results) => results,
+            Err(poisoned) => poisoned.into_inner()
+        };
+        unique.into_iter().zip(results.drain(..)).map(|(target, result)| {
+            (target, result.unwrap_or_else(|| Err(From::from(Error::new("worker_panicked", "push was not sent, sending thread panicked")))))
+        }).collect()
+    }
+
+    fn _send<O: PbObj>(&mut self, path: &str, content: &str) -> PbResult<O> {
+        let resp = try!(self.post(path, content));
         match json::decode(&*resp) {
             Ok(o) => Ok(o),
-            Err(e) => Err(match json::decode::<Error>(&*resp) {
-                Ok(err) => From::from(err),
-                Err(_) => From::from(e)
+            Err(e) => Err(match json::decode::<Envelope>(&*resp) {
+                Ok(Envelope { error: Some(err), .. }) => From::from(err),
+                _ => From::from(e)
             })
         }
     }
 
-    pub fn remove<O: PbObj>(&mut self, iden: Iden) -> PbResult<()> {
+    /// Marks a push as dismissed on all devices.
+    pub fn dismiss(&mut self, iden: PushIden) -> PbResult<Push> {
+        self.update::<Push, _>(iden, &Dismissal { dismissed: true })
+    }
+
+    /// Sends an SMS from a phone with SMS support.
+    pub fn send_text(&mut self, msg: &TextMsg) -> PbResult<Text> {
+        let body = try!(json::encode(msg));
+        self._send::<Text>(Text::root_uri(), &*body)
+    }
+
+    /// Edits items of a list push. If the push is modified by someone else
+    /// while `f` runs, `f` is applied again to the new items, and after a few
+    /// such attempts fails with `PbError::Conflict`. The API has no conditional
+    /// updates, so a change made right between the last check and the update
+    /// itself still wins over it.
+    pub fn update_list<F: FnMut(&mut Vec<ListItem>)>(&mut self, iden: PushIden, mut f: F) -> PbResult<Push> {
+        for _ in 0..LIST_UPDATE_ATTEMPTS {
+            let push = try!(self.load_by_iden::<Push>(iden.clone()));
+            let mut items = match push.data {
+                PushData::List(items) => items,
+                _ => return Err(PbError::NotList)
+            };
+            f(&mut items);
+
+            let current = try!(self.load_by_iden::<Push>(iden.clone()));
+            if current.modified == push.modified {
+                return self.update::<Push, _>(iden, &ListItemsUpdate { items: &*items });
+            }
+        }
+        Err(PbError::Conflict)
+    }
+
+    /// Changes fields of an object, e.g. `dismissed` of a push or `nickname` of a device.
+    pub fn update<O: Updatable, C: Encodable>(&mut self, iden: O::Iden, changes: &C) -> PbResult<O> {
+        let body = try!(json::encode(changes));
+        self._send::<O>(&*format!("{}/{}", O::root_uri(), iden), &*body)
+    }
+
+    pub fn remove<O: Deletable>(&mut self, iden: O::Iden) -> PbResult<()> {
         try!(self.delete(&*format!("{}/{}", O::root_uri(), iden)));
         Ok(())
     }
 
-    #[inline] fn _load<R: PbObj + FromEnvelope>(&mut self, obj: &str, limit: Option<usize>, since: Option<Timestamp>, cursor: Option<Cursor>) -> PbResult<PbVec<R>> {
+    /// Loads a page of objects, at most `limit` of them, modified after `since`,
+    /// only active ones if `active`, continuing from `cursor`. A cursor continues
+    /// the listing it was returned for, so the other parameters must stay the same.
+    pub fn load_page<R: Listable>(&mut self, limit: Option<usize>, since: Option<Timestamp>, active: bool, cursor: Option<Cursor>) -> PbResult<PbVec<R>> {
         let l = limit.map(|v| v.to_string()).unwrap_or("".to_string());
         let s = since.map(|v| v.to_string()).unwrap_or("".to_string());
         let c = cursor.map(|v| v.to_string()).unwrap_or("".to_string());
-        let result = try!(self.get(obj, &*qs![limit -> &*l, modified_after -> &*s, cursor -> &*c]));
+        let a = if active { "true" } else { "" };
+        let result = try!(self.get(R::root_uri(), &*qs![limit -> &*l, modified_after -> &*s, active -> a, cursor -> &*c]));
         let env = try!(json::decode::<Envelope>(&*result));
         env.get::<R>().map_err(From::from)
     }
 
-    pub fn load_by_iden<R: PbObj>(&mut self, iden: Iden) -> PbResult<R> {
+    pub fn load_by_iden<R: Listable>(&mut self, iden: R::Iden) -> PbResult<R> {
         let url = format!("{}/{}", R::root_uri(), iden);
         let result = try!(self.get(&*url, &[]));
-        Ok(try!(json::decode(&*result)))
+        match json::decode(&*result) {
+            Ok(o) => Ok(o),
+            Err(e) => Err(match json::decode::<Envelope>(&*result) {
+                Ok(Envelope { error: Some(err), .. }) => From::from(err),
+                _ => From::from(e)
+            })
+        }
+    }
+
+    pub fn load_since<R: Listable>(&mut self, since: Timestamp) -> PbResult<PbVec<R>> {
+        self.load_page::<R>(None, Some(since), false, None)
     }
 
-    pub fn load_since<R: PbObj + FromEnvelope>(&mut self, since: Timestamp) -> PbResult<PbVec<R>> {
-        self._load::<R>(R::root_uri(), None, Some(since), None)
+    pub fn load_from<R: Listable>(&mut self, cursor: Cursor) -> PbResult<PbVec<R>> {
+        self.load_page::<R>(None, None, false, Some(cursor))
     }
 
-    pub fn load_from<R: PbObj + FromEnvelope>(&mut self, cursor: Cursor) -> PbResult<PbVec<R>> {
-        self._load::<R>(R::root_uri(), None, None, Some(cursor))
+    pub fn load<R: Listable>(&mut self) -> PbResult<PbVec<R>> {
+        self.load_page::<R>(None, None, false, None)
     }
 
-    pub fn load<R: PbObj + FromEnvelope>(&mut self) -> PbResult<PbVec<R>> {
-        self._load::<R>(R::root_uri(), None, None, None)
+    pub fn loadn<R: Listable>(&mut self, limit: usize) -> PbResult<PbVec<R>> {
+        self.load_page::<R>(Some(limit), None, false, None)
     }
 
-    pub fn loadn<R: PbObj + FromEnvelope>(&mut self, limit: usize) -> PbResult<PbVec<R>> {
-        self._load::<R>(R::root_uri(), Some(limit), None, None)
+    pub fn loadn_from<R: Listable>(&mut self, limit: usize, cursor: Cursor) -> PbResult<PbVec<R>> {
+        self.load_page::<R>(Some(limit), None, false, Some(cursor))
     }
 
-    pub fn loadn_from<R: PbObj + FromEnvelope>(&mut self, limit: usize, cursor: Cursor) -> PbResult<PbVec<R>> {
-        self._load::<R>(R::root_uri(), Some(limit), None, Some(cursor))
+    pub fn loadn_since<R: Listable>(&mut self, limit: usize, since: Timestamp) -> PbResult<PbVec<R>> {
+        self.load_page::<R>(Some(limit), Some(since), false, None)
     }
 
-    pub fn loadn_since<R: PbObj + FromEnvelope>(&mut self, limit: usize, since: Timestamp) -> PbResult<PbVec<R>> {
-        self._load::<R>(R::root_uri(), Some(limit), Some(since), None)
+    /// Loads objects of all kinds modified after `since` (or all of them), following cursors.
+    pub fn everything(&mut self, since: Option<Timestamp>) -> PbResult<Envelope> {
+        let s = since.map(|v| v.to_string()).unwrap_or("".to_string());
+        let mut env = Envelope::new();
+        let mut cursor = String::new();
+        loop {
+            let result = try!(self.get("everything", &*qs![modified_after -> &*s, cursor -> &*cursor]));
+            let page = try!(json::decode::<Envelope>(&*result));
+            if let Some(error) = page.error {
+                return Err(From::from(error));
+            }
+            env.extend(page);
+            match env.cursor.take() {
+                Some(c) => cursor = c,
+                None => return Ok(env)
+            }
+        }
+    }
+
+    /// Loads all objects of a kind, following cursors.
+    pub fn load_all<R: Listable>(&mut self) -> PbResult<Vec<R>> {
+        let (mut objs, mut cursor) = try!(self.load::<R>());
+        while let Some(c) = cursor {
+            let (next, c) = try!(self.load_from::<R>(c));
+            objs.extend(next);
+            cursor = c;
+        }
+        Ok(objs)
+    }
+
+    pub fn ensure_device(&mut self, nickname: &str) -> PbResult<Device> {
+        let (mut devices, mut cursor) = try!(self.load::<Device>());
+        loop {
+            if let Some(idx) = devices.iter().position(|d| d.is_active() && d.nickname() == nickname) {
+                return Ok(devices.swap_remove(idx));
+            }
+            match cursor {
+                Some(c) => {
+                    let (next, c) = try!(self.load_from::<Device>(c));
+                    devices = next;
+                    cursor = c;
+                },
+                None => break
+            }
+        }
+        self.send(&DeviceMsg::new(nickname))
     }
 }
 
-//#[test]
-//#[allow(unused_imports)]
-//fn test_get_objects() {
-//    use objects::{Push, Device, Subscription, Grant, Client, Channel, Contact};
-//    use objects::Envelope;
-//
-//    let mut api = PbAPI::new(option_env!("PB_API_KEY").unwrap());
-//    let r = api.loadn::<Push>(10);
-//    r.unwrap();
-//    //panic!("{:?}", r);
-//}
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+    use hyper::status::StatusCode;
+    use uuid::Uuid;
+    use super::{PbAPI, PbError, RetryPolicy};
+    use cassette::{Cassette, Interaction};
+    use testing::MockServer;
+    use objects::{Push, Device, DeviceIden, ListItem, PushData};
+    use messages::{PushMsg, TargetIden};
+    use rustc_serialize::json::Json;
+
+    #[test]
+    fn test_get_objects() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        for title in &["one", "two", "three"] {
+            api.send(&PushMsg::new(TargetIden::CurrentUser).title(*title)).unwrap();
+        }
+
+        let (pushes, cursor) = api.loadn::<Push>(2).unwrap();
+        assert_eq!(pushes.iter().map(|p| p.title.clone().unwrap()).collect::<Vec<_>>(), vec!["three", "two"]);
+
+        let (pushes, cursor) = api.loadn_from::<Push>(2, cursor.unwrap()).unwrap();
+        assert_eq!(pushes.len(), 1);
+        assert_eq!(pushes[0].title, Some("one".to_string()));
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn test_load_page() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let old = api.send(&PushMsg::new(TargetIden::CurrentUser).title("old")).unwrap();
+        for n in 0..6 {
+            let push = api.send(&PushMsg::new(TargetIden::CurrentUser).title(format!("new {}", n))).unwrap();
+            if n % 2 == 0 {
+                api.remove::<Push>(push.iden).unwrap();
+            }
+        }
+        server.page_size(2);
+
+        let mut titles = Vec::new();
+        let mut cursor = None;
+        loop {
+            let (page, next) = api.load_page::<Push>(None, Some(old.modified), true, cursor).unwrap();
+            assert!(page.len() <= 2);
+            titles.extend(page.into_iter().map(|p| p.title.unwrap()));
+            cursor = match next { Some(c) => Some(c), None => break };
+        }
+        assert_eq!(titles, vec!["new 5", "new 3", "new 1"]);
+    }
+
+    #[test]
+    fn test_delete() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let push = api.send(&PushMsg::new(TargetIden::CurrentUser).title("Temporary")).unwrap();
+
+        api.remove::<Push>(push.iden.clone()).unwrap();
+        assert!(api.load_by_iden::<Push>(push.iden).is_err());
+        assert_eq!(server.objects("pushes")[0].find("active"), Some(&Json::Boolean(false)));
+    }
+
+    #[test]
+    fn test_everything() {
+        let server = MockServer::start("mock-key").unwrap();
+        server.page_size(2);
+        let mut api = server.api();
+        for title in &["first", "second", "third"] {
+            api.send(&PushMsg::new(TargetIden::CurrentUser).title(*title)).unwrap();
+        }
+        let device = api.ensure_device("dashboard").unwrap();
+
+        let env = api.everything(None).unwrap();
+        assert_eq!(env.pushes.as_ref().map(|p| p.len()), Some(3));
+        assert_eq!(env.devices, Some(vec![device]));
+        assert_eq!(env.subscriptions, Some(vec![]));
+        assert_eq!(env.cursor, None);
+
+        let since = env.pushes.unwrap()[0].modified;
+        let env = api.everything(Some(since)).unwrap();
+        assert_eq!(env.pushes.map(|p| p.len()), Some(0));
+        assert_eq!(env.devices.map(|d| d.len()), Some(1));
+    }
+
+    #[test]
+    fn test_update() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let push = api.send(&PushMsg::new(TargetIden::CurrentUser).title("Unread")).unwrap();
+
+        let updated = api.update::<Push, _>(push.iden.clone(), &Json::from_str("{\"dismissed\": true}").unwrap()).unwrap();
+        assert!(updated.dismissed);
+        assert!(updated.modified > push.modified);
+    }
+
+    #[test]
+    fn test_error_response() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        match api.send(&PushMsg::new(TargetIden::DeviceIden(DeviceIden::from("missing")))) {
+            Err(PbError::Pb(ref e)) => assert_eq!(e.to_string(), "Target device not found."),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
 
-//#[test]
-//fn test_delete() {
-    //let api = PbAPI::new(option_env!("PB_API_KEY").unwrap());
-    //let result = api.remove::<Push>("123".to_string());
-    //assert_eq!(result, Ok(()));
-//}
+    #[test]
+    fn test_ensure_device() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let device = api.ensure_device("build-01").unwrap();
+        assert_eq!(device.nickname(), "build-01");
+        assert_eq!(api.ensure_device("build-01").unwrap().iden(), device.iden());
+        assert_eq!(api.load::<Device>().unwrap().0.len(), 1);
+    }
+
+    #[test]
+    fn test_send_retry() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api().retry(RetryPolicy::new(3, Duration::from_millis(10)));
+
+        // push is stored, but the reply is lost: found by its guid, not sent again
+        server.fail_next_reply(StatusCode::InternalServerError);
+        let push = api.send(&PushMsg::new(TargetIden::CurrentUser).title("Deploy")).unwrap();
+        let pushes = server.objects("pushes");
+        assert_eq!(pushes.len(), 1);
+        assert_eq!(push.guid, Some(pushes[0]["guid"].as_string().unwrap().to_string()));
+
+        // request never reached the server: sent again
+        server.fail_next(StatusCode::ServiceUnavailable);
+        api.send(&PushMsg::new(TargetIden::CurrentUser).title("Rollback")).unwrap();
+        assert_eq!(server.objects("pushes").len(), 2);
+
+        // the reply to the last attempt is lost, but the push is there
+        let mut api = server.api().retry(RetryPolicy::new(1, Duration::from_millis(10)));
+        server.fail_next_reply(StatusCode::InternalServerError);
+        let push = api.send(&PushMsg::new(TargetIden::CurrentUser).title("Restart")).unwrap();
+        assert_eq!(server.objects("pushes").len(), 3);
+        assert_eq!(push.title, Some("Restart".to_string()));
+    }
 
+    #[test]
+    fn test_html_server_error() {
+        let path = env::temp_dir().join(format!("pb-test-html-error-{}.json", Uuid::new_v4().simple()));
+        Cassette::record(&path).push(Interaction {
+            method: "GET".to_string(),
+            path: "pushes".to_string(),
+            query: "limit=10".to_string(),
+            body: None,
+            status: 502,
+            response: "<html><body>Bad Gateway</body></html>".to_string(),
+        }, "").unwrap();
+
+        let mut api = PbAPI::new("mock-key").cassette(Cassette::replay(&path).unwrap());
+        assert!(api.loadn::<Push>(10).unwrap_err().is_transient());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_send_to_many() {
+        let server = MockServer::start("mock-key").unwrap();
+        server.insert("channels", Json::from_str("{\"tag\":\"alerts\",\"name\":\"Alerts\",\"description\":\"\"}").unwrap()).unwrap();
+        let mut api = server.api();
+
+        let results = api.send_to_many(&PushMsg::new(TargetIden::CurrentUser).title("Disk full"), &[
+            TargetIden::ContactEmail("Ops@Example.com".to_string()),
+            TargetIden::ContactEmail("ops@example.com".to_string()),
+            TargetIden::ChannelTag("alerts".to_string()),
+            TargetIden::ChannelTag("missing".to_string()),
+        ]);
+
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].1.as_ref().unwrap().receiver_email, Some("Ops@Example.com".to_string()));
+        assert!(results[1].1.as_ref().unwrap().channel_iden.is_some());
+        assert!(results[2].1.is_err());
+
+        // more targets than workers, results keep their order
+        let emails: Vec<TargetIden> = (0..20).map(|n| TargetIden::ContactEmail(format!("user{}@example.com", n))).collect();
+        let results = api.send_to_many(&PushMsg::new(TargetIden::CurrentUser).title("Deploy"), &emails);
+        assert_eq!(results.len(), 20);
+        for (n, &(ref target, ref result)) in results.iter().enumerate() {
+            assert_eq!(*target, emails[n]);
+            assert_eq!(result.as_ref().unwrap().receiver_email, Some(format!("user{}@example.com", n)));
+        }
+    }
+
+    #[test]
+    fn test_upload_file() {
+        use std::env;
+        use std::fs::File;
+        use std::io::{Read, Write};
+        use hyper::client::Client;
+
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let dir = env::temp_dir().join(format!("pb-test-upload-{}", Uuid::new_v4().simple()));
+        ::std::fs::create_dir(&dir).unwrap();
+        let path = dir.join("pb-test-upload.txt");
+        File::create(&path).unwrap().write_all(b"disk usage report").unwrap();
+
+        let info = api.upload(&path).unwrap();
+        assert_eq!(info.file_name, "pb-test-upload.txt");
+        assert_eq!(info.file_type, "text/plain");
+
+        let mut content = String::new();
+        Client::new().get(info.file_url.clone()).send().unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "disk usage report");
+
+        let push = api.send(&PushMsg::file(TargetIden::CurrentUser, info)).unwrap();
+        assert_eq!(push.data.type_name(), "file");
+        ::std::fs::remove_dir_all(&dir).unwrap();
+
+        // a missing local file isn't worth retrying
+        match api.upload(&path) {
+            Err(e @ PbError::Io(_)) => assert!(!e.is_transient()),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_update_list() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let push = api.send(&PushMsg::list(TargetIden::CurrentUser, vec![ListItem::new("milk", false), ListItem::new("eggs", false)])).unwrap();
+
+        let updated = api.update_list(push.iden.clone(), |items| {
+            let item = items.remove(0).checked();
+            items.push(item);
+        }).unwrap();
+        assert_eq!(updated.data, PushData::List(vec![ListItem::new("eggs", false), ListItem::new("milk", true)]));
+        assert!(updated.modified > push.modified);
+
+        // someone else adds an item while the first attempt runs
+        let mut seen = Vec::new();
+        let updated = api.update_list(push.iden.clone(), |items| {
+            seen.push(items.len());
+            if seen.len() == 1 {
+                server.api().update::<Push, _>(push.iden.clone(), &Json::from_str("{\"items\": [{\"text\": \"eggs\", \"checked\": false}, {\"text\": \"milk\", \"checked\": true}, {\"text\": \"tea\", \"checked\": false}]}").unwrap()).unwrap();
+            }
+            items.retain(|i| !i.is_checked());
+        }).unwrap();
+        assert_eq!(seen, vec![2, 3]);
+        assert_eq!(updated.data, PushData::List(vec![ListItem::new("eggs", false), ListItem::new("tea", false)]));
+
+        // modified every time
+        match api.update_list(push.iden.clone(), |_| { server.api().dismiss(push.iden.clone()).unwrap(); }) {
+            Err(PbError::Conflict) => (),
+            other => panic!("expected conflict, got {:?}", other)
+        }
+        assert_eq!(api.load_by_iden::<Push>(push.iden.clone()).unwrap().data, updated.data);
+
+        let note = api.send(&PushMsg::new(TargetIden::CurrentUser).body("milk, eggs")).unwrap();
+        match api.update_list(note.iden.clone(), |items| items.clear()) {
+            Err(PbError::NotList) => (),
+            other => panic!("expected not a list error, got {:?}", other)
+        }
+    }
+}
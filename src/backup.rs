@@ -0,0 +1,299 @@
+//! Account snapshots: backup of devices, contacts, chats, channels, subscriptions and grants,
+//! and idempotent restore of them into another account.
+
+use std::fmt;
+use std::io::{Read, Write};
+
+use rustc_serialize::Decodable;
+use rustc_serialize::json::{self, Json, DecoderError};
+
+use api::{PbAPI, PbError, PbResult};
+use messages::{ChannelMsg, ChatMsg, ContactMsg, DeviceMsg, SubscriptionMsg};
+use objects::{Channel, Chat, Contact, Device, Error, Grant, Resource, Subscription, Timestamp};
+
+/// Version of snapshot format written by this library.
+/// Version 1 snapshots have no chats.
+pub static SNAPSHOT_VERSION: u64 = 2;
+
+/// Active objects of an account at some moment.
+#[derive(Debug, PartialEq, RustcEncodable, RustcDecodable)]
+pub struct Snapshot {
+    pub version: u64,
+    pub created: Timestamp,
+    pub devices: Vec<Device>,
+    pub contacts: Vec<Contact>,
+    pub chats: Vec<Chat>,
+    pub channels: Vec<Channel>,
+    pub subscriptions: Vec<Subscription>,
+    /// OAuth grants are kept for reference only, they can't be restored.
+    pub grants: Vec<Grant>,
+}
+
+fn active<R: Resource>(objs: Vec<R>) -> Vec<R> {
+    objs.into_iter().filter(|o| o.active()).collect()
+}
+
+impl Snapshot {
+    /// Takes a snapshot of the account.
+    pub fn take(api: &mut PbAPI) -> PbResult<Snapshot> {
+        Ok(Snapshot {
+            version: SNAPSHOT_VERSION,
+            created: Timestamp::now(),
+            devices: active(try!(api.load_all::<Device>())),
+            contacts: active(try!(api.load_all::<Contact>())),
+            chats: active(try!(api.load_all::<Chat>())),
+            channels: active(try!(api.load_all::<Channel>())),
+            subscriptions: active(try!(api.load_all::<Subscription>())),
+            grants: active(try!(api.load_all::<Grant>())),
+        })
+    }
+
+    pub fn write<W: Write>(&self, out: &mut W) -> PbResult<()> {
+        try!(writeln!(out, "{}", json::as_pretty_json(self)));
+        Ok(())
+    }
+
+    /// Reads a snapshot, refusing ones written by newer versions of the library.
+    pub fn read<R: Read>(input: &mut R) -> PbResult<Snapshot> {
+        let mut data = try!(Json::from_reader(input).map_err(DecoderError::ParseError));
+        match data.find("version").and_then(|v| v.as_u64()) {
+            Some(version) if version <= SNAPSHOT_VERSION => (),
+            version => return Err(From::from(Error::new("unsupported_snapshot", &*format!(
+                "snapshot version {} is not supported, expected at most {}",
+                version.map(|v| v.to_string()).unwrap_or("none".to_string()), SNAPSHOT_VERSION))))
+        }
+        if let Json::Object(ref mut obj) = data {
+            obj.entry("chats".to_string()).or_insert(Json::Array(Vec::new()));
+        }
+        Ok(try!(Decodable::decode(&mut json::Decoder::new(data))))
+    }
+
+    /// Compares the snapshot with current state of an account, listing objects missing from it.
+    /// Channels and subscriptions are matched by channel tag, contacts and chats by email,
+    /// devices by nickname.
+    pub fn plan(&self, api: &mut PbAPI) -> PbResult<Plan> {
+        let current = try!(Snapshot::take(api));
+        let mut plan = Plan { changes: Vec::new(), unchanged: 0 };
+
+        for channel in &self.channels {
+            if current.channels.iter().any(|c| c.tag == channel.tag) {
+                plan.unchanged += 1;
+            } else {
+                plan.changes.push(Change::CreateChannel(channel_msg(channel)));
+            }
+        }
+        for tag in self.subscriptions.iter().filter_map(|s| s.channel.as_ref()).map(|c| &c.tag) {
+            if current.subscriptions.iter().filter_map(|s| s.channel.as_ref()).any(|c| c.tag == *tag) {
+                plan.unchanged += 1;
+            } else {
+                plan.changes.push(Change::Subscribe(tag.clone()));
+            }
+        }
+        for contact in &self.contacts {
+            if current.contacts.iter().any(|c| c.email_normalized == contact.email_normalized) {
+                plan.unchanged += 1;
+            } else {
+                plan.changes.push(Change::AddContact { name: contact.name.clone(), email: contact.email.clone() });
+            }
+        }
+        for chat in &self.chats {
+            if current.chats.iter().any(|c| c.with.email_normalized == chat.with.email_normalized) {
+                plan.unchanged += 1;
+            } else {
+                plan.changes.push(Change::AddChat(chat.with.email.clone()));
+            }
+        }
+        for device in &self.devices {
+            if current.devices.iter().any(|d| d.nickname() == device.nickname()) {
+                plan.unchanged += 1;
+            } else {
+                plan.changes.push(Change::AddDevice(device.nickname().to_string()));
+            }
+        }
+        Ok(plan)
+    }
+
+    /// Re-creates objects missing from the account, returns applied plan
+    /// and the changes which failed.
+    pub fn restore(&self, api: &mut PbAPI) -> PbResult<RestoreSummary> {
+        let plan = try!(self.plan(api));
+        let failed = plan.apply(api);
+        Ok(RestoreSummary { plan: plan, failed: failed })
+    }
+}
+
+fn channel_msg(channel: &Channel) -> ChannelMsg<'static> {
+    ChannelMsg {
+        tag: channel.tag.clone().into(),
+        name: channel.name.clone().into(),
+        description: channel.description.clone().into(),
+        image_url: channel.image_url.clone(),
+        website_url: channel.website_url.clone(),
+        feed_url: channel.feed_url.clone(),
+    }
+}
+
+/// Single object to re-create during restore.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Change {
+    CreateChannel(ChannelMsg<'static>),
+    Subscribe(String),
+    AddContact { name: String, email: String },
+    AddChat(String),
+    AddDevice(String),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Change::CreateChannel(ref msg) => write!(fmt, "+ channel {} ({})", msg.tag, msg.name),
+            Change::Subscribe(ref tag) => write!(fmt, "+ subscription {}", tag),
+            Change::AddContact { ref name, ref email } => write!(fmt, "+ contact {} <{}>", name, email),
+            Change::AddChat(ref email) => write!(fmt, "+ chat {}", email),
+            Change::AddDevice(ref nickname) => write!(fmt, "+ device {}", nickname),
+        }
+    }
+}
+
+/// Difference between a snapshot and an account, see `Snapshot::plan`.
+#[derive(Debug, PartialEq)]
+pub struct Plan {
+    /// Objects to create, channels first, so that subscriptions to them succeed.
+    pub changes: Vec<Change>,
+    /// Number of snapshot objects already present in the account.
+    pub unchanged: usize,
+}
+
+impl Plan {
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// Makes all the changes, one failing doesn't stop the others. Returns the failed ones.
+    /// Channel tags are unique across accounts, so creating a channel fails when another
+    /// account took its tag; subscriptions to that tag are skipped then, as they would
+    /// subscribe to the other account's channel.
+    pub fn apply(&self, api: &mut PbAPI) -> Vec<(Change, PbError)> {
+        let mut failed: Vec<(Change, PbError)> = Vec::new();
+        for change in &self.changes {
+            let result = match *change {
+                Change::CreateChannel(ref msg) => api.send(msg).map(|_| ()),
+                Change::Subscribe(ref tag) => {
+                    let not_created = failed.iter().any(|&(ref c, _)| match *c {
+                        Change::CreateChannel(ref msg) => msg.tag == **tag,
+                        _ => false
+                    });
+                    if not_created {
+                        Err(From::from(Error::new("channel_not_created", &*format!("channel {} was not created", tag))))
+                    } else {
+                        api.send(&SubscriptionMsg::new(&**tag)).map(|_| ())
+                    }
+                },
+                Change::AddContact { ref name, ref email } => api.send(&ContactMsg { name: (&**name).into(), email: (&**email).into() }).map(|_| ()),
+                Change::AddChat(ref email) => api.send(&ChatMsg { email: (&**email).into() }).map(|_| ()),
+                Change::AddDevice(ref nickname) => api.send(&DeviceMsg::new(&**nickname)).map(|_| ()),
+            };
+            if let Err(e) = result {
+                failed.push((change.clone(), e));
+            }
+        }
+        failed
+    }
+}
+
+/// Outcome of `Snapshot::restore`.
+#[derive(Debug)]
+pub struct RestoreSummary {
+    pub plan: Plan,
+    /// Changes refused by the API, e.g. channels with a tag taken by another account.
+    pub failed: Vec<(Change, PbError)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use testing::MockServer;
+    use api::PbAPI;
+    use rustc_serialize::json::Json;
+    use messages::{ChannelMsg, ChatMsg, ContactMsg, DeviceMsg, SubscriptionMsg};
+    use super::{Change, Snapshot};
+
+    fn golden(api: &mut PbAPI) {
+        api.send(&ChannelMsg::new("releases", "Releases", "New versions")).unwrap();
+        api.send(&SubscriptionMsg::new("releases")).unwrap();
+        api.send(&ContactMsg { name: "Ops".into(), email: "Ops@Example.com".into() }).unwrap();
+        api.send(&ChatMsg { email: "Dev@Example.com".into() }).unwrap();
+        api.send(&DeviceMsg::new("build-01")).unwrap();
+        api.send(&DeviceMsg::new("laptop")).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let server = MockServer::start("mock-key").unwrap();
+        golden(&mut server.api());
+        let snapshot = Snapshot::take(&mut server.api()).unwrap();
+        assert_eq!((snapshot.devices.len(), snapshot.contacts.len(), snapshot.chats.len(), snapshot.channels.len(), snapshot.subscriptions.len()),
+                   (2, 1, 1, 1, 1));
+
+        let mut data = Vec::new();
+        snapshot.write(&mut data).unwrap();
+        assert_eq!(Snapshot::read(&mut &*data).unwrap(), snapshot);
+
+        let newer = String::from_utf8(data.clone()).unwrap().replace("\"version\": 2", "\"version\": 3");
+        assert!(Snapshot::read(&mut newer.as_bytes()).is_err());
+
+        // snapshots written before chats were backed up
+        let mut old = Json::from_str(&*String::from_utf8(data).unwrap()).unwrap();
+        old.as_object_mut().unwrap().remove("chats");
+        old.as_object_mut().unwrap().insert("version".to_string(), Json::U64(1));
+        assert!(Snapshot::read(&mut old.to_string().as_bytes()).unwrap().chats.is_empty());
+    }
+
+    #[test]
+    fn test_restore() {
+        let source = MockServer::start("source-key").unwrap();
+        golden(&mut source.api());
+        let snapshot = Snapshot::take(&mut source.api()).unwrap();
+
+        let dest = MockServer::start("dest-key").unwrap();
+        let mut api = dest.api();
+        api.send(&DeviceMsg::new("laptop")).unwrap();
+
+        let plan = snapshot.plan(&mut api).unwrap();
+        assert_eq!(plan.unchanged, 1);
+        assert_eq!(plan.changes.iter().map(|c| c.to_string()).collect::<Vec<_>>(), vec![
+            "+ channel releases (Releases)", "+ subscription releases", "+ contact Ops <Ops@Example.com>", "+ chat Dev@Example.com",
+            "+ device build-01"]);
+        assert!(dest.objects("channels").is_empty());
+
+        let restored = snapshot.restore(&mut api).unwrap();
+        assert_eq!(restored.plan, plan);
+        assert!(restored.failed.is_empty());
+        assert_eq!(dest.objects("subscriptions").len(), 1);
+        assert_eq!(dest.objects("chats").len(), 1);
+        assert_eq!(dest.objects("devices").len(), 2);
+
+        // restoring again changes nothing
+        let again = snapshot.restore(&mut api).unwrap();
+        assert!(again.plan.is_empty());
+        assert_eq!(again.plan.unchanged, 6);
+        assert!(match plan.changes[0] { Change::CreateChannel(ref msg) => msg.description == "New versions", _ => false });
+    }
+    #[test]
+    fn test_apply_taken_tag() {
+        let source = MockServer::start("source-key").unwrap();
+        golden(&mut source.api());
+        let snapshot = Snapshot::take(&mut source.api()).unwrap();
+
+        let dest = MockServer::start("dest-key").unwrap();
+        let mut api = dest.api();
+        let plan = snapshot.plan(&mut api).unwrap();
+
+        // the tag is taken after planning, the other changes are still made
+        api.send(&ChannelMsg::new("releases", "Someone else's releases", "")).unwrap();
+        let failed = plan.apply(&mut api);
+        assert_eq!(failed.iter().map(|&(ref c, _)| c.to_string()).collect::<Vec<_>>(),
+                   vec!["+ channel releases (Releases)", "+ subscription releases"]);
+        assert!(dest.objects("subscriptions").is_empty());
+        assert_eq!((dest.objects("contacts").len(), dest.objects("chats").len(), dest.objects("devices").len()), (1, 1, 2));
+    }
+}
@@ -0,0 +1,116 @@
+//! Minimal command line parser: positional arguments,
+//! `--name value` options and `--flag` switches.
+
+use std::collections::HashMap;
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    positional: Vec<String>,
+    options: HashMap<String, String>,
+    flags: Vec<String>,
+    rest: Vec<String>,
+}
+
+impl Args {
+    /// Parses arguments, taking a value for every option listed in `valued`.
+    /// Everything after `--` is kept verbatim.
+    pub fn parse<I: IntoIterator<Item=String>>(args: I, valued: &[&str]) -> Result<Args, String> {
+        let mut result = Args {
+            positional: Vec::new(),
+            options: HashMap::new(),
+            flags: Vec::new(),
+            rest: Vec::new(),
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                result.rest.extend(args);
+                break;
+            }
+
+            if arg.starts_with("--") && arg.len() > 2 {
+                let (name, value) = match arg.find('=') {
+                    Some(idx) => (arg[2..idx].to_string(), Some(arg[idx + 1..].to_string())),
+                    None => (arg[2..].to_string(), None)
+                };
+
+                if valued.contains(&&*name) {
+                    let value = match value.or_else(|| args.next()) {
+                        Some(v) => v,
+                        None => return Err(format!("option --{} requires a value", name))
+                    };
+                    result.options.insert(name, value);
+                } else if value.is_some() {
+                    return Err(format!("option --{} doesn't take a value", name));
+                } else {
+                    result.flags.push(name);
+                }
+            } else {
+                result.positional.push(arg);
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Takes the next positional argument.
+    pub fn shift(&mut self) -> Option<String> {
+        if self.positional.is_empty() { None } else { Some(self.positional.remove(0)) }
+    }
+
+    pub fn positional(&self) -> &[String] {
+        &*self.positional
+    }
+
+    pub fn rest(&self) -> &[String] {
+        &*self.rest
+    }
+
+    pub fn option(&self, name: &str) -> Option<&str> {
+        self.options.get(name).map(|v| &**v)
+    }
+
+    /// Sets option value unless it was given on the command line.
+    pub fn default_option(&mut self, name: &str, value: String) {
+        self.options.entry(name.to_string()).or_insert(value);
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Args;
+
+    fn parse(args: &[&str]) -> Result<Args, String> {
+        Args::parse(args.iter().map(|s| s.to_string()), &["target", "limit"])
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let mut args = parse(&["--json", "push", "note", "--target", "email:ops@example.com", "--limit=5", "Disk", "full"]).unwrap();
+        assert!(args.flag("json"));
+        assert!(!args.flag("active"));
+        assert_eq!(args.option("target"), Some("email:ops@example.com"));
+        assert_eq!(args.option("limit"), Some("5"));
+        assert_eq!(args.shift(), Some("push".to_string()));
+        assert_eq!(args.shift(), Some("note".to_string()));
+        assert_eq!(args.positional(), &["Disk".to_string(), "full".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_rest() {
+        let args = parse(&["run", "--", "make", "--jobs", "4"]).unwrap();
+        assert_eq!(args.positional(), &["run".to_string()]);
+        assert_eq!(args.rest(), &["make".to_string(), "--jobs".to_string(), "4".to_string()]);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(parse(&["--target"]), Err("option --target requires a value".to_string()));
+        assert_eq!(parse(&["--json=yes"]), Err("option --json doesn't take a value".to_string()));
+    }
+}
@@ -0,0 +1,276 @@
+//! `pb listen`: prints pushes and ephemerals (mirrored notifications) as they
+//! arrive and optionally runs a hook command for each of the pushes.
+
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use rustc_serialize::json::{self, Json};
+
+use pb::{PbAPI, PbError, Push, PushData, Event};
+use pb::objects::{Timestamp, DeviceIden};
+
+use args::Args;
+use {CliResult, find_device, format_push};
+
+static RECONNECT_DELAY: u64 = 5;
+
+#[derive(Debug, PartialEq)]
+pub struct Filter {
+    types: Vec<String>,
+    sender: Option<String>,
+    device: Option<DeviceIden>,
+}
+
+impl Filter {
+    pub fn matches(&self, push: &Push) -> bool {
+        (self.types.is_empty() || self.types.iter().any(|t| t == push.data.type_name())) &&
+        self.sender.as_ref().map_or(true, |s| {
+            push.sender_email_normalized.as_ref().or(push.sender_email.as_ref()).map_or(false, |e| e.to_lowercase() == *s) ||
+            push.sender_name.as_ref().map_or(false, |n| n.to_lowercase() == *s)
+        }) &&
+        self.device.as_ref().map_or(true, |d| push.target_device_iden.as_ref() == Some(d))
+    }
+
+    /// Ephemerals have no sender email or name, so they never pass a sender filter.
+    pub fn matches_ephemeral(&self, ephemeral: &Json) -> bool {
+        let field = |name: &str| ephemeral.find(name).and_then(|v| v.as_string());
+        (self.types.is_empty() || self.types.iter().any(|t| Some(&**t) == field("type"))) &&
+        self.sender.is_none() &&
+        self.device.as_ref().map_or(true, |d| field("target_device_iden") == Some(d.as_str()))
+    }
+}
+
+/// Same columns as `format_push`, with notification id instead of iden.
+pub fn format_ephemeral(ephemeral: &Json) -> String {
+    let field = |name: &str| ephemeral.find(name).and_then(|v| v.as_string()).unwrap_or("");
+    let title = match (field("application_name"), field("title")) {
+        ("", title) => title.to_string(),
+        (app, "") => app.to_string(),
+        (app, title) => format!("{}: {}", app, title)
+    };
+    format!("{}\t{}\t{}\t{}", field("notification_id"), field("type"), title, field("body").replace("\n", " "))
+}
+
+/// Describes a push with `PB_*` environment variables for hook commands.
+pub fn push_env(push: &Push) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("PB_IDEN", push.iden.to_string()),
+        ("PB_TYPE", push.data.type_name().to_string()),
+        ("PB_CREATED", push.created.to_string()),
+    ];
+
+    let optional = [
+        ("PB_TITLE", push.title.as_ref().map(|s| &**s)),
+        ("PB_BODY", push.body.as_ref().map(|s| &**s)),
+        ("PB_SENDER_NAME", push.sender_name.as_ref().map(|s| &**s)),
+        ("PB_SENDER_EMAIL", push.sender_email.as_ref().map(|s| &**s)),
+        ("PB_SOURCE_DEVICE_IDEN", push.source_device_iden.as_ref().map(|i| i.as_str())),
+        ("PB_TARGET_DEVICE_IDEN", push.target_device_iden.as_ref().map(|i| i.as_str())),
+        ("PB_CHANNEL_IDEN", push.channel_iden.as_ref().map(|i| i.as_str())),
+    ];
+    for &(name, value) in optional.iter() {
+        if let Some(value) = value {
+            env.push((name, value.to_string()));
+        }
+    }
+
+    match push.data {
+        PushData::Link(Some(ref url)) => env.push(("PB_URL", url.to_string())),
+        PushData::File { ref file_name, ref file_type, ref file_url, .. } => {
+            env.push(("PB_FILE_NAME", file_name.clone()));
+            env.push(("PB_FILE_TYPE", file_type.clone()));
+            env.push(("PB_FILE_URL", file_url.to_string()));
+        },
+        PushData::Address(ref address) => env.push(("PB_ADDRESS", address.clone())),
+        _ => ()
+    }
+
+    env
+}
+
+fn run_hook(command: &str, push: &Push) -> CliResult<()> {
+    let mut child = Command::new("sh");
+    child.arg("-c").arg(command).stdin(Stdio::piped());
+    for (name, value) in push_env(push) {
+        child.env(name, value);
+    }
+
+    let mut child = try!(child.spawn());
+    if let Some(mut stdin) = child.stdin.take() {
+        try!(stdin.write_all(try!(json::encode(push)).as_bytes()));
+    }
+    let status = try!(child.wait());
+    if !status.success() {
+        return Err(From::from(format!("hook failed for push {}: {}", push.iden, status)));
+    }
+    Ok(())
+}
+
+struct Listener<'a> {
+    args: &'a Args,
+    filter: Filter,
+    since: Timestamp,
+}
+
+impl<'a> Listener<'a> {
+    fn handle(&self, push: &Push) {
+        if !self.filter.matches(push) {
+            return;
+        }
+
+        if self.args.flag("json") {
+            println!("{}", json::encode(push).unwrap_or(String::new()));
+        } else {
+            println!("{}", format_push(push));
+        }
+        let _ = io::stdout().flush();
+
+        if let Some(command) = self.args.option("exec") {
+            if let Err(e) = run_hook(command, push) {
+                let _ = writeln!(io::stderr(), "pb: {}", e);
+            }
+        }
+    }
+
+    fn handle_ephemeral(&self, ephemeral: &Json) {
+        if !self.filter.matches_ephemeral(ephemeral) {
+            return;
+        }
+
+        if self.args.flag("json") {
+            println!("{}", ephemeral);
+        } else {
+            println!("{}", format_ephemeral(ephemeral));
+        }
+        let _ = io::stdout().flush();
+    }
+
+    /// Fetches pushes created since the last tickle.
+    fn fetch(&mut self, api: &mut PbAPI) -> CliResult<()> {
+        let (mut pushes, mut cursor) = try!(api.load_since::<Push>(self.since));
+        while let Some(c) = cursor {
+            let (next, c) = try!(api.load_page::<Push>(None, Some(self.since), false, Some(c)));
+            pushes.extend(next);
+            cursor = c;
+        }
+
+        let since = self.since;
+        self.since = pushes.iter().fold(since, |acc, p| acc.max(p.modified));
+
+        let mut created: Vec<Push> = pushes.into_iter().filter(|p| p.active && p.created > since).collect();
+        created.sort_by_key(|p| p.created);
+        for push in created {
+            self.handle(&push);
+        }
+        Ok(())
+    }
+}
+
+pub fn listen(api: &mut PbAPI, args: &Args) -> CliResult<()> {
+    let device = match args.option("device") {
+        Some(name) => Some(try!(find_device(api, name)).iden().clone()),
+        None => None
+    };
+    let filter = Filter {
+        types: args.option("type").map(|t| t.split(',').map(|s| s.trim().to_string()).collect()).unwrap_or(Vec::new()),
+        sender: args.option("sender").map(|s| s.to_lowercase()),
+        device: device,
+    };
+
+    // start from the newest push known to server, so that clock skew doesn't matter
+    let since = match try!(api.loadn::<Push>(1)).0.first() {
+        Some(push) => push.modified,
+        None => Timestamp::now()
+    };
+    let mut listener = Listener { args: args, filter: filter, since: since };
+
+    loop {
+        match api.stream() {
+            Ok(stream) => for event in stream {
+                match event {
+                    Ok(Event::PushTickle) => if let Err(e) = listener.fetch(api) {
+                        let _ = writeln!(io::stderr(), "pb: {}", e);
+                    },
+                    Ok(Event::Ephemeral(ref ephemeral)) => listener.handle_ephemeral(ephemeral),
+                    Ok(_) => (),
+                    // other kinds of messages are of no interest here
+                    Err(PbError::Js(_)) => (),
+                    Err(e) => {
+                        let _ = writeln!(io::stderr(), "pb: {}", e);
+                        break;
+                    }
+                }
+            },
+            Err(e) => {
+                let _ = writeln!(io::stderr(), "pb: {}", e);
+            }
+        }
+        thread::sleep(Duration::from_secs(RECONNECT_DELAY));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json::{self, Json};
+    use pb::Push;
+    use pb::objects::DeviceIden;
+    use super::{Filter, format_ephemeral, push_env};
+
+    fn link_push() -> Push {
+        json::decode("{
+            \"iden\": \"ujxPklLhvyKsjAvkMyTVh6\",
+            \"type\": \"link\",
+            \"title\": \"Release notes\",
+            \"url\": \"https://github.com/kstep/rust-pb\",
+            \"created\": 1411595195.1267679,
+            \"modified\": 1411595195.1267679,
+            \"active\": true,
+            \"dismissed\": false,
+            \"sender_name\": \"Ryan Oldenburg\",
+            \"sender_email\": \"Ryan@pushbullet.com\",
+            \"target_device_iden\": \"ujpah72o0sjAoRtnM0jc\"
+        }").unwrap()
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let push = link_push();
+        let filter = |types: &[&str], sender: Option<&str>, device: Option<&str>| Filter {
+            types: types.iter().map(|t| t.to_string()).collect(),
+            sender: sender.map(|s| s.to_string()),
+            device: device.map(DeviceIden::from),
+        };
+
+        assert!(filter(&[], None, None).matches(&push));
+        assert!(filter(&["note", "link"], Some("ryan@pushbullet.com"), Some("ujpah72o0sjAoRtnM0jc")).matches(&push));
+        assert!(filter(&[], Some("ryan oldenburg"), None).matches(&push));
+        assert!(!filter(&["note"], None, None).matches(&push));
+        assert!(!filter(&[], Some("someone@example.com"), None).matches(&push));
+        assert!(!filter(&[], None, Some("ujother")).matches(&push));
+
+        let mirror = Json::from_str("{\"type\": \"mirror\", \"title\": \"Call\", \"source_device_iden\": \"ujphone\"}").unwrap();
+        assert!(filter(&[], None, None).matches_ephemeral(&mirror));
+        assert!(filter(&["mirror"], None, None).matches_ephemeral(&mirror));
+        assert!(!filter(&["note"], None, None).matches_ephemeral(&mirror));
+        assert!(!filter(&[], Some("ryan@pushbullet.com"), None).matches_ephemeral(&mirror));
+    }
+
+    #[test]
+    fn test_format_ephemeral() {
+        let mirror = Json::from_str("{\"type\": \"mirror\", \"notification_id\": \"42\", \"application_name\": \"Messages\",
+                                      \"title\": \"Alice\", \"body\": \"Running late\\nsorry\"}").unwrap();
+        assert_eq!(format_ephemeral(&mirror), "42\tmirror\tMessages: Alice\tRunning late sorry");
+    }
+
+    #[test]
+    fn test_push_env() {
+        let env = push_env(&link_push());
+        assert!(env.contains(&("PB_TYPE", "link".to_string())));
+        assert!(env.contains(&("PB_TITLE", "Release notes".to_string())));
+        assert!(env.contains(&("PB_URL", "https://github.com/kstep/rust-pb".to_string())));
+        assert!(env.contains(&("PB_SENDER_EMAIL", "Ryan@pushbullet.com".to_string())));
+        assert!(!env.iter().any(|&(name, _)| name == "PB_BODY"));
+    }
+}
@@ -0,0 +1,402 @@
+extern crate pb;
+extern crate rustc_serialize;
+extern crate url;
+
+mod args;
+mod listen;
+mod run;
+mod schedule;
+
+use std::env;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::process;
+
+use rustc_serialize::Encodable;
+use rustc_serialize::json;
+use url::Url;
+
+use pb::{PbAPI, Config, Export, Format, Snapshot, Janitor, Policy, Timestamp, PushIden, ClientIden, PushMsg, DeviceMsg, TargetIden, Push, PushData, ListItem, Device, Contact, Subscription};
+
+use args::Args;
+
+static USAGE: &'static str = "Usage: pb [--key KEY | --profile NAME] [--json] <command> [options]
+
+Commands:
+    push note [--title TITLE] [--target TARGET] [BODY...]
+    push link [--title TITLE] [--body BODY] [--target TARGET] URL
+    push file [--body BODY] [--target TARGET] PATH
+    push list [--title TITLE] [--target TARGET] ITEM...
+    pushes ls [--since TIMESTAMP] [--limit N] [--active]
+    pushes rm IDEN...
+    pushes clean [--older-than DAYS] [--dismissed] [--max-per-device N]
+        [--type TYPE,...] [--sender EMAIL,...] [--concurrency N] [--dry-run]
+    devices ls
+    devices add NICKNAME
+    devices rm IDEN|NICKNAME...
+    contacts
+    subscriptions
+    me
+    export [--format jsonl|csv|html] [--output FILE] [--files DIR] [--inactive]
+    import [--target TARGET] FILE
+    backup [--output FILE]
+    restore [--dry-run] FILE
+    listen [--type TYPE,...] [--sender EMAIL|NAME] [--device NICKNAME] [--exec COMMAND]
+    run [--target TARGET] [--title TEMPLATE] [--body TEMPLATE] [--lines N]
+        [--on-failure] [--longer-than SECONDS] -- COMMAND...
+    schedule add (--at TIME | --cron EXPR) [--missed skip|once|all]
+        [--title TITLE] [--target TARGET] [BODY...]
+    schedule ls
+    schedule rm ID...
+    schedule run
+
+Targets:
+    device:NICKNAME, email:ADDRESS, channel:TAG, client:IDEN
+    (pushes go to all of your devices by default)
+
+With --exec, the command is run by `sh -c` for every matching push,
+with the push as JSON on its stdin and in PB_* environment variables.
+
+`pushes clean` deletes pushes of given types and senders which are older than
+DAYS, dismissed, or beyond N newest ones sent from the same device.
+
+`export` writes all pushes to FILE (stdout by default), downloading contents
+of file pushes into DIR when --files is given. `import` re-creates note, link
+and list pushes from a JSON Lines export.
+
+`backup` saves devices, contacts, chats, channels, subscriptions and grants of the
+account. `restore` re-creates channels, subscriptions, contacts, chats and devices
+missing from the account; with --dry-run it only lists them.
+
+With `run`, title and body templates may refer to {command}, {status}, {code},
+{duration}, {seconds} and {output} (the last --lines lines of output, 10 by default).
+With --on-failure or --longer-than, only jobs which failed or ran at least
+that long are reported.
+
+`schedule add` saves a note push to send at TIME (YYYY-MM-DD HH:MM in UTC, unix
+timestamp or +N[smhd] from now) or on a cron schedule (five fields in UTC,
+e.g. `0 9 * * mon-fri`). Pushes are sent by `schedule run`; --missed tells
+whether runs missed while it was stopped are skipped (the default), sent once,
+or sent for each of them. Jobs are kept in $XDG_DATA_HOME/pb/schedule.json,
+or in --file FILE given to all schedule commands.
+
+Unless --key is given, API key, base URL and default target are taken from
+the profile in $XDG_CONFIG_HOME/pb/config.toml (PB_PROFILE or `default` key
+selects it), with PB_API_KEY, PB_BASE_URL and PB_TARGET environment overrides.
+PB_API_KEY doesn't apply to a profile selected with --profile or PB_PROFILE.";
+
+static VALUED_OPTIONS: &'static [&'static str] = &["key", "profile", "target", "title", "body", "since", "limit", "type", "sender", "device", "exec", "lines", "longer-than", "format", "output", "files", "older-than", "max-per-device", "concurrency", "at", "cron", "missed", "file"];
+static DEFAULT_LIMIT: usize = 20;
+
+pub type CliResult<T> = Result<T, Box<Error>>;
+
+fn main() {
+    let args = match Args::parse(env::args().skip(1), VALUED_OPTIONS) {
+        Ok(args) => args,
+        Err(e) => fail(&*e)
+    };
+
+    if args.flag("help") || args.positional().is_empty() {
+        println!("{}", USAGE);
+        return;
+    }
+
+    if let Err(e) = run(args) {
+        fail(&*e.to_string());
+    }
+}
+
+fn fail(message: &str) -> ! {
+    let _ = writeln!(io::stderr(), "pb: {}", message);
+    process::exit(1);
+}
+
+fn run(mut args: Args) -> CliResult<()> {
+    let command = args.shift().unwrap_or(String::new());
+    let sub = args.shift();
+
+    // scheduled pushes are listed and removed without an account
+    match (&*command, sub.as_ref().map(|s| &**s)) {
+        ("schedule", Some(local @ "ls")) | ("schedule", Some(local @ "rm")) => return schedule::local(local, &args),
+        _ => ()
+    }
+    let mut api = try!(connect(&mut args));
+
+    match (&*command, sub.as_ref().map(|s| &**s)) {
+        ("push", Some(kind)) => push(&mut api, kind, &args),
+        ("pushes", Some("ls")) => list_pushes(&mut api, &args),
+        ("pushes", Some("rm")) => {
+            for iden in args.positional() {
+                try!(api.remove::<Push>(PushIden::from(&**iden)));
+            }
+            Ok(())
+        },
+        ("pushes", Some("clean")) => clean_pushes(&mut api, &args),
+        ("devices", Some("ls")) | ("devices", None) => {
+            let devices: Vec<Device> = try!(api.load_all::<Device>()).into_iter().filter(|d| d.is_active()).collect();
+            print_all(&args, &devices, |d| format!("{}\t{}\t{}", d.iden(), d.nickname(), d.icon().map(|i| i.as_str()).unwrap_or("")))
+        },
+        ("devices", Some("add")) => {
+            let nickname = args.positional().join(" ");
+            if nickname.is_empty() {
+                return Err(From::from("device nickname is required"));
+            }
+            let device = try!(api.send(&DeviceMsg::new(nickname)));
+            print_one(&args, &device, |d| d.iden().to_string())
+        },
+        ("devices", Some("rm")) => {
+            for name in args.positional() {
+                let iden = try!(find_device(&mut api, name)).iden().clone();
+                try!(api.remove::<Device>(iden));
+            }
+            Ok(())
+        },
+        ("contacts", None) => {
+            let contacts: Vec<Contact> = try!(api.load_all::<Contact>()).into_iter().filter(|c| c.active).collect();
+            print_all(&args, &contacts, |c| format!("{}\t{}\t{}", c.iden, c.email, c.name))
+        },
+        ("subscriptions", None) => {
+            let subscriptions: Vec<Subscription> = try!(api.load_all::<Subscription>()).into_iter().filter(|s| s.active).collect();
+            print_all(&args, &subscriptions, |s| match s.channel {
+                Some(ref c) => format!("{}\t{}\t{}", s.iden, c.tag, c.name),
+                None => s.iden.to_string()
+            })
+        },
+        ("export", None) => export(&mut api, &args),
+        ("import", Some(path)) => {
+            let target = try!(resolve_target(&mut api, args.option("target")));
+            let summary = try!(pb::export::import(&mut api, BufReader::new(try!(File::open(path))), &target));
+            println!("created {} pushes, skipped {}, already imported {}", summary.created, summary.skipped, summary.existing);
+            Ok(())
+        },
+        ("backup", None) => {
+            let snapshot = try!(Snapshot::take(&mut api));
+            match args.option("output") {
+                Some(path) => try!(snapshot.write(&mut try!(File::create(path)))),
+                None => try!(snapshot.write(&mut io::stdout()))
+            }
+            Ok(())
+        },
+        ("restore", Some(path)) => {
+            let snapshot = try!(Snapshot::read(&mut try!(File::open(path))));
+            let plan = try!(snapshot.plan(&mut api));
+            for change in &plan.changes {
+                println!("{}", change);
+            }
+            let failed = if args.flag("dry-run") { Vec::new() } else { plan.apply(&mut api) };
+            for &(ref change, ref error) in &failed {
+                let _ = writeln!(io::stderr(), "pb: failed: {}: {}", change, error);
+            }
+            println!("{} to create, {} unchanged", plan.changes.len(), plan.unchanged);
+            if failed.is_empty() {
+                Ok(())
+            } else {
+                Err(From::from(format!("{} changes were not made", failed.len())))
+            }
+        },
+        ("listen", None) => listen::listen(&mut api, &args),
+        ("run", None) => run::run(&mut api, &args),
+        ("schedule", Some(sub)) => schedule::schedule(&mut api, sub, &args),
+        ("me", None) => {
+            let me = try!(api.me());
+            print_one(&args, &me, |a| format!("{}\t{}\t{}", a.iden(), a.email(), a.name()))
+        },
+        _ => Err(From::from(format!("unknown command: {} {}\n\n{}", command, sub.unwrap_or(String::new()), USAGE)))
+    }
+}
+
+fn connect(args: &mut Args) -> CliResult<PbAPI> {
+    if let Some(key) = args.option("key") {
+        return Ok(PbAPI::new(key));
+    }
+
+    let config = try!(Config::load());
+    let profile = args.option("profile").map(|p| p.to_string());
+    if let Some(target) = try!(config.profile(profile.as_ref().map(|p| &**p))).default_target {
+        args.default_option("target", target);
+    }
+    Ok(try!(config.api(profile.as_ref().map(|p| &**p))))
+}
+
+pub fn find_device(api: &mut PbAPI, name: &str) -> CliResult<Device> {
+    try!(api.load_all::<Device>()).into_iter()
+        .find(|d| d.is_active() && (*d.iden() == *name || d.nickname() == name))
+        .ok_or_else(|| From::from(format!("no device with nickname or iden {:?}", name)))
+}
+
+pub fn resolve_target(api: &mut PbAPI, spec: Option<&str>) -> CliResult<TargetIden> {
+    let spec = match spec {
+        Some(s) => s,
+        None => return Ok(TargetIden::CurrentUser)
+    };
+
+    let (kind, value) = match spec.find(':') {
+        Some(idx) => (&spec[..idx], spec[idx + 1..].to_string()),
+        None => return Err(From::from(format!("invalid target {:?}, expected KIND:VALUE", spec)))
+    };
+
+    match kind {
+        "device" => Ok(TargetIden::DeviceIden(try!(find_device(api, &*value)).iden().clone())),
+        "email" => Ok(TargetIden::ContactEmail(value)),
+        "channel" => Ok(TargetIden::ChannelTag(value)),
+        "client" => Ok(TargetIden::ClientIden(ClientIden::from(value))),
+        _ => Err(From::from(format!("unknown target kind {:?}", kind)))
+    }
+}
+
+fn push(api: &mut PbAPI, kind: &str, args: &Args) -> CliResult<()> {
+    let target = try!(resolve_target(api, args.option("target")));
+    let text = args.positional().join(" ");
+
+    let msg = match kind {
+        "note" => PushMsg::new(target).body(args.option("body").map(|b| b.to_string()).unwrap_or(text)),
+        "link" => {
+            let url = try!(Url::parse(&*text));
+            PushMsg::link(target, url)
+        },
+        "file" => {
+            if text.is_empty() {
+                return Err(From::from("file path is required"));
+            }
+            PushMsg::file(target, try!(api.upload(&*text)))
+        },
+        "list" => PushMsg::list(target, args.positional().iter().map(|i| ListItem::new(&**i, false)).collect()),
+        _ => return Err(From::from(format!("unknown push type: {}", kind)))
+    };
+
+    let msg = match (kind, args.option("body")) {
+        ("note", _) | (_, None) => msg,
+        (_, Some(body)) => msg.body(body.to_string())
+    };
+    let msg = match args.option("title") {
+        Some(title) => msg.title(title.to_string()),
+        None => msg
+    };
+
+    let push = try!(api.send(&msg));
+    print_one(args, &push, |p| p.iden.to_string())
+}
+
+fn list_pushes(api: &mut PbAPI, args: &Args) -> CliResult<()> {
+    let limit = match args.option("limit") {
+        Some(l) => try!(l.parse::<usize>()),
+        None => DEFAULT_LIMIT
+    };
+    let since = match args.option("since") {
+        Some(s) => Some(try!(s.parse::<Timestamp>())),
+        None => None
+    };
+    let active = args.flag("active");
+
+    let mut pushes = Vec::new();
+    let (mut page, mut cursor) = try!(api.load_page::<Push>(Some(limit), since, active, None));
+    loop {
+        pushes.extend(page);
+        match cursor {
+            Some(c) if pushes.len() < limit => {
+                let (next, c) = try!(api.load_page::<Push>(Some(limit), since, active, Some(c)));
+                page = next;
+                cursor = c;
+            },
+            _ => break
+        }
+    }
+    pushes.truncate(limit);
+
+    print_all(args, &pushes, format_push)
+}
+
+fn clean_pushes(api: &mut PbAPI, args: &Args) -> CliResult<()> {
+    let mut policy = Policy::new();
+    if let Some(days) = args.option("older-than") {
+        policy = policy.older_than_days(try!(days.parse::<u64>()));
+    }
+    if args.flag("dismissed") {
+        policy = policy.dismissed();
+    }
+    if let Some(count) = args.option("max-per-device") {
+        policy = policy.max_per_device(try!(count.parse::<usize>()));
+    }
+    for typ in args.option("type").into_iter().flat_map(|t| t.split(',')) {
+        policy = policy.typ(typ);
+    }
+    for sender in args.option("sender").into_iter().flat_map(|s| s.split(',')) {
+        policy = policy.sender(sender);
+    }
+
+    let janitor = Janitor::new(policy).concurrency(match args.option("concurrency") {
+        Some(n) => try!(n.parse::<usize>()),
+        None => 1
+    });
+    let janitor = if args.flag("dry-run") { janitor.dry_run() } else { janitor };
+
+    let report = try!(janitor.run(api));
+    for candidate in &report.candidates {
+        println!("{}\t{}", candidate.reason, format_push(&candidate.push));
+    }
+    for &(ref iden, ref error) in &report.failed {
+        let _ = writeln!(io::stderr(), "pb: failed to delete {}: {}", iden, error);
+    }
+    println!("scanned {}, matched {}, deleted {}", report.scanned, report.candidates.len(), report.deleted);
+    if report.failed.is_empty() {
+        Ok(())
+    } else {
+        Err(From::from(format!("{} pushes were not deleted", report.failed.len())))
+    }
+}
+
+fn export(api: &mut PbAPI, args: &Args) -> CliResult<()> {
+    let format = try!(args.option("format").unwrap_or("jsonl").parse::<Format>());
+    let export = Export::new(format);
+    let export = match args.option("files") {
+        Some(dir) => export.download_files(dir),
+        None => export
+    };
+    let export = if args.flag("inactive") { export.include_inactive() } else { export };
+
+    let summary = match args.option("output") {
+        Some(path) => try!(export.write(api, &mut try!(File::create(path)))),
+        None => try!(export.write(api, &mut io::stdout()))
+    };
+    for &(ref iden, ref error) in &summary.failed {
+        let _ = writeln!(io::stderr(), "pb: failed to download file of {}: {}", iden, error);
+    }
+    let _ = writeln!(io::stderr(), "exported {} pushes", summary.exported);
+    if summary.failed.is_empty() {
+        Ok(())
+    } else {
+        Err(From::from(format!("{} files were not downloaded", summary.failed.len())))
+    }
+}
+
+pub fn format_push(push: &Push) -> String {
+    let content = match push.data {
+        PushData::Link(Some(ref url)) => url.to_string(),
+        PushData::File { ref file_name, .. } => file_name.clone(),
+        PushData::List(ref items) => items.iter().map(|i| format!("[{}] {}", if i.is_checked() { "x" } else { " " }, i.to_string())).collect::<Vec<_>>().join(", "),
+        PushData::Address(ref address) => address.clone(),
+        _ => push.body.clone().unwrap_or(String::new())
+    };
+    format!("{}\t{}\t{}\t{}", push.iden, push.data.type_name(), push.title.as_ref().map(|t| &**t).unwrap_or(""), content.replace("\n", " "))
+}
+
+pub fn print_one<T: Encodable, F: Fn(&T) -> String>(args: &Args, obj: &T, format: F) -> CliResult<()> {
+    if args.flag("json") {
+        println!("{}", json::as_pretty_json(obj));
+    } else {
+        println!("{}", format(obj));
+    }
+    Ok(())
+}
+
+pub fn print_all<T: Encodable, F: Fn(&T) -> String>(args: &Args, objs: &Vec<T>, format: F) -> CliResult<()> {
+    if args.flag("json") {
+        println!("{}", json::as_pretty_json(objs));
+    } else {
+        for obj in objs {
+            println!("{}", format(obj));
+        }
+    }
+    Ok(())
+}
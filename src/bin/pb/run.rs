@@ -0,0 +1,210 @@
+//! `pb run`: runs a command and sends a push when it finishes.
+
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::process::{self, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pb::{PbAPI, PushMsg};
+
+use args::Args;
+use {CliResult, resolve_target};
+
+static DEFAULT_TITLE: &'static str = "{command} {status}";
+static DEFAULT_BODY: &'static str = "Took {duration}, exit code {code}\n\n{output}";
+static DEFAULT_LINES: usize = 10;
+
+/// Outcome of a finished job, used to render notification templates.
+#[derive(Debug)]
+pub struct Report {
+    pub command: String,
+    pub code: Option<i32>,
+    pub duration: Duration,
+    pub output: Vec<String>,
+}
+
+impl Report {
+    pub fn succeeded(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    /// Replaces `{command}`, `{status}`, `{code}`, `{duration}`, `{seconds}`
+    /// and `{output}` placeholders in `template`.
+    pub fn render(&self, template: &str) -> String {
+        let status = match self.code {
+            Some(0) => "succeeded".to_string(),
+            Some(code) => format!("failed with exit code {}", code),
+            None => "was killed by a signal".to_string()
+        };
+
+        // a single pass, so placeholders within the command or its output are left as they are
+        let mut rendered = String::new();
+        let mut rest = template;
+        while let Some(start) = rest.find('{') {
+            rendered.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let end = rest.find('}').map_or(0, |e| e + 1);
+            let value = match &rest[..end] {
+                "{command}" => self.command.clone(),
+                "{status}" => status.clone(),
+                "{code}" => self.code.map(|c| c.to_string()).unwrap_or("none".to_string()),
+                "{duration}" => format_duration(self.duration),
+                "{seconds}" => self.duration.as_secs().to_string(),
+                "{output}" => self.output.join("\n"),
+                _ => {
+                    rendered.push('{');
+                    rest = &rest[1..];
+                    continue;
+                }
+            };
+            rendered.push_str(&*value);
+            rest = &rest[end..];
+        }
+        rendered.push_str(rest);
+        rendered
+    }
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs / 60 % 60, secs % 60) {
+        (0, 0, s) => format!("{}s", s),
+        (0, m, s) => format!("{}m {}s", m, s),
+        (h, m, s) => format!("{}h {}m {}s", h, m, s),
+    }
+}
+
+/// Decides whether to notify about a job. With neither condition set every job is reported,
+/// otherwise the job must either fail (`on_failure`) or run at least `longer_than`.
+pub fn should_notify(report: &Report, on_failure: bool, longer_than: Option<Duration>) -> bool {
+    if !on_failure && longer_than.is_none() {
+        return true;
+    }
+    (on_failure && !report.succeeded()) || longer_than.map_or(false, |d| report.duration >= d)
+}
+
+/// Copies lines from `input` to `output`, keeping the last `limit` of them in `tail`.
+fn tee<R: Read, W: Write>(input: R, mut output: W, tail: Arc<Mutex<VecDeque<String>>>, limit: usize) {
+    for line in BufReader::new(input).lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(_) => break
+        };
+        let _ = writeln!(output, "{}", line);
+
+        let mut tail = tail.lock().unwrap();
+        tail.push_back(line);
+        while tail.len() > limit {
+            tail.pop_front();
+        }
+    }
+}
+
+fn execute(command: &[String], lines: usize) -> CliResult<Report> {
+    let tail = Arc::new(Mutex::new(VecDeque::new()));
+    let started = Instant::now();
+
+    let mut child = try!(Command::new(&*command[0]).args(&command[1..])
+                         .stdout(Stdio::piped()).stderr(Stdio::piped()).spawn());
+
+    let stdout = child.stdout.take().map(|out| {
+        let tail = tail.clone();
+        thread::spawn(move || tee(out, io::stdout(), tail, lines))
+    });
+    let stderr = child.stderr.take().map(|err| {
+        let tail = tail.clone();
+        thread::spawn(move || tee(err, io::stderr(), tail, lines))
+    });
+
+    let status = try!(child.wait());
+    for reader in stdout.into_iter().chain(stderr) {
+        let _ = reader.join();
+    }
+
+    let output = tail.lock().unwrap().iter().cloned().collect();
+    Ok(Report {
+        command: command.join(" "),
+        code: status.code(),
+        duration: started.elapsed(),
+        output: output,
+    })
+}
+
+pub fn run(api: &mut PbAPI, args: &Args) -> CliResult<()> {
+    let command = args.rest();
+    if command.is_empty() {
+        return Err(From::from("command is required: pb run [options] -- COMMAND..."));
+    }
+
+    let lines = match args.option("lines") {
+        Some(n) => try!(n.parse::<usize>()),
+        None => DEFAULT_LINES
+    };
+    let longer_than = match args.option("longer-than") {
+        Some(secs) => Some(Duration::from_secs(try!(secs.parse::<u64>()))),
+        None => None
+    };
+    // resolve target before the job, so that typos don't get noticed hours later
+    let target = try!(resolve_target(api, args.option("target")));
+
+    let report = try!(execute(command, lines));
+
+    if should_notify(&report, args.flag("on-failure"), longer_than) {
+        let msg = PushMsg::new(target)
+            .title(report.render(args.option("title").unwrap_or(DEFAULT_TITLE)))
+            .body(report.render(args.option("body").unwrap_or(DEFAULT_BODY)));
+        try!(api.send(&msg));
+    }
+
+    match report.code {
+        Some(0) => Ok(()),
+        code => process::exit(code.unwrap_or(1))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use super::{Report, format_duration, should_notify};
+
+    fn report(code: Option<i32>, secs: u64) -> Report {
+        Report {
+            command: "make release".to_string(),
+            code: code,
+            duration: Duration::from_secs(secs),
+            output: vec!["compiling".to_string(), "error: linker failed".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(150)), "2m 30s");
+        assert_eq!(format_duration(Duration::from_secs(3 * 3600 + 61)), "3h 1m 1s");
+    }
+
+    #[test]
+    fn test_render_report() {
+        let report = report(Some(2), 3725);
+        assert_eq!(report.render("{command} {status}"), "make release failed with exit code 2");
+        assert_eq!(report.render("{duration} ({seconds}s), code {code}:\n{output}"),
+                   "1h 2m 5s (3725s), code 2:\ncompiling\nerror: linker failed");
+        assert_eq!(Report { code: None, ..report }.render("{status}, code {code}"), "was killed by a signal, code none");
+
+        let report = Report { command: "echo {output} {status".to_string(), ..self::report(Some(2), 1) };
+        assert_eq!(report.render("{command}: {status} {unknown}"), "echo {output} {status: failed with exit code 2 {unknown}");
+    }
+
+    #[test]
+    fn test_should_notify() {
+        let hour = Some(Duration::from_secs(3600));
+        assert!(should_notify(&report(Some(0), 1), false, None));
+        assert!(!should_notify(&report(Some(0), 1), true, None));
+        assert!(should_notify(&report(Some(1), 1), true, None));
+        assert!(!should_notify(&report(Some(0), 60), false, hour));
+        assert!(should_notify(&report(Some(0), 7200), false, hour));
+        assert!(should_notify(&report(None, 60), true, hour));
+    }
+}
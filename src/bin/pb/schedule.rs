@@ -0,0 +1,145 @@
+//! `pb schedule`: pushes sent later, once or on a cron schedule.
+
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use pb::{PbAPI, PushMsg, Scheduler, Recurrence, Missed, Timestamp};
+
+use args::Args;
+use {CliResult, resolve_target, print_all};
+
+/// Default place of the job file, `$XDG_DATA_HOME/pb/schedule.json`.
+fn default_path() -> CliResult<PathBuf> {
+    env::var_os("XDG_DATA_HOME").map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".local").join("share")))
+        .map(|dir| dir.join("pb").join("schedule.json"))
+        .ok_or_else(|| From::from("can't find home directory, use --file"))
+}
+
+fn open(args: &Args) -> CliResult<Scheduler> {
+    let path = match args.option("file") {
+        Some(path) => PathBuf::from(path),
+        None => try!(default_path())
+    };
+    if let Some(dir) = path.parent() {
+        try!(fs::create_dir_all(dir));
+    }
+    Ok(try!(Scheduler::open(path)))
+}
+
+/// Parses `--at` time: `YYYY-MM-DD HH:MM` (or with `T`) in UTC,
+/// unix timestamp, or `+N` seconds from `now` with `s`, `m`, `h` or `d` suffix.
+fn parse_at(spec: &str, now: Timestamp) -> Option<Timestamp> {
+    let spec = spec.trim();
+    if spec.starts_with('+') {
+        let (number, unit) = match spec.chars().last() {
+            Some('s') => (&spec[1..spec.len() - 1], 1),
+            Some('m') => (&spec[1..spec.len() - 1], 60),
+            Some('h') => (&spec[1..spec.len() - 1], 3600),
+            Some('d') => (&spec[1..spec.len() - 1], 86400),
+            _ => (&spec[1..], 1)
+        };
+        return number.parse::<u64>().ok().map(|n| now + Duration::from_secs(n * unit));
+    }
+    if let Ok(ts) = spec.parse::<Timestamp>() {
+        return Some(ts);
+    }
+
+    let parts: Vec<&str> = spec.split(|c| c == '-' || c == ' ' || c == 'T' || c == ':').collect();
+    let numbers: Vec<u32> = parts.iter().filter_map(|p| p.parse().ok()).collect();
+    if parts.len() != 5 || numbers.len() != 5 {
+        return None;
+    }
+    let (year, month, day, hour, minute) = (numbers[0], numbers[1], numbers[2], numbers[3], numbers[4]);
+    if month < 1 || month > 12 || day < 1 || day > 31 || hour > 23 || minute > 59 {
+        return None;
+    }
+    Some(Timestamp::from_utc(year as i64, month, day, hour, minute))
+}
+
+fn add(api: &mut PbAPI, args: &Args) -> CliResult<()> {
+    let recurrence = match (args.option("at"), args.option("cron")) {
+        (Some(at), None) => Recurrence::Once(try!(parse_at(at, Timestamp::now())
+            .ok_or_else(|| format!("invalid time {:?}, expected YYYY-MM-DD HH:MM (UTC), unix timestamp or +N[smhd]", at)))),
+        (None, Some(cron)) => Recurrence::Cron(try!(cron.parse())),
+        _ => return Err(From::from("either --at or --cron is required"))
+    };
+    let missed = try!(args.option("missed").unwrap_or("skip").parse::<Missed>());
+
+    let body = args.positional().join(" ");
+    if body.is_empty() && args.option("title").is_none() {
+        return Err(From::from("title or body is required"));
+    }
+
+    let target = try!(resolve_target(api, args.option("target")));
+    let msg = PushMsg::new(target);
+    let msg = if body.is_empty() { msg } else { msg.body(body) };
+    let msg = match args.option("title") {
+        Some(title) => msg.title(title.to_string()),
+        None => msg
+    };
+
+    let mut scheduler = try!(open(args));
+    let id = try!(scheduler.add(&msg, recurrence, missed));
+    println!("{}", id);
+    Ok(())
+}
+
+pub fn schedule(api: &mut PbAPI, sub: &str, args: &Args) -> CliResult<()> {
+    match sub {
+        "add" => add(api, args),
+        "run" => {
+            let mut scheduler = try!(open(args));
+            Ok(try!(scheduler.run(api, |tick| for &(ref id, ref error) in &tick.failed {
+                let _ = writeln!(io::stderr(), "pb: failed to send scheduled push {}: {}", id, error);
+            })))
+        },
+        _ => local(sub, args)
+    }
+}
+
+/// Commands which only change the job file and don't need an API key.
+pub fn local(sub: &str, args: &Args) -> CliResult<()> {
+    match sub {
+        "ls" => {
+            let scheduler = try!(open(args));
+            let jobs = scheduler.jobs().into_iter().cloned().collect();
+            print_all(args, &jobs, |j| format!("{}\t{}\t{}\t{}\t{}", j.id, j.next.format_utc(), j.recurrence,
+                                                j.title.as_ref().map(|t| &**t).unwrap_or(""), j.body.as_ref().map(|b| &**b).unwrap_or("")))
+        },
+        "rm" => {
+            let mut scheduler = try!(open(args));
+            for id in args.positional() {
+                if try!(scheduler.remove(id)).is_none() {
+                    return Err(From::from(format!("no scheduled push {:?}", id)));
+                }
+            }
+            Ok(())
+        },
+        _ => Err(From::from(format!("unknown schedule command: {}", sub)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pb::Timestamp;
+    use super::parse_at;
+
+    #[test]
+    fn test_parse_at() {
+        let now = Timestamp::from(1700000000.0);
+        assert_eq!(parse_at("2024-03-15 09:30", now), Some(Timestamp::from(1710495000.0)));
+        assert_eq!(parse_at("2024-03-15T09:30", now), Some(Timestamp::from(1710495000.0)));
+        assert_eq!(parse_at("1710495000", now), Some(Timestamp::from(1710495000.0)));
+        assert_eq!(parse_at("+90", now), Some(Timestamp::from(1700000090.0)));
+        assert_eq!(parse_at("+15m", now), Some(Timestamp::from(1700000900.0)));
+        assert_eq!(parse_at("+2d", now), Some(Timestamp::from(1700172800.0)));
+
+        for spec in &["tomorrow", "2024-13-01 00:00", "2024-03-15", "+h", "2024-03-15 25:00"] {
+            assert_eq!(parse_at(spec, now), None);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Record and replay of HTTP interactions made by `PbAPI`,
+//! to test against real API responses without network access.
+
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use rustc_serialize::json;
+
+static REDACTED: &'static str = "<REDACTED>";
+
+#[derive(Debug, PartialEq, Clone, RustcEncodable, RustcDecodable)]
+pub struct Interaction {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub body: Option<String>,
+    pub status: u16,
+    pub response: String,
+}
+
+impl Interaction {
+    fn request_line(&self) -> String {
+        if self.query.is_empty() {
+            format!("{} {}", self.method, self.path)
+        } else {
+            format!("{} {}?{}", self.method, self.path, self.query)
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    Record,
+    Replay,
+}
+
+#[derive(Debug)]
+pub struct Cassette {
+    path: PathBuf,
+    mode: Mode,
+    interactions: Vec<Interaction>,
+    played: Vec<bool>,
+}
+
+#[derive(Debug)]
+pub enum CassetteError {
+    Io(io::Error),
+    Format(json::DecoderError),
+    Mismatch { expected: Option<String>, actual: String },
+}
+
+impl From<io::Error> for CassetteError {
+    fn from(e: io::Error) -> CassetteError { CassetteError::Io(e) }
+}
+
+impl From<json::DecoderError> for CassetteError {
+    fn from(e: json::DecoderError) -> CassetteError { CassetteError::Format(e) }
+}
+
+impl error::Error for CassetteError {
+    fn description(&self) -> &str {
+        match *self {
+            CassetteError::Io(ref e) => e.description(),
+            CassetteError::Format(ref e) => e.description(),
+            CassetteError::Mismatch { .. } => "request doesn't match any recorded interaction"
+        }
+    }
+
+    fn cause<'a>(&'a self) -> Option<&'a error::Error> {
+        match *self {
+            CassetteError::Io(ref e) => Some(e as &error::Error),
+            CassetteError::Format(ref e) => Some(e as &error::Error),
+            CassetteError::Mismatch { .. } => None
+        }
+    }
+}
+
+impl fmt::Display for CassetteError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            CassetteError::Io(ref e) => e.fmt(fmt),
+            CassetteError::Format(ref e) => e.fmt(fmt),
+            CassetteError::Mismatch { ref expected, ref actual } => write!(fmt,
+                "request doesn't match any recorded interaction\n- expected: {}\n+ actual:   {}",
+                expected.as_ref().map(|s| &**s).unwrap_or("<end of cassette>"), actual)
+        }
+    }
+}
+
+impl Cassette {
+    /// Starts recording a new cassette, overwriting the file as interactions are captured.
+    pub fn record<P: AsRef<Path>>(path: P) -> Cassette {
+        Cassette {
+            path: path.as_ref().to_path_buf(),
+            mode: Mode::Record,
+            interactions: Vec::new(),
+            played: Vec::new(),
+        }
+    }
+
+    pub fn replay<P: AsRef<Path>>(path: P) -> Result<Cassette, CassetteError> {
+        let mut content = String::new();
+        try!(try!(File::open(path.as_ref())).read_to_string(&mut content));
+        let interactions: Vec<Interaction> = try!(json::decode(&*content));
+        Ok(Cassette {
+            path: path.as_ref().to_path_buf(),
+            mode: Mode::Replay,
+            played: vec![false; interactions.len()],
+            interactions: interactions,
+        })
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn interactions(&self) -> &[Interaction] {
+        &*self.interactions
+    }
+
+    pub fn save(&self) -> Result<(), CassetteError> {
+        let mut file = try!(File::create(&*self.path));
+        try!(write!(file, "{}", json::as_pretty_json(&self.interactions)));
+        Ok(())
+    }
+
+    /// Captures an interaction, replacing every occurrence of `secret` with a placeholder.
+    pub fn push(&mut self, interaction: Interaction, secret: &str) -> Result<(), CassetteError> {
+        let redact = |s: &str| if secret.is_empty() { s.to_string() } else { s.replace(secret, REDACTED) };
+        self.interactions.push(Interaction {
+            method: interaction.method,
+            path: redact(&*interaction.path),
+            query: redact(&*interaction.query),
+            body: interaction.body.as_ref().map(|b| redact(&**b)),
+            status: interaction.status,
+            response: redact(&*interaction.response),
+        });
+        self.save()
+    }
+
+    /// Returns status and response of the first not yet played interaction with given request.
+    pub fn play(&mut self, method: &str, path: &str, query: &str) -> Result<(u16, String), CassetteError> {
+        let found = self.interactions.iter().enumerate()
+            .position(|(i, r)| !self.played[i] && r.method == method && r.path == path && r.query == query);
+
+        match found {
+            Some(idx) => {
+                self.played[idx] = true;
+                Ok((self.interactions[idx].status, self.interactions[idx].response.clone()))
+            },
+            None => {
+                let actual = Interaction {
+                    method: method.to_string(),
+                    path: path.to_string(),
+                    query: query.to_string(),
+                    body: None,
+                    status: 0,
+                    response: String::new(),
+                };
+                Err(CassetteError::Mismatch {
+                    expected: self.played.iter().position(|p| !p).map(|idx| self.interactions[idx].request_line()),
+                    actual: actual.request_line(),
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use hyper::status::StatusCode;
+    use uuid::Uuid;
+    use super::{Cassette, CassetteError, Interaction};
+    use api::{PbAPI, PbError};
+    use testing::MockServer;
+    use objects::{Push, Device};
+    use messages::{PushMsg, TargetIden};
+
+    #[test]
+    fn test_redact_secret() {
+        let path = env::temp_dir().join(format!("pb-test-redact-secret-{}.json", Uuid::new_v4().simple()));
+        let mut cassette = Cassette::record(&path);
+        cassette.push(Interaction {
+            method: "GET".to_string(),
+            path: "users/me".to_string(),
+            query: String::new(),
+            body: None,
+            status: 200,
+            response: "{\"api_key\":\"secret-key\"}".to_string(),
+        }, "secret-key").unwrap();
+
+        let cassette = Cassette::replay(&path).unwrap();
+        assert_eq!(cassette.interactions()[0].response, "{\"api_key\":\"<REDACTED>\"}");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_record_and_replay() {
+        let path = env::temp_dir().join(format!("pb-test-record-and-replay-{}.json", Uuid::new_v4().simple()));
+        let pushed = {
+            let server = MockServer::start("secret-key").unwrap();
+            let mut api = server.api().cassette(Cassette::record(&path));
+            let push = api.send(&PushMsg::new(TargetIden::CurrentUser).title("Recorded")).unwrap();
+            api.load::<Device>().unwrap();
+            api.loadn::<Push>(10).unwrap();
+            push
+        };
+
+        let mut api = PbAPI::new("other-key").cassette(Cassette::replay(&path).unwrap());
+        assert_eq!(api.loadn::<Push>(10).unwrap().0, vec![pushed]);
+        assert_eq!(api.load::<Device>().unwrap().0, vec![]);
+
+        match api.loadn::<Push>(5) {
+            Err(PbError::Cassette(CassetteError::Mismatch { expected, actual })) => {
+                assert_eq!(expected, Some("POST pushes".to_string()));
+                assert_eq!(actual, "GET pushes?limit=5");
+            },
+            other => panic!("Unexpected result: {:?}", other.map(|_| ()))
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_replay_status() {
+        let path = env::temp_dir().join(format!("pb-test-replay-status-{}.json", Uuid::new_v4().simple()));
+        {
+            let server = MockServer::start("secret-key").unwrap();
+            let mut api = server.api().cassette(Cassette::record(&path));
+            server.fail_next(StatusCode::TooManyRequests);
+            assert!(api.loadn::<Push>(10).unwrap_err().is_rate_limited());
+        }
+
+        let mut api = PbAPI::new("other-key").cassette(Cassette::replay(&path).unwrap());
+        assert_eq!(Cassette::replay(&path).unwrap().interactions()[0].status, 429);
+        assert!(api.loadn::<Push>(10).unwrap_err().is_rate_limited());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,398 @@
+//! Configuration file with named account profiles.
+//!
+//! The file lives at `$XDG_CONFIG_HOME/pb/config.toml` (or `~/.config/pb/config.toml`)
+//! and uses a small subset of TOML:
+//!
+//! ```toml
+//! default = "personal"
+//!
+//! [profiles.personal]
+//! token_file = "~/.config/pb/personal.token"
+//! default_target = "device:Phone"
+//!
+//! [profiles.team]
+//! token = "o.XXXXXXXX"
+//! base_url = "https://pb-proxy.example.com/v2/"
+//! e2e_password = "env:PB_TEAM_E2E"
+//! ```
+//!
+//! Environment variables `PB_PROFILE`, `PB_API_KEY`, `PB_BASE_URL`, `PB_TARGET`
+//! and `PB_E2E_PASSWORD` override the file, except that `PB_API_KEY` is ignored
+//! for a profile chosen by name.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use api::PbAPI;
+
+static DEFAULT_PROFILE: &'static str = "default";
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Profile {
+    pub token: Option<String>,
+    pub token_file: Option<PathBuf>,
+    pub base_url: Option<String>,
+    /// Target in `pb` command line syntax, e.g. `device:Phone` or `email:me@example.com`.
+    pub default_target: Option<String>,
+    /// Either `env:VARIABLE`, `file:PATH` or the password itself.
+    pub e2e_password: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Config {
+    pub default: Option<String>,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Syntax { line: usize, message: String },
+    UnknownProfile(String),
+    MissingToken(String),
+}
+
+impl error::Error for ConfigError {
+    fn description(&self) -> &str {
+        match *self {
+            ConfigError::Io(_, ref e) => e.description(),
+            ConfigError::Syntax { .. } => "invalid config file",
+            ConfigError::UnknownProfile(_) => "unknown profile",
+            ConfigError::MissingToken(_) => "no API token configured"
+        }
+    }
+
+    fn cause<'a>(&'a self) -> Option<&'a error::Error> {
+        match *self {
+            ConfigError::Io(_, ref e) => Some(e as &error::Error),
+            _ => None
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            ConfigError::Io(ref path, ref e) => write!(fmt, "{}: {}", path.display(), e),
+            ConfigError::Syntax { line, ref message } => write!(fmt, "config line {}: {}", line, message),
+            ConfigError::UnknownProfile(ref name) => write!(fmt, "unknown profile {:?}", name),
+            ConfigError::MissingToken(ref name) => write!(fmt,
+                "no API token for profile {:?}: set token or token_file, or PB_API_KEY environment variable", name)
+        }
+    }
+}
+
+fn expand_home(path: &str) -> PathBuf {
+    match (path.starts_with("~/"), env::var_os("HOME")) {
+        (true, Some(home)) => PathBuf::from(home).join(&path[2..]),
+        _ => PathBuf::from(path)
+    }
+}
+
+fn read_file(path: &Path) -> Result<String, ConfigError> {
+    let mut content = String::new();
+    try!(File::open(path).and_then(|mut f| f.read_to_string(&mut content))
+         .map_err(|e| ConfigError::Io(path.to_path_buf(), e)));
+    Ok(content)
+}
+
+/// Parses a basic (`"..."`) or literal (`'...'`) TOML string, followed by an optional comment.
+fn parse_string(value: &str) -> Result<String, String> {
+    let mut chars = value.chars();
+    let quote = match chars.next() {
+        Some(q @ '"') | Some(q @ '\'') => q,
+        _ => return Err(format!("expected quoted string, found {:?}", value))
+    };
+
+    let mut result = String::new();
+    loop {
+        match chars.next() {
+            Some(c) if c == quote => break,
+            Some('\\') if quote == '"' => match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('"') => result.push('"'),
+                Some('\\') => result.push('\\'),
+                other => return Err(format!("unsupported escape sequence \\{}", other.map(|c| c.to_string()).unwrap_or(String::new())))
+            },
+            Some(c) => result.push(c),
+            None => return Err("unterminated string".to_string())
+        }
+    }
+
+    let tail = chars.as_str().trim();
+    if !tail.is_empty() && !tail.starts_with('#') {
+        return Err(format!("unexpected {:?} after string", tail));
+    }
+    Ok(result)
+}
+
+/// Splits `profiles.name` or `profiles."name with spaces"` table header.
+fn parse_header(header: &str) -> Result<Option<String>, String> {
+    let header = header.trim();
+    if !header.starts_with("profiles.") {
+        return Err(format!("unknown table [{}]", header));
+    }
+
+    let name = header["profiles.".len()..].trim();
+    if name.starts_with('"') || name.starts_with('\'') {
+        parse_string(name).map(Some)
+    } else if !name.is_empty() && name.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+        Ok(Some(name.to_string()))
+    } else {
+        Err(format!("invalid profile name {:?}", name))
+    }
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok()
+}
+
+impl Profile {
+    /// Applies `PB_API_KEY`, `PB_BASE_URL`, `PB_TARGET` and `PB_E2E_PASSWORD` overrides.
+    pub fn with_env(self) -> Profile {
+        self.apply_env(true, &env_var)
+    }
+
+    fn apply_env(mut self, token: bool, var: &Fn(&str) -> Option<String>) -> Profile {
+        if let (true, Some(token)) = (token, var("PB_API_KEY")) {
+            self.token = Some(token);
+            self.token_file = None;
+        }
+        if let Some(url) = var("PB_BASE_URL") {
+            self.base_url = Some(url);
+        }
+        if let Some(target) = var("PB_TARGET") {
+            self.default_target = Some(target);
+        }
+        if let Some(password) = var("PB_E2E_PASSWORD") {
+            self.e2e_password = Some(password);
+        }
+        self
+    }
+
+    /// Returns API token, reading it from `token_file` if needed.
+    pub fn token(&self) -> Result<Option<String>, ConfigError> {
+        match (&self.token, &self.token_file) {
+            (&Some(ref token), _) => Ok(Some(token.clone())),
+            (&None, &Some(ref path)) => read_file(path).map(|t| Some(t.trim().to_string())),
+            (&None, &None) => Ok(None)
+        }
+    }
+
+    /// Resolves `e2e_password` reference into the password.
+    pub fn e2e_password(&self) -> Result<Option<String>, ConfigError> {
+        let reference = match self.e2e_password {
+            Some(ref r) => r,
+            None => return Ok(None)
+        };
+
+        if reference.starts_with("env:") {
+            Ok(env::var(&reference[4..]).ok())
+        } else if reference.starts_with("file:") {
+            read_file(&*expand_home(&reference[5..])).map(|p| Some(p.trim_end_matches('\n').to_string()))
+        } else {
+            Ok(Some(reference.clone()))
+        }
+    }
+}
+
+impl Config {
+    /// Default location of config file.
+    pub fn path() -> Option<PathBuf> {
+        env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")))
+            .map(|dir| dir.join("pb").join("config.toml"))
+    }
+
+    /// Loads config from default location, returning empty config if there's no file.
+    pub fn load() -> Result<Config, ConfigError> {
+        match Config::path() {
+            Some(ref path) if path.exists() => Config::load_from(path),
+            _ => Ok(Config::default())
+        }
+    }
+
+    pub fn load_from<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        Config::parse(&*try!(read_file(path.as_ref())))
+    }
+
+    pub fn parse(content: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut current: Option<String> = None;
+
+        for (idx, line) in content.lines().enumerate() {
+            let syntax = |message: String| ConfigError::Syntax { line: idx + 1, message: message };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') {
+                let end = try!(line.rfind(']').ok_or_else(|| syntax("unterminated table header".to_string())));
+                current = try!(parse_header(&line[1..end]).map_err(&syntax));
+                if let Some(ref name) = current {
+                    if config.profiles.insert(name.clone(), Profile::default()).is_some() {
+                        return Err(syntax(format!("duplicate profile {:?}", name)));
+                    }
+                }
+                continue;
+            }
+
+            let eq = try!(line.find('=').ok_or_else(|| syntax(format!("expected key = value, found {:?}", line))));
+            let key = line[..eq].trim();
+            let value = try!(parse_string(line[eq + 1..].trim()).map_err(&syntax));
+
+            match current {
+                None => match key {
+                    "default" => config.default = Some(value),
+                    _ => return Err(syntax(format!("unknown key {:?}", key)))
+                },
+                Some(ref name) => {
+                    let profile = config.profiles.get_mut(name).unwrap();
+                    match key {
+                        "token" => profile.token = Some(value),
+                        "token_file" => profile.token_file = Some(expand_home(&*value)),
+                        "base_url" => profile.base_url = Some(value),
+                        "default_target" => profile.default_target = Some(value),
+                        "e2e_password" => profile.e2e_password = Some(value),
+                        _ => return Err(syntax(format!("unknown key {:?} in profile {:?}", key, name)))
+                    }
+                }
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// Name of the profile to use: `PB_PROFILE` environment variable,
+    /// `default` key or the profile named `default`.
+    pub fn default_profile(&self) -> String {
+        self.default_profile_with(&env_var)
+    }
+
+    fn default_profile_with(&self, var: &Fn(&str) -> Option<String>) -> String {
+        var("PB_PROFILE")
+            .or_else(|| self.default.clone())
+            .unwrap_or(DEFAULT_PROFILE.to_string())
+    }
+
+    /// Returns named profile (or the default one) with environment overrides applied.
+    /// Missing default profile is not an error, so that bare `PB_API_KEY` still works.
+    /// `PB_API_KEY` doesn't replace the token of a profile chosen by name or `PB_PROFILE`,
+    /// so that a stray exported key never sends to another account.
+    pub fn profile(&self, name: Option<&str>) -> Result<Profile, ConfigError> {
+        self.profile_with(name, &env_var)
+    }
+
+    /// Same as `profile`, with environment variables looked up by `var`.
+    fn profile_with(&self, name: Option<&str>, var: &Fn(&str) -> Option<String>) -> Result<Profile, ConfigError> {
+        let chosen = name.is_some() || var("PB_PROFILE").is_some();
+        let (name, explicit) = match name {
+            Some(name) => (name.to_string(), true),
+            None => (self.default_profile_with(var), chosen || self.default.is_some())
+        };
+
+        match self.profiles.get(&*name) {
+            Some(profile) => Ok(profile.clone().apply_env(!chosen, var)),
+            None if !explicit => Ok(Profile::default().apply_env(true, var)),
+            None => Err(ConfigError::UnknownProfile(name))
+        }
+    }
+
+    /// Creates API client for the profile.
+    pub fn api(&self, name: Option<&str>) -> Result<PbAPI, ConfigError> {
+        let profile = try!(self.profile(name));
+        let token = match try!(profile.token()) {
+            Some(token) => token,
+            None => return Err(ConfigError::MissingToken(name.map(|n| n.to_string()).unwrap_or_else(|| self.default_profile())))
+        };
+
+        let api = PbAPI::new(&*token);
+        Ok(match profile.base_url {
+            Some(ref url) => api.base_url(url),
+            None => api
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use super::{Config, ConfigError, Profile};
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse("
+            # accounts
+            default = \"personal\"
+
+            [profiles.personal]
+            token_file = \"/etc/pb/personal.token\"
+            default_target = 'device:Phone'  # my phone
+
+            [profiles.\"team account\"]
+            token = \"o.abc\\\"def\"
+            base_url = \"http://localhost:8080/v2/\"
+            e2e_password = \"env:PB_TEAM_E2E\"
+        ").unwrap();
+
+        assert_eq!(config.default, Some("personal".to_string()));
+        assert_eq!(config.profiles["personal"], Profile {
+            token_file: Some(PathBuf::from("/etc/pb/personal.token")),
+            default_target: Some("device:Phone".to_string()),
+            ..Profile::default()
+        });
+        assert_eq!(config.profiles["team account"], Profile {
+            token: Some("o.abc\"def".to_string()),
+            base_url: Some("http://localhost:8080/v2/".to_string()),
+            e2e_password: Some("env:PB_TEAM_E2E".to_string()),
+            ..Profile::default()
+        });
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        let line = |content: &str| match Config::parse(content) {
+            Err(ConfigError::Syntax { line, .. }) => line,
+            other => panic!("Unexpected result: {:?}", other)
+        };
+
+        assert_eq!(line("[profiles.work]\ntoken = \"x\"\ntokn = \"y\""), 3);
+        assert_eq!(line("[accounts]"), 1);
+        assert_eq!(line("\n[profiles.work]\ntoken = \"unterminated"), 3);
+        assert_eq!(line("[profiles.a]\n[profiles.a]"), 2);
+        assert_eq!(line("default = 42"), 1);
+    }
+
+    #[test]
+    fn test_unknown_profile() {
+        let config = Config::parse("[profiles.work]\ntoken = \"x\"").unwrap();
+        match config.profile(Some("home")) {
+            Err(ConfigError::UnknownProfile(ref name)) => assert_eq!(name, "home"),
+            other => panic!("Unexpected result: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn test_env_token() {
+        let config = Config::parse("[profiles.default]\ntoken = \"o.default\"\n[profiles.work]\ntoken = \"o.work\"").unwrap();
+        let var = |name: &str| if name == "PB_API_KEY" { Some("o.env".to_string()) } else { None };
+        let (default, work) = (config.profile_with(None, &var).unwrap(), config.profile_with(Some("work"), &var).unwrap());
+
+        assert_eq!(default.token().unwrap(), Some("o.env".to_string()));
+        assert_eq!(work.token().unwrap(), Some("o.work".to_string()));
+
+        let var = |name: &str| match name {
+            "PB_API_KEY" => Some("o.env".to_string()),
+            "PB_PROFILE" => Some("work".to_string()),
+            _ => None
+        };
+        assert_eq!(config.profile_with(None, &var).unwrap().token().unwrap(), Some("o.work".to_string()));
+    }
+}
@@ -0,0 +1,293 @@
+//! Batching of many messages into a few combined pushes.
+
+use std::time::Duration;
+
+use rustc_serialize::json;
+
+use api::{PbAPI, PbResult};
+use messages::{PushMsg, TargetIden};
+use objects::{ListItem, Push, PushData, Timestamp};
+
+/// API rejects pushes bigger than this.
+static DEFAULT_MAX_BYTES: usize = 4096;
+static DEFAULT_MAX_MESSAGES: usize = 50;
+static DEFAULT_WINDOW_SECS: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    /// A note with one line per message.
+    Note,
+    /// A list with one item per message.
+    List,
+}
+
+/// One line summary of a message.
+fn summarize(msg: &PushMsg) -> String {
+    let mut parts: Vec<String> = msg.title.iter().chain(msg.body.iter()).map(|s| s.to_string()).collect();
+    match msg.data {
+        PushData::Link(Some(ref url)) => parts.push(url.serialize()),
+        PushData::File { ref file_name, ref file_url, .. } => parts.push(format!("{} {}", file_name, file_url)),
+        PushData::List(ref items) => parts.push(items.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ")),
+        PushData::Address(ref address) => parts.push(address.clone()),
+        _ => ()
+    }
+    parts.join(": ").replace('\n', " ")
+}
+
+/// Collects messages for a target and sends them as a single push once `window`
+/// passes since the first of them or `max_messages` are collected.
+/// Combined pushes bigger than `max_bytes` are split into several ones,
+/// numbered in titles.
+#[derive(Debug, Clone)]
+pub struct Digest {
+    target: TargetIden,
+    title: Option<String>,
+    style: Style,
+    window: Duration,
+    max_messages: usize,
+    max_bytes: usize,
+    started: Option<Timestamp>,
+    lines: Vec<String>,
+}
+
+impl Digest {
+    /// Digest for `target`, which replaces targets of collected messages.
+    pub fn new(target: TargetIden) -> Digest {
+        Digest {
+            target: target,
+            title: None,
+            style: Style::Note,
+            window: Duration::from_secs(DEFAULT_WINDOW_SECS),
+            max_messages: DEFAULT_MAX_MESSAGES,
+            max_bytes: DEFAULT_MAX_BYTES,
+            started: None,
+            lines: Vec::new(),
+        }
+    }
+
+    /// Title of combined pushes, "N messages" by default.
+    pub fn title<T: Into<String>>(mut self, title: T) -> Digest {
+        self.title = Some(title.into());
+        self
+    }
+
+    pub fn style(mut self, style: Style) -> Digest {
+        self.style = style;
+        self
+    }
+
+    pub fn window(mut self, window: Duration) -> Digest {
+        self.window = window;
+        self
+    }
+
+    pub fn max_messages(mut self, count: usize) -> Digest {
+        self.max_messages = count;
+        self
+    }
+
+    /// Size limit of encoded push.
+    pub fn max_bytes(mut self, size: usize) -> Digest {
+        self.max_bytes = size;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.lines.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.lines.is_empty()
+    }
+
+    /// Whether collected messages should be sent, for callers flushing on a timer.
+    pub fn is_due(&self) -> bool {
+        self.is_due_at(Timestamp::now())
+    }
+
+    fn is_due_at(&self, now: Timestamp) -> bool {
+        self.lines.len() >= self.max_messages || self.started.map_or(false, |t| now - self.window >= t)
+    }
+
+    /// Adds a message, sending the digest if it's due. Returns sent pushes.
+    pub fn add(&mut self, api: &mut PbAPI, msg: &PushMsg) -> PbResult<Vec<Push>> {
+        self.add_at(api, msg, Timestamp::now())
+    }
+
+    fn add_at(&mut self, api: &mut PbAPI, msg: &PushMsg, now: Timestamp) -> PbResult<Vec<Push>> {
+        if self.started.is_none() {
+            self.started = Some(now);
+        }
+        self.lines.push(summarize(msg));
+        if self.is_due_at(now) { self.flush(api) } else { Ok(Vec::new()) }
+    }
+
+    /// Sends collected messages right away. If a push of a split digest fails,
+    /// messages of pushes already sent are dropped, and the rest are kept for the next flush.
+    pub fn flush(&mut self, api: &mut PbAPI) -> PbResult<Vec<Push>> {
+        let mut pushes = Vec::new();
+        let mut sent = 0;
+        for (msg, count) in self.build() {
+            match api.send(&msg) {
+                Ok(push) => pushes.push(push),
+                Err(e) => {
+                    self.lines.drain(..sent);
+                    return Err(e);
+                }
+            }
+            sent += count;
+        }
+        self.lines.clear();
+        self.started = None;
+        Ok(pushes)
+    }
+
+    fn message(&self, title: String, lines: &[String]) -> PushMsg<'static> {
+        match self.style {
+            Style::Note => PushMsg::new(self.target.clone()).title(title).body(lines.join("\n")),
+            Style::List => PushMsg::list(self.target.clone(), lines.iter().map(|l| ListItem::new(&**l, false)).collect()).title(title),
+        }
+    }
+
+    fn size(msg: &PushMsg) -> usize {
+        json::encode(msg).map(|s| s.len()).unwrap_or(0)
+    }
+
+    /// Combined messages, each fitting into `max_bytes`, with numbers of lines in them.
+    fn build(&self) -> Vec<(PushMsg<'static>, usize)> {
+        if self.lines.is_empty() {
+            return Vec::new();
+        }
+        let title = self.title.clone().unwrap_or(format!("{} messages", self.lines.len()));
+        // room for numbering, in case the digest gets split
+        let numbered = format!("{} ({}/{})", title, self.lines.len(), self.lines.len());
+
+        let mut chunks: Vec<Vec<String>> = Vec::new();
+        let mut chunk: Vec<String> = Vec::new();
+        for line in &self.lines {
+            chunk.push(line.clone());
+            if chunk.len() > 1 && Digest::size(&self.message(numbered.clone(), &chunk)) > self.max_bytes {
+                let line = chunk.pop().unwrap();
+                chunks.push(chunk);
+                chunk = vec![line];
+            }
+            // a single line too big to fit anywhere is cut short
+            loop {
+                let excess = Digest::size(&self.message(numbered.clone(), &chunk)).saturating_sub(self.max_bytes);
+                if excess == 0 || chunk[0].is_empty() {
+                    break;
+                }
+                let mut line = chunk.pop().unwrap();
+                let mut cut = line.len().saturating_sub(excess + '…'.len_utf8());
+                while !line.is_char_boundary(cut) {
+                    cut -= 1;
+                }
+                line.truncate(cut);
+                if !line.is_empty() {
+                    line.push('…');
+                }
+                chunk.push(line);
+            }
+        }
+        chunks.push(chunk);
+
+        let count = chunks.len();
+        chunks.iter().enumerate().map(|(idx, lines)| {
+            let title = if count == 1 { title.clone() } else { format!("{} ({}/{})", title, idx + 1, count) };
+            (self.message(title, lines), lines.len())
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use hyper::status::StatusCode;
+    use rustc_serialize::json;
+    use testing::MockServer;
+    use objects::{ListItem, PushData, Timestamp};
+    use messages::{PushMsg, TargetIden};
+    use url::Url;
+    use super::{Digest, Style};
+
+    fn msg(n: usize) -> PushMsg<'static> {
+        PushMsg::new(TargetIden::ContactEmail("someone@example.com".to_string())).title(format!("Job {}", n)).body("done\nin 5s")
+    }
+
+    #[test]
+    fn test_digest_note() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let mut digest = Digest::new(TargetIden::CurrentUser).max_messages(3);
+
+        assert!(digest.add(&mut api, &msg(1)).unwrap().is_empty());
+        assert!(digest.add(&mut api, &PushMsg::link(TargetIden::CurrentUser, Url::parse("https://example.com/report").unwrap()).title("Report")).unwrap().is_empty());
+        let pushes = digest.add(&mut api, &msg(3)).unwrap();
+        assert_eq!(pushes.len(), 1);
+        assert_eq!(pushes[0].title, Some("3 messages".to_string()));
+        assert_eq!(pushes[0].body, Some("Job 1: done in 5s\nReport: https://example.com/report\nJob 3: done in 5s".to_string()));
+        assert!(digest.is_empty());
+        assert!(digest.flush(&mut api).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_digest_list_window() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let mut digest = Digest::new(TargetIden::CurrentUser).style(Style::List).title("Nightly").window(Duration::from_secs(60));
+        let t0 = Timestamp::now();
+
+        assert!(digest.add_at(&mut api, &msg(1), t0).unwrap().is_empty());
+        assert!(digest.add_at(&mut api, &msg(2), t0 + Duration::from_secs(30)).unwrap().is_empty());
+        assert!(!digest.is_due_at(t0 + Duration::from_secs(59)));
+        let pushes = digest.add_at(&mut api, &msg(3), t0 + Duration::from_secs(60)).unwrap();
+        assert_eq!(pushes[0].title, Some("Nightly".to_string()));
+        assert_eq!(pushes[0].data, PushData::List(vec![
+            ListItem::new("Job 1: done in 5s", false), ListItem::new("Job 2: done in 5s", false), ListItem::new("Job 3: done in 5s", false)]));
+    }
+
+    #[test]
+    fn test_digest_split() {
+        let mut digest = Digest::new(TargetIden::CurrentUser).max_bytes(200);
+        for n in 0..10 {
+            digest.lines.push(format!("Job {}: done in 5s", n));
+        }
+        digest.lines.push("x".repeat(500));
+
+        let msgs: Vec<_> = digest.build().into_iter().map(|(m, _)| m).collect();
+        assert!(msgs.len() > 2);
+        assert!(msgs.iter().all(|m| json::encode(m).unwrap().len() <= 200));
+        assert_eq!(msgs[0].title.as_ref().map(|t| t.to_string()), Some(format!("11 messages (1/{})", msgs.len())));
+
+        let lines: Vec<String> = msgs.iter().flat_map(|m| m.body.as_ref().unwrap().split('\n').map(|l| l.to_string()).collect::<Vec<_>>()).collect();
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[9], "Job 9: done in 5s");
+        assert!(lines[10].starts_with("xxx") && lines[10].ends_with('…'));
+    }
+
+    #[test]
+    fn test_digest_partial_flush() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let mut digest = Digest::new(TargetIden::CurrentUser).max_bytes(200);
+        for n in 0..20 {
+            digest.add(&mut api, &msg(n)).unwrap();
+        }
+        let chunks = digest.build();
+        assert!(chunks.len() > 2);
+
+        server.fail_after(1, StatusCode::ServiceUnavailable);
+        assert!(digest.flush(&mut api).is_err());
+        assert_eq!(digest.len(), 20 - chunks[0].1);
+
+        // only the messages which weren't sent go out again
+        digest.flush(&mut api).unwrap();
+        let mut lines: Vec<String> = server.objects("pushes").iter()
+            .flat_map(|p| p["body"].as_string().unwrap().split('\n').map(|l| l.to_string()).collect::<Vec<_>>()).collect();
+        lines.sort();
+        lines.dedup();
+        assert_eq!(lines.len(), 20);
+        assert_eq!(server.objects("pushes").iter().map(|p| p["body"].as_string().unwrap().split('\n').count()).sum::<usize>(), 20);
+        assert!(digest.is_empty());
+    }
+}
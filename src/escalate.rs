@@ -0,0 +1,348 @@
+//! Escalation of undismissed pushes to further tiers of recipients.
+
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use api::{PbAPI, PbError, PbResult};
+use events::Event;
+use messages::{PushMsg, TargetIden, TextMsg};
+use objects::{DeviceIden, Push, PushIden, Text};
+
+static DEFAULT_POLL_SECS: u64 = 30;
+static DISMISS_ATTEMPTS: usize = 3;
+
+/// Where a copy of an alert goes.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recipient {
+    Push(TargetIden),
+    /// SMS to `numbers` sent from phone `device`. Texts can't be dismissed,
+    /// so they only notify.
+    Sms(DeviceIden, Vec<String>),
+}
+
+/// Recipients notified at once, and how long to wait for dismissal before the next tier.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tier {
+    pub recipients: Vec<Recipient>,
+    pub window: Duration,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Push `iden` sent at tier `tier` (counting from 0) was dismissed.
+    Dismissed { tier: usize, iden: PushIden },
+    /// Nothing was dismissed within the window of the last tier.
+    Exhausted,
+}
+
+/// Result of an escalation: what was sent and who reacted.
+#[derive(Debug)]
+pub struct Escalation {
+    pub outcome: Outcome,
+    pub pushes: Vec<Push>,
+    pub texts: Vec<Text>,
+    /// Recipients which couldn't be notified, or whose copy couldn't be dismissed
+    /// at the end. They don't stop escalation.
+    pub failed: Vec<(Recipient, PbError)>,
+}
+
+/// Sends a push and re-sends it to the next tier of recipients
+/// while none of the copies is dismissed.
+///
+/// Dismissal is noticed by polling sent pushes and, with `watch_stream`,
+/// right away on push tickles. Only pushes delivered to the sending account
+/// (its devices) report dismissals, copies sent to emails and channels
+/// are dismissed by their recipients in their own accounts.
+#[derive(Debug, Clone)]
+pub struct Escalator<'a> {
+    msg: PushMsg<'a>,
+    tiers: Vec<Tier>,
+    poll_interval: Duration,
+    stream: bool,
+}
+
+impl<'a> Escalator<'a> {
+    /// Escalates `msg`, its target is replaced by recipients of tiers.
+    pub fn new(msg: PushMsg<'a>) -> Escalator<'a> {
+        Escalator {
+            msg: msg,
+            tiers: Vec::new(),
+            poll_interval: Duration::from_secs(DEFAULT_POLL_SECS),
+            stream: false,
+        }
+    }
+
+    pub fn tier(mut self, recipients: Vec<Recipient>, window: Duration) -> Escalator<'a> {
+        self.tiers.push(Tier { recipients: recipients, window: window });
+        self
+    }
+
+    pub fn poll_interval(mut self, interval: Duration) -> Escalator<'a> {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Also checks for dismissal on every push tickle from realtime event stream.
+    pub fn watch_stream(mut self) -> Escalator<'a> {
+        self.stream = true;
+        self
+    }
+
+    /// Runs escalation to the end, blocking current thread. Once a copy is dismissed,
+    /// the rest of them are dismissed too.
+    pub fn run(&self, api: &mut PbAPI) -> PbResult<Escalation> {
+        let stop = Stop(Arc::new(AtomicBool::new(false)));
+        let tickles = if self.stream { Some(try!(tickles(api, stop.0.clone()))) } else { None };
+        let mut escalation = Escalation {
+            outcome: Outcome::Exhausted,
+            pushes: Vec::new(),
+            texts: Vec::new(),
+            failed: Vec::new(),
+        };
+        let (mut tiers_of, mut recipients_of) = (Vec::new(), Vec::new());
+
+        for (tier, &Tier { ref recipients, window }) in self.tiers.iter().enumerate() {
+            for recipient in recipients {
+                match self.notify(api, recipient, &mut escalation) {
+                    Ok(()) => {
+                        tiers_of.resize(escalation.pushes.len(), tier);
+                        recipients_of.resize(escalation.pushes.len(), recipient.clone());
+                    },
+                    Err(e) => escalation.failed.push((recipient.clone(), e))
+                }
+            }
+
+            let deadline = Instant::now() + window;
+            loop {
+                if let Some(idx) = try!(self.find_dismissed(api, &escalation.pushes)) {
+                    escalation.outcome = Outcome::Dismissed { tier: tiers_of[idx], iden: escalation.pushes[idx].iden.clone() };
+                    for (idx, e) in dismiss_rest(api, &mut escalation.pushes) {
+                        escalation.failed.push((recipients_of[idx].clone(), e));
+                    }
+                    return Ok(escalation);
+                }
+
+                let now = Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                let wait = cmp::min(self.poll_interval, deadline - now);
+                match tickles {
+                    // once the stream is gone, keep polling at the usual pace
+                    Some(ref tickles) => if let Err(RecvTimeoutError::Disconnected) = tickles.recv_timeout(wait) {
+                        thread::sleep(wait)
+                    },
+                    None => thread::sleep(wait)
+                }
+            }
+        }
+
+        Ok(escalation)
+    }
+
+    fn notify(&self, api: &mut PbAPI, recipient: &Recipient, escalation: &mut Escalation) -> PbResult<()> {
+        match *recipient {
+            Recipient::Push(ref target) => escalation.pushes.push(try!(api.send(&self.msg.clone().target(target.clone())))),
+            Recipient::Sms(ref device, ref numbers) => {
+                let text = [self.msg.title.as_ref(), self.msg.body.as_ref()].iter()
+                    .filter_map(|s| s.map(|s| &**s)).collect::<Vec<_>>().join("\n");
+                escalation.texts.push(try!(api.send_text(&TextMsg::new(device.clone(), numbers.clone(), text))));
+            }
+        }
+        Ok(())
+    }
+
+    /// Index of the first dismissed push, if any. Pushes which can't be loaded
+    /// for a transient error are checked again on the next poll.
+    fn find_dismissed(&self, api: &mut PbAPI, pushes: &[Push]) -> PbResult<Option<usize>> {
+        for (idx, push) in pushes.iter().enumerate() {
+            let current = match api.load_by_iden::<Push>(push.iden.clone()) {
+                Ok(current) => current,
+                Err(ref e) if e.is_transient() => continue,
+                Err(e) => return Err(e)
+            };
+            if current.dismissed || !current.active {
+                return Ok(Some(idx));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Dismisses pushes which are still active, retrying transient errors a few times.
+/// A push which still fails is left as it was last seen and returned with the error.
+fn dismiss_rest(api: &mut PbAPI, pushes: &mut Vec<Push>) -> Vec<(usize, PbError)> {
+    let mut failed = Vec::new();
+    for (idx, push) in pushes.iter_mut().enumerate() {
+        let mut attempt = 1;
+        loop {
+            let result = api.load_by_iden::<Push>(push.iden.clone()).and_then(|current|
+                if current.active && !current.dismissed { api.dismiss(current.iden) } else { Ok(current) });
+            match result {
+                Ok(current) => *push = current,
+                Err(ref e) if e.is_transient() && attempt < DISMISS_ATTEMPTS => {
+                    attempt += 1;
+                    continue;
+                },
+                Err(e) => failed.push((idx, e))
+            }
+            break;
+        }
+    }
+    failed
+}
+
+/// Tells the tickles thread to close the stream once escalation is over, however it ends.
+struct Stop(Arc<AtomicBool>);
+
+impl Drop for Stop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+/// Forwards push tickles from event stream, until the stream or receiver is gone or `stop`
+/// is set. The stream is closed on the next message after that, at the latest
+/// with a nop, which the server sends every 30 seconds.
+fn tickles(api: &PbAPI, stop: Arc<AtomicBool>) -> PbResult<Receiver<()>> {
+    let mut stream = try!(api.stream());
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        for event in stream.by_ref() {
+            if stop.load(Ordering::SeqCst) {
+                break;
+            }
+            match event {
+                Ok(Event::PushTickle) => if tx.send(()).is_err() { break },
+                // events of unknown shape don't end the stream
+                Ok(_) | Err(PbError::Js(_)) => (),
+                Err(_) => break
+            }
+        }
+    });
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::{Duration, Instant};
+    use testing::MockServer;
+    use hyper::status::StatusCode;
+    use objects::{DeviceIden, Push};
+    use messages::{DeviceMsg, PushMsg, TargetIden};
+    use super::{Escalator, Outcome, Recipient, dismiss_rest};
+
+    fn device(server: &MockServer, nickname: &str) -> DeviceIden {
+        server.api().send(&DeviceMsg::new(nickname).has_sms(true)).unwrap().iden().clone()
+    }
+
+    #[test]
+    fn test_escalation_exhausted() {
+        let server = MockServer::start("mock-key").unwrap();
+        let phone = device(&server, "phone");
+        let escalation = Escalator::new(PushMsg::new(TargetIden::CurrentUser).title("Disk full").body("db-01"))
+            .tier(vec![Recipient::Push(TargetIden::DeviceIden(phone.clone()))], Duration::from_millis(50))
+            .tier(vec![Recipient::Sms(phone.clone(), vec!["+15551234567".to_string()]),
+                       Recipient::Push(TargetIden::ChannelTag("missing".to_string()))], Duration::from_millis(50))
+            .poll_interval(Duration::from_millis(10))
+            .run(&mut server.api()).unwrap();
+
+        assert_eq!(escalation.outcome, Outcome::Exhausted);
+        assert_eq!(escalation.pushes.len(), 1);
+        assert_eq!(escalation.texts[0].data.message, "Disk full\ndb-01");
+        assert_eq!(escalation.failed.len(), 1);
+        assert!(!server.api().load_by_iden::<Push>(escalation.pushes[0].iden.clone()).unwrap().dismissed);
+    }
+
+    #[test]
+    fn test_escalation_dismissed() {
+        let server = MockServer::start("mock-key").unwrap();
+        let (phone, laptop) = (device(&server, "phone"), device(&server, "laptop"));
+        let escalator = Escalator::new(PushMsg::new(TargetIden::CurrentUser).title("Disk full"))
+            .tier(vec![Recipient::Push(TargetIden::DeviceIden(phone))], Duration::from_millis(50))
+            .tier(vec![Recipient::Push(TargetIden::DeviceIden(laptop))], Duration::from_secs(30))
+            .poll_interval(Duration::from_secs(30))
+            .watch_stream();
+
+        let mut api = server.api();
+        let started = Instant::now();
+        let handle = thread::spawn(move || escalator.run(&mut api).unwrap());
+
+        // dismiss the second copy as soon as it shows up
+        let iden = loop {
+            let pushes = server.objects("pushes");
+            if pushes.len() == 2 {
+                break pushes[1]["iden"].as_string().unwrap().to_string();
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        server.api().dismiss(iden.clone().into()).unwrap();
+
+        let escalation = handle.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(escalation.outcome, Outcome::Dismissed { tier: 1, iden: iden.into() });
+        assert!(escalation.pushes.iter().all(|p| p.dismissed));
+        assert!(escalation.failed.is_empty());
+
+        // the stream is closed on the next message after the escalation ends
+        for _ in 0..500 {
+            if server.open_streams() == 0 {
+                break;
+            }
+            server.tickle("device");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(server.open_streams(), 0);
+    }
+
+    #[test]
+    fn test_dismiss_rest_failed() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let mut pushes = vec![api.send(&PushMsg::new(TargetIden::CurrentUser).title("Disk full")).unwrap(),
+                              api.send(&PushMsg::new(TargetIden::CurrentUser).title("Disk full")).unwrap()];
+
+        // loading the second push keeps failing, the first one is still dismissed
+        server.fail_after(2, StatusCode::ServiceUnavailable);
+        server.fail_next(StatusCode::ServiceUnavailable);
+        server.fail_next(StatusCode::ServiceUnavailable);
+        let failed = dismiss_rest(&mut api, &mut pushes);
+        assert_eq!(failed.iter().map(|&(idx, _)| idx).collect::<Vec<_>>(), vec![1]);
+        assert!(pushes[0].dismissed && !pushes[1].dismissed);
+    }
+
+    #[test]
+    fn test_escalation_stream_closed() {
+        let server = MockServer::start("mock-key").unwrap();
+        let phone = device(&server, "phone");
+        let escalator = Escalator::new(PushMsg::new(TargetIden::CurrentUser).title("Disk full"))
+            .tier(vec![Recipient::Push(TargetIden::DeviceIden(phone))], Duration::from_secs(30))
+            .poll_interval(Duration::from_millis(20))
+            .watch_stream();
+
+        let mut api = server.api();
+        let started = Instant::now();
+        let handle = thread::spawn(move || escalator.run(&mut api).unwrap());
+
+        let iden = loop {
+            if let Some(push) = server.objects("pushes").pop() {
+                break push["iden"].as_string().unwrap().to_string();
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        // neither a closed stream nor a failed poll stops the escalation
+        server.close_streams();
+        server.fail_next(StatusCode::ServiceUnavailable);
+        thread::sleep(Duration::from_millis(100));
+        server.api().dismiss(iden.clone().into()).unwrap();
+
+        let escalation = handle.join().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert_eq!(escalation.outcome, Outcome::Dismissed { tier: 0, iden: iden.into() });
+    }
+}
@@ -1,12 +1,24 @@
+use std::io::{Read, Write, BufRead, BufReader};
+
+use hyper::net::{NetworkConnector, NetworkStream, DefaultConnector};
+use hyper::error::Error as HttpError;
 use rustc_serialize::{Decodable, Decoder};
-use objects::Push;
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use rustc_serialize::json::{self, Json};
+use url::Url;
+use uuid::Uuid;
+
+use objects::Error;
+use api::{PbError, PbResult};
 
 #[derive(Debug, PartialEq)]
-enum Event {
+pub enum Event {
     Nop,
     PushTickle,
     DeviceTickle,
-    Push(Push)
+    /// Ephemeral message (mirrored notification, dismissal, clipboard),
+    /// its nested `push` object. These are not stored as pushes.
+    Ephemeral(Json)
 }
 
 impl Decodable for Event {
@@ -19,9 +31,140 @@ impl Decodable for Event {
                     "device" => Ok(Event::DeviceTickle),
                     subtyp @ _ => Err(d.error(&*format!("Unknown tickle subtype: {:?}", subtyp)))
                 },
-                "push" => Ok(Event::Push(try!(Decodable::decode(d)))),
                 typ @ _ => Err(d.error(&*format!("Unknown type: {:?}", typ)))
             }
         })
     }
 }
+
+/// Client of Pushbullet realtime event stream.
+pub struct EventStream {
+    stream: BufReader<Box<NetworkStream + Send>>,
+}
+
+fn stream_error(message: &str) -> PbError {
+    From::from(Error::new("stream_error", message))
+}
+
+impl EventStream {
+    /// Connects to a stream at given `ws://` or `wss://` URL.
+    pub fn connect_url(url: &str) -> PbResult<EventStream> {
+        let url = try!(Url::parse(url).map_err(|e| stream_error(&*e.to_string())));
+        let (scheme, default_port) = match &*url.scheme {
+            "ws" => ("http", 80),
+            "wss" => ("https", 443),
+            _ => return Err(stream_error("stream URL must use ws or wss scheme"))
+        };
+        let host = match url.host() {
+            Some(host) => host.serialize(),
+            None => return Err(stream_error("stream URL has no host"))
+        };
+        let port = url.port().unwrap_or(default_port);
+        let path = url.serialize_path().unwrap_or("/".to_string());
+
+        let mut stream: Box<NetworkStream + Send> = Box::new(try!(DefaultConnector::default().connect(&*host, port, scheme)));
+        let key = Uuid::new_v4().as_bytes().to_base64(STANDARD);
+        try!(write!(stream, "GET {} HTTP/1.1\r\nHost: {}:{}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n",
+                    path, host, port, key).map_err(HttpError::from));
+        try!(stream.flush().map_err(HttpError::from));
+
+        let mut reader = BufReader::new(stream);
+        let mut status = String::new();
+        try!(reader.read_line(&mut status).map_err(HttpError::from));
+        loop {
+            let mut line = String::new();
+            if try!(reader.read_line(&mut line).map_err(HttpError::from)) == 0 || line.trim().is_empty() {
+                break;
+            }
+        }
+        if status.split(' ').nth(1) != Some("101") {
+            return Err(stream_error(&*format!("stream handshake failed: {}", status.trim())));
+        }
+
+        Ok(EventStream { stream: reader })
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> PbResult<()> {
+        self.stream.read_exact(buf).map_err(|e| From::from(HttpError::from(e)))
+    }
+
+    fn write_frame(&mut self, opcode: u8, payload: &[u8]) -> PbResult<()> {
+        // client frames must be masked, but mask itself can be anything
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8, 0, 0, 0, 0];
+        frame.extend(payload);
+        let stream = self.stream.get_mut();
+        try!(stream.write_all(&*frame).and_then(|_| stream.flush()).map_err(HttpError::from));
+        Ok(())
+    }
+
+    /// Reads next text message, answering pings on the way.
+    /// Returns `None` when the server closes the stream.
+    pub fn next_message(&mut self) -> PbResult<Option<String>> {
+        let mut message = Vec::new();
+        loop {
+            let mut header = [0u8; 2];
+            try!(self.read_exact(&mut header));
+            let fin = header[0] & 0x80 != 0;
+            let opcode = header[0] & 0x0f;
+
+            let len = match header[1] & 0x7f {
+                126 => {
+                    let mut buf = [0u8; 2];
+                    try!(self.read_exact(&mut buf));
+                    (buf[0] as usize) << 8 | buf[1] as usize
+                },
+                127 => {
+                    let mut buf = [0u8; 8];
+                    try!(self.read_exact(&mut buf));
+                    buf.iter().fold(0, |acc, &b| acc << 8 | b as usize)
+                },
+                n => n as usize
+            };
+            let mut mask = [0u8; 4];
+            if header[1] & 0x80 != 0 {
+                try!(self.read_exact(&mut mask));
+            }
+            let mut payload = vec![0u8; len];
+            try!(self.read_exact(&mut payload));
+            for (i, b) in payload.iter_mut().enumerate() {
+                *b ^= mask[i % 4];
+            }
+
+            match opcode {
+                0x0 | 0x1 => {
+                    message.extend(payload);
+                    if fin {
+                        return String::from_utf8(message).map(Some).map_err(|_| stream_error("stream message is not valid UTF-8"));
+                    }
+                },
+                0x8 => return Ok(None),
+                0x9 if payload.len() < 126 => try!(self.write_frame(0xa, &*payload)),
+                _ => ()
+            }
+        }
+    }
+
+    pub fn next_event(&mut self) -> PbResult<Option<Event>> {
+        let msg = match try!(self.next_message()) {
+            Some(msg) => try!(Json::from_str(&*msg).map_err(json::DecoderError::ParseError)),
+            None => return Ok(None)
+        };
+        // ephemerals have arbitrary contents, so they are kept as they are
+        if msg.find("type").and_then(|t| t.as_string()) == Some("push") {
+            return Ok(Some(Event::Ephemeral(msg.find("push").cloned().unwrap_or(Json::Null))));
+        }
+        Ok(Some(try!(Decodable::decode(&mut json::Decoder::new(msg)))))
+    }
+}
+
+impl Iterator for EventStream {
+    type Item = PbResult<Event>;
+
+    fn next(&mut self) -> Option<PbResult<Event>> {
+        match self.next_event() {
+            Ok(Some(event)) => Some(Ok(event)),
+            Ok(None) => None,
+            Err(e) => Some(Err(e))
+        }
+    }
+}
@@ -0,0 +1,450 @@
+//! Export of pushes into JSON Lines, CSV or HTML archive,
+//! and import of exported pushes into another account.
+
+use std::collections::BTreeSet;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use rustc_serialize::json;
+
+use api::{PbAPI, PbError, PbResult};
+use messages::{PushMsg, TargetIden};
+use objects::{Push, PushData, PushIden};
+
+static PAGE_SIZE: usize = 100;
+
+static CSV_COLUMNS: &'static [&'static str] = &[
+    "iden", "type", "created", "modified", "active", "dismissed",
+    "sender_name", "sender_email", "target_device_iden", "title", "body",
+    "url", "file_name", "file_url", "local_file", "items"
+];
+
+static HTML_HEADER: &'static str = "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Pushbullet archive</title>
+<style>
+body { font-family: sans-serif; max-width: 50em; margin: 2em auto; color: #222; }
+article { border-bottom: 1px solid #ddd; padding: 1em 0; }
+.meta { color: #777; font-size: 80%; }
+.body { white-space: pre-wrap; }
+.inactive { opacity: 0.5; }
+</style>
+</head>
+<body>
+<h1>Pushbullet archive</h1>
+";
+
+static HTML_FOOTER: &'static str = "</body>\n</html>\n";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    JsonLines,
+    Csv,
+    Html,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct FormatParseError;
+
+impl error::Error for FormatParseError {
+    fn description(&self) -> &str { "unknown export format, expected jsonl, csv or html" }
+}
+
+impl fmt::Display for FormatParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(error::Error::description(self))
+    }
+}
+
+impl FromStr for Format {
+    type Err = FormatParseError;
+    fn from_str(s: &str) -> Result<Format, FormatParseError> {
+        match s {
+            "jsonl" | "json" => Ok(Format::JsonLines),
+            "csv" => Ok(Format::Csv),
+            "html" => Ok(Format::Html),
+            _ => Err(FormatParseError)
+        }
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", value.replace("\"", "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn html_escape(value: &str) -> String {
+    value.replace("&", "&amp;").replace("<", "&lt;").replace(">", "&gt;").replace("\"", "&quot;")
+}
+
+/// Local file name for a downloaded file push, safe to use in any directory.
+fn local_name(push: &Push, file_name: &str) -> String {
+    let safe: String = file_name.chars().map(|c| if c.is_alphanumeric() || c == '.' || c == '-' || c == '_' { c } else { '_' }).collect();
+    format!("{}-{}", push.iden, safe.trim_start_matches('.'))
+}
+
+/// Outcome of `Export::write`.
+#[derive(Debug, Default)]
+pub struct ExportSummary {
+    pub exported: usize,
+    /// File pushes whose contents couldn't be downloaded (e.g. expired `file_url`),
+    /// exported with a link to the remote file instead.
+    pub failed: Vec<(PushIden, PbError)>,
+}
+
+/// Export settings, see `Export::write`.
+#[derive(Debug, Clone)]
+pub struct Export {
+    format: Format,
+    files_dir: Option<PathBuf>,
+    inactive: bool,
+}
+
+impl Export {
+    pub fn new(format: Format) -> Export {
+        Export {
+            format: format,
+            files_dir: None,
+            inactive: false,
+        }
+    }
+
+    /// Downloads contents of file pushes into `dir`, referring to them from the archive.
+    pub fn download_files<P: AsRef<Path>>(mut self, dir: P) -> Export {
+        self.files_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Also exports deleted pushes.
+    pub fn include_inactive(mut self) -> Export {
+        self.inactive = true;
+        self
+    }
+
+    /// Streams all pushes of the account into `out` page by page, newest first.
+    /// Failed file downloads don't stop the export and are listed in the summary.
+    pub fn write<W: Write>(&self, api: &mut PbAPI, out: &mut W) -> PbResult<ExportSummary> {
+        if let Some(ref dir) = self.files_dir {
+            try!(fs::create_dir_all(dir));
+        }
+        try!(self.header(out));
+
+        let mut summary = ExportSummary::default();
+        let (mut pushes, mut cursor) = try!(api.loadn::<Push>(PAGE_SIZE));
+        loop {
+            for push in pushes.iter().filter(|p| self.inactive || p.active) {
+                let local = match self.download(api, push) {
+                    Ok(local) => local,
+                    Err(e) => {
+                        summary.failed.push((push.iden.clone(), e));
+                        None
+                    }
+                };
+                try!(self.push(out, push, local.as_ref().map(|s| &**s)));
+                summary.exported += 1;
+            }
+            match cursor {
+                Some(c) => {
+                    let (next, c) = try!(api.loadn_from::<Push>(PAGE_SIZE, c));
+                    pushes = next;
+                    cursor = c;
+                },
+                None => break
+            }
+        }
+
+        try!(self.footer(out));
+        Ok(summary)
+    }
+
+    fn download(&self, api: &mut PbAPI, push: &Push) -> PbResult<Option<String>> {
+        match (self.files_dir.as_ref(), &push.data) {
+            (Some(dir), &PushData::File { ref file_name, ref file_url, .. }) if push.active => {
+                let name = local_name(push, file_name);
+                try!(api.download(file_url, dir.join(&*name)));
+                Ok(Some(name))
+            },
+            _ => Ok(None)
+        }
+    }
+
+    fn header<W: Write>(&self, out: &mut W) -> ::std::io::Result<()> {
+        match self.format {
+            Format::JsonLines => Ok(()),
+            Format::Csv => writeln!(out, "{}", CSV_COLUMNS.join(",")),
+            Format::Html => out.write_all(HTML_HEADER.as_bytes())
+        }
+    }
+
+    fn footer<W: Write>(&self, out: &mut W) -> ::std::io::Result<()> {
+        match self.format {
+            Format::Html => out.write_all(HTML_FOOTER.as_bytes()),
+            _ => Ok(())
+        }
+    }
+
+    fn push<W: Write>(&self, out: &mut W, push: &Push, local_file: Option<&str>) -> ::std::io::Result<()> {
+        match self.format {
+            Format::JsonLines => writeln!(out, "{}", json::encode(push).unwrap_or(String::new())),
+            Format::Csv => write_csv(out, push, local_file),
+            Format::Html => write_html(out, push, local_file)
+        }
+    }
+}
+
+fn write_csv<W: Write>(out: &mut W, push: &Push, local_file: Option<&str>) -> ::std::io::Result<()> {
+    let opt = |s: &Option<String>| s.clone().unwrap_or(String::new());
+    let (url, file_name, file_url, items) = match push.data {
+        PushData::Link(ref url) => (url.as_ref().map(|u| u.serialize()).unwrap_or(String::new()), String::new(), String::new(), String::new()),
+        PushData::File { ref file_name, ref file_url, .. } => (String::new(), file_name.clone(), file_url.serialize(), String::new()),
+        PushData::List(ref items) => (String::new(), String::new(), String::new(),
+            items.iter().map(|i| format!("[{}] {}", if i.is_checked() { "x" } else { " " }, &**i)).collect::<Vec<_>>().join("\n")),
+        PushData::Address(ref address) => (String::new(), String::new(), String::new(), address.clone()),
+        _ => (String::new(), String::new(), String::new(), String::new())
+    };
+
+    let fields = vec![
+        push.iden.to_string(), push.data.type_name().to_string(), push.created.to_string(), push.modified.to_string(),
+        push.active.to_string(), push.dismissed.to_string(),
+        opt(&push.sender_name), opt(&push.sender_email),
+        push.target_device_iden.as_ref().map(|i| i.to_string()).unwrap_or(String::new()),
+        opt(&push.title), opt(&push.body),
+        url, file_name, file_url, local_file.unwrap_or("").to_string(), items,
+    ];
+    writeln!(out, "{}", fields.iter().map(|f| csv_field(&**f)).collect::<Vec<_>>().join(","))
+}
+
+fn write_html<W: Write>(out: &mut W, push: &Push, local_file: Option<&str>) -> ::std::io::Result<()> {
+    try!(writeln!(out, "<article id=\"{}\" class=\"{}{}\">", html_escape(&*push.iden), push.data.type_name(),
+                  if push.active { "" } else { " inactive" }));
+    try!(writeln!(out, "<div class=\"meta\">{} &middot; {}{}</div>", push.created.format_utc(),
+                  html_escape(push.sender_name.as_ref().or(push.sender_email.as_ref()).map(|s| &**s).unwrap_or("")),
+                  if push.dismissed { " &middot; dismissed" } else { "" }));
+    if let Some(ref title) = push.title {
+        try!(writeln!(out, "<h2>{}</h2>", html_escape(title)));
+    }
+
+    match push.data {
+        PushData::Link(Some(ref url)) => {
+            let url = html_escape(&*url.serialize());
+            try!(writeln!(out, "<p><a href=\"{}\">{}</a></p>", url, url));
+        },
+        PushData::File { ref file_name, ref file_url, .. } => {
+            let href = local_file.map(|f| f.to_string()).unwrap_or(file_url.serialize());
+            try!(writeln!(out, "<p><a href=\"{}\">{}</a></p>", html_escape(&*href), html_escape(file_name)));
+        },
+        PushData::List(ref items) => {
+            try!(writeln!(out, "<ul>"));
+            for item in items {
+                try!(writeln!(out, "<li>{} {}</li>", if item.is_checked() { "&#9745;" } else { "&#9744;" }, html_escape(item)));
+            }
+            try!(writeln!(out, "</ul>"));
+        },
+        PushData::Address(ref address) => try!(writeln!(out, "<p class=\"body\">{}</p>", html_escape(address))),
+        _ => ()
+    }
+
+    if let Some(ref body) = push.body {
+        try!(writeln!(out, "<p class=\"body\">{}</p>", html_escape(body)));
+    }
+    writeln!(out, "</article>")
+}
+
+/// Outcome of `import`.
+#[derive(Debug, PartialEq, Default)]
+pub struct ImportSummary {
+    pub created: usize,
+    /// Deleted pushes and pushes of kinds which can't be re-created (e.g. files).
+    pub skipped: usize,
+    /// Pushes created by an earlier import.
+    pub existing: usize,
+}
+
+/// Guid of a push re-created from the exported push `iden`.
+fn import_guid(iden: &PushIden) -> String {
+    format!("import-{}", iden)
+}
+
+/// Re-creates note, link and list pushes exported as JSON Lines, sending them to `target`
+/// oldest first, so that they keep their order.
+/// Pushes are tagged with a guid derived from their original iden, so an interrupted
+/// import can be repeated without duplicating pushes already created.
+pub fn import<R: BufRead>(api: &mut PbAPI, input: R, target: &TargetIden) -> PbResult<ImportSummary> {
+    let mut pushes = Vec::new();
+    for line in input.lines() {
+        let line = try!(line);
+        if !line.trim().is_empty() {
+            pushes.push(try!(json::decode::<Push>(&*line)));
+        }
+    }
+    pushes.sort_by_key(|p| p.created);
+
+    let imported: BTreeSet<String> = try!(api.load_all::<Push>()).into_iter().filter_map(|p| p.guid).collect();
+
+    let mut summary = ImportSummary::default();
+    for push in pushes {
+        let msg = match push.data {
+            _ if !push.active => None,
+            PushData::Note => Some(PushMsg::new(target.clone())),
+            PushData::Link(Some(ref url)) => Some(PushMsg::link(target.clone(), url.clone())),
+            PushData::List(ref items) => Some(PushMsg::list(target.clone(), items.clone())),
+            _ => None
+        };
+
+        match msg {
+            Some(_) if imported.contains(&import_guid(&push.iden)) => summary.existing += 1,
+            Some(msg) => {
+                let msg = msg.guid(import_guid(&push.iden));
+                let msg = match push.title { Some(ref title) => msg.title(title.clone()), None => msg };
+                let msg = match push.body { Some(ref body) => msg.body(body.clone()), None => msg };
+                try!(api.send(&msg));
+                summary.created += 1;
+            },
+            None => summary.skipped += 1
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{Read, Write};
+    use testing::MockServer;
+    use objects::{Push, PushData, ListItem, FileInfo};
+    use messages::{PushMsg, TargetIden};
+    use url::Url;
+    use uuid::Uuid;
+    use super::{Export, Format, ImportSummary, import, csv_field};
+
+    fn fill(server: &MockServer) -> Push {
+        let mut api = server.api();
+        api.send(&PushMsg::new(TargetIden::CurrentUser).title("Note, \"quoted\"").body("<b>bold</b>")).unwrap();
+        api.send(&PushMsg::link(TargetIden::CurrentUser, Url::parse("https://github.com/kstep/rust-pb").unwrap())).unwrap();
+        api.send(&PushMsg::list(TargetIden::CurrentUser, vec![ListItem::new("milk", true)]).title("Groceries")).unwrap();
+        let gone = api.send(&PushMsg::new(TargetIden::CurrentUser).title("Deleted")).unwrap();
+        api.remove::<Push>(gone.iden).unwrap();
+
+        let dir = env::temp_dir().join(format!("pb-test-upload-{}", Uuid::new_v4().simple()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join("pb-test-export-upload.txt");
+        File::create(&path).unwrap().write_all(b"retained").unwrap();
+        let file = api.upload(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        api.send(&PushMsg::file(TargetIden::CurrentUser, file)).unwrap()
+    }
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!(csv_field("plain"), "plain");
+        assert_eq!(csv_field("a,b"), "\"a,b\"");
+        assert_eq!(csv_field("say \"hi\"\n"), "\"say \"\"hi\"\"\n\"");
+    }
+
+    #[test]
+    fn test_export_formats() {
+        let server = MockServer::start("mock-key").unwrap();
+        let file_push = fill(&server);
+        let mut api = server.api();
+
+        let mut jsonl = Vec::new();
+        assert_eq!(Export::new(Format::JsonLines).write(&mut api, &mut jsonl).unwrap().exported, 4);
+        let lines: Vec<String> = String::from_utf8(jsonl).unwrap().lines().map(|l| l.to_string()).collect();
+        assert_eq!(::rustc_serialize::json::decode::<Push>(&*lines[0]).unwrap(), file_push);
+
+        let mut csv = Vec::new();
+        assert_eq!(Export::new(Format::Csv).include_inactive().write(&mut api, &mut csv).unwrap().exported, 5);
+        let csv = String::from_utf8(csv).unwrap();
+        assert!(csv.starts_with("iden,type,created,"));
+        assert!(csv.contains(",\"Note, \"\"quoted\"\"\",<b>bold</b>,"));
+        assert!(csv.contains(",\"[x] milk\"") || csv.contains(",[x] milk"));
+
+        let dir = env::temp_dir().join(format!("pb-test-export-files-{}", Uuid::new_v4().simple()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut html = Vec::new();
+        let summary = Export::new(Format::Html).download_files(&dir).write(&mut api, &mut html).unwrap();
+        assert_eq!((summary.exported, summary.failed.len()), (4, 0));
+        let html = String::from_utf8(html).unwrap();
+        let local = format!("{}-pb-test-export-upload.txt", file_push.iden);
+        assert!(html.contains(&*format!("<a href=\"{}\">pb-test-export-upload.txt</a>", local)));
+        assert!(html.contains("&lt;b&gt;bold&lt;/b&gt;"));
+        assert!(html.contains("&#9745; milk"));
+        assert!(!html.contains("Deleted"));
+
+        let mut content = String::new();
+        File::open(dir.join(&*local)).unwrap().read_to_string(&mut content).unwrap();
+        assert_eq!(content, "retained");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_failed_download() {
+        let server = MockServer::start("mock-key").unwrap();
+        let file_push = fill(&server);
+        let mut api = server.api();
+        let expired = FileInfo {
+            file_name: "expired.txt".to_string(),
+            file_type: "text/plain".to_string(),
+            file_url: Url::parse(&*format!("{}files/404/expired.txt", server.base_url().trim_end_matches("v2/"))).unwrap(),
+            image_url: None,
+        };
+        let expired = api.send(&PushMsg::file(TargetIden::CurrentUser, expired)).unwrap();
+
+        let dir = env::temp_dir().join(format!("pb-test-export-expired-{}", Uuid::new_v4().simple()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut html = Vec::new();
+        let summary = Export::new(Format::Html).download_files(&dir).write(&mut api, &mut html).unwrap();
+        assert_eq!(summary.exported, 5);
+        assert_eq!(summary.failed.iter().map(|f| f.0.clone()).collect::<Vec<_>>(), vec![expired.iden]);
+
+        let html = String::from_utf8(html).unwrap();
+        assert!(html.contains("files/404/expired.txt\">expired.txt</a>"));
+        assert!(html.contains(&*format!("<a href=\"{}-pb-test-export-upload.txt\">", file_push.iden)));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_import() {
+        let source = MockServer::start("source-key").unwrap();
+        fill(&source);
+        let mut jsonl = Vec::new();
+        Export::new(Format::JsonLines).include_inactive().write(&mut source.api(), &mut jsonl).unwrap();
+
+        let dest = MockServer::start("dest-key").unwrap();
+        let mut api = dest.api();
+        assert_eq!(import(&mut api, &*jsonl, &TargetIden::CurrentUser).unwrap(), ImportSummary { created: 3, skipped: 2, existing: 0 });
+
+        let pushes = api.load_all::<Push>().unwrap();
+        let titles: Vec<Option<String>> = pushes.iter().map(|p| p.title.clone()).collect();
+        assert_eq!(titles, vec![Some("Groceries".to_string()), None, Some("Note, \"quoted\"".to_string())]);
+        assert_eq!(pushes[1].data, PushData::Link(Some(Url::parse("https://github.com/kstep/rust-pb").unwrap())));
+        assert_eq!(pushes[0].data, PushData::List(vec![ListItem::new("milk", true)]));
+    }
+
+    #[test]
+    fn test_import_repeated() {
+        let source = MockServer::start("source-key").unwrap();
+        fill(&source);
+        let mut jsonl = Vec::new();
+        Export::new(Format::JsonLines).write(&mut source.api(), &mut jsonl).unwrap();
+
+        // the first import was interrupted after the oldest push
+        let dest = MockServer::start("dest-key").unwrap();
+        let mut api = dest.api();
+        let first = jsonl.split(|&b| b == b'\n').filter(|l| !l.is_empty()).last().unwrap().to_vec();
+        assert_eq!(import(&mut api, &*first, &TargetIden::CurrentUser).unwrap(), ImportSummary { created: 1, skipped: 0, existing: 0 });
+
+        assert_eq!(import(&mut api, &*jsonl, &TargetIden::CurrentUser).unwrap(), ImportSummary { created: 2, skipped: 1, existing: 1 });
+        assert_eq!(api.load_all::<Push>().unwrap().len(), 3);
+    }
+}
@@ -0,0 +1,371 @@
+//! Retention janitor: deletion of pushes matching a policy.
+
+use std::cmp;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use api::{PbAPI, PbError, PbResult};
+use objects::{DeviceIden, Error, Push, PushIden, Timestamp};
+
+static PAGE_SIZE: usize = 500;
+static DEFAULT_BACKOFF_SECS: u64 = 5;
+static DEFAULT_ATTEMPTS: usize = 5;
+
+/// Which pushes to delete. A push is deleted when it passes all filters
+/// (`sender`, `typ`) and meets any of the conditions (`older_than`,
+/// `dismissed`, `max_per_device`). A policy with filters only deletes
+/// every push passing them, an empty policy deletes nothing.
+#[derive(Debug, Clone, Default)]
+pub struct Policy {
+    older_than: Option<Duration>,
+    dismissed: bool,
+    senders: Vec<String>,
+    types: Vec<String>,
+    max_per_device: Option<usize>,
+}
+
+impl Policy {
+    pub fn new() -> Policy {
+        Policy::default()
+    }
+
+    pub fn older_than(mut self, age: Duration) -> Policy {
+        self.older_than = Some(age);
+        self
+    }
+
+    pub fn older_than_days(self, days: u64) -> Policy {
+        self.older_than(Duration::from_secs(days * 24 * 3600))
+    }
+
+    pub fn dismissed(mut self) -> Policy {
+        self.dismissed = true;
+        self
+    }
+
+    /// Only pushes from given sender email, may be given several times.
+    pub fn sender(mut self, email: &str) -> Policy {
+        self.senders.push(email.trim().to_lowercase());
+        self
+    }
+
+    /// Only pushes of given type, may be given several times.
+    pub fn typ(mut self, typ: &str) -> Policy {
+        self.types.push(typ.to_string());
+        self
+    }
+
+    /// Keeps only `count` newest pushes sent from each device
+    /// (pushes sent without a device count as one more device).
+    pub fn max_per_device(mut self, count: usize) -> Policy {
+        self.max_per_device = Some(count);
+        self
+    }
+
+    fn has_conditions(&self) -> bool {
+        self.older_than.is_some() || self.dismissed || self.max_per_device.is_some()
+    }
+
+    fn has_filters(&self) -> bool {
+        !self.senders.is_empty() || !self.types.is_empty()
+    }
+
+    fn accepts(&self, push: &Push) -> bool {
+        (self.types.is_empty() || self.types.iter().any(|t| t == push.data.type_name())) &&
+        (self.senders.is_empty() || push.sender_email_normalized.as_ref().or(push.sender_email.as_ref())
+            .map_or(false, |e| self.senders.contains(&e.to_lowercase())))
+    }
+
+    /// Selects pushes to delete with reasons, newest first.
+    fn select(&self, mut pushes: Vec<Push>, now: Timestamp) -> Vec<Candidate> {
+        if !self.has_conditions() && !self.has_filters() {
+            return Vec::new();
+        }
+
+        pushes.retain(|p| p.active && self.accepts(p));
+        pushes.sort_by(|a, b| b.created.cmp(&a.created));
+
+        let expired = self.older_than.map(|age| now - age);
+        let mut per_device: BTreeMap<Option<DeviceIden>, usize> = BTreeMap::new();
+        pushes.into_iter().filter_map(|push| {
+            let seen = per_device.entry(push.source_device_iden.clone()).or_insert(0);
+            *seen += 1;
+
+            let reason = if expired.map_or(false, |t| push.created < t) {
+                Reason::Expired
+            } else if self.dismissed && push.dismissed {
+                Reason::Dismissed
+            } else if self.max_per_device.map_or(false, |max| *seen > max) {
+                Reason::Overflow
+            } else if !self.has_conditions() {
+                Reason::Matched
+            } else {
+                return None;
+            };
+            Some(Candidate { push: push, reason: reason })
+        }).collect()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Reason {
+    Expired,
+    Dismissed,
+    /// Beyond `max_per_device` newest pushes of its device.
+    Overflow,
+    /// Passed filters of a policy without conditions.
+    Matched,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(match *self {
+            Reason::Expired => "expired",
+            Reason::Dismissed => "dismissed",
+            Reason::Overflow => "over device limit",
+            Reason::Matched => "matched",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct Candidate {
+    pub push: Push,
+    pub reason: Reason,
+}
+
+/// Outcome of a janitor run.
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Number of active pushes looked at.
+    pub scanned: usize,
+    /// Pushes matching the policy, deleted unless in dry run.
+    pub candidates: Vec<Candidate>,
+    pub deleted: usize,
+    pub failed: Vec<(PushIden, PbError)>,
+}
+
+/// Deletes pushes matching a policy with a few concurrent workers,
+/// pausing all of them when API rate limit is hit.
+#[derive(Debug, Clone)]
+pub struct Janitor {
+    policy: Policy,
+    concurrency: usize,
+    dry_run: bool,
+    backoff: Duration,
+    attempts: usize,
+}
+
+impl Janitor {
+    pub fn new(policy: Policy) -> Janitor {
+        Janitor {
+            policy: policy,
+            concurrency: 1,
+            dry_run: false,
+            backoff: Duration::from_secs(DEFAULT_BACKOFF_SECS),
+            attempts: DEFAULT_ATTEMPTS,
+        }
+    }
+
+    /// Number of parallel delete requests, 1 by default.
+    pub fn concurrency(mut self, workers: usize) -> Janitor {
+        self.concurrency = cmp::max(workers, 1);
+        self
+    }
+
+    /// Only reports pushes which would be deleted.
+    pub fn dry_run(mut self) -> Janitor {
+        self.dry_run = true;
+        self
+    }
+
+    /// Pause after hitting rate limit, doubled on every subsequent hit for the same push,
+    /// which is given up on after `attempts` tries.
+    pub fn backoff(mut self, delay: Duration, attempts: usize) -> Janitor {
+        self.backoff = delay;
+        self.attempts = cmp::max(attempts, 1);
+        self
+    }
+
+    pub fn run(&self, api: &mut PbAPI) -> PbResult<Report> {
+        let backoff = Backoff {
+            delay: self.backoff,
+            attempts: self.attempts,
+            paused_until: Arc::new(Mutex::new(None)),
+        };
+
+        let mut pushes = Vec::new();
+        let (mut page, mut cursor) = try!(backoff.call(|| api.loadn::<Push>(PAGE_SIZE)));
+        loop {
+            pushes.extend(page.into_iter().filter(|p| p.active));
+            match cursor {
+                Some(c) => {
+                    let (next, c) = try!(backoff.call(|| api.loadn_from::<Push>(PAGE_SIZE, c.clone())));
+                    page = next;
+                    cursor = c;
+                },
+                None => break
+            }
+        }
+
+        let mut report = Report::default();
+        report.scanned = pushes.len();
+        report.candidates = self.policy.select(pushes, Timestamp::now());
+        if self.dry_run || report.candidates.is_empty() {
+            return Ok(report);
+        }
+
+        let queue = Arc::new(Mutex::new(report.candidates.iter().rev().map(|c| c.push.iden.clone()).collect::<Vec<_>>()));
+        let workers: Vec<_> = (0..cmp::min(self.concurrency, report.candidates.len())).map(|_| {
+            let mut api = api.fork();
+            let queue = queue.clone();
+            let backoff = backoff.clone();
+            thread::spawn(move || {
+                let mut deleted = 0;
+                let mut failed = Vec::new();
+                loop {
+                    let iden = match queue.lock().unwrap().pop() {
+                        Some(iden) => iden,
+                        None => break
+                    };
+                    match backoff.call(|| api.remove::<Push>(iden.clone())) {
+                        Ok(()) => deleted += 1,
+                        Err(e) => failed.push((iden, e))
+                    }
+                }
+                (deleted, failed)
+            })
+        }).collect();
+
+        let mut panicked = false;
+        for worker in workers {
+            match worker.join() {
+                Ok((deleted, failed)) => {
+                    report.deleted += deleted;
+                    report.failed.extend(failed);
+                },
+                Err(_) => panicked = true
+            }
+        }
+        if panicked {
+            return Err(From::from(Error::new("worker_panicked", "some pushes may be left, deleting thread panicked")));
+        }
+        Ok(report)
+    }
+}
+
+/// Retries of rate limited requests, shared by all workers of a run,
+/// so that none of them keeps hammering the API while others wait.
+#[derive(Clone)]
+struct Backoff {
+    delay: Duration,
+    attempts: usize,
+    paused_until: Arc<Mutex<Option<Instant>>>,
+}
+
+impl Backoff {
+    fn wait(&self) {
+        let until = *self.paused_until.lock().unwrap();
+        if let Some(until) = until {
+            let now = Instant::now();
+            if until > now {
+                thread::sleep(until - now);
+            }
+        }
+    }
+
+    fn call<T, F: FnMut() -> PbResult<T>>(&self, mut f: F) -> PbResult<T> {
+        let mut delay = self.delay;
+        let mut attempt = 1;
+        loop {
+            self.wait();
+            match f() {
+                Err(ref e) if e.is_rate_limited() && attempt < self.attempts => {
+                    let resume = Instant::now() + delay;
+                    let mut paused_until = self.paused_until.lock().unwrap();
+                    if paused_until.map_or(true, |t| t < resume) {
+                        *paused_until = Some(resume);
+                    }
+                    attempt += 1;
+                    delay = delay * 2;
+                },
+                result => return result
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use hyper::status::StatusCode;
+    use rustc_serialize::json;
+    use testing::MockServer;
+    use objects::{Push, Timestamp};
+    use messages::{PushMsg, TargetIden};
+    use url::Url;
+    use super::{Janitor, Policy, Reason};
+
+    fn push(iden: &str, created: f64, extra: &str) -> Push {
+        json::decode(&*format!("{{
+            \"iden\": \"{}\", \"created\": {}, \"modified\": {}, \"active\": true, \"dismissed\": false,
+            \"sender_email\": \"alerts@example.com\", \"type\": \"note\" {}
+        }}", iden, created, created, extra)).unwrap()
+    }
+
+    fn select(policy: Policy, pushes: Vec<Push>) -> Vec<(String, Reason)> {
+        policy.select(pushes, Timestamp::from(10000.0)).into_iter().map(|c| (c.push.iden.to_string(), c.reason)).collect()
+    }
+
+    #[test]
+    fn test_policy_select() {
+        let pushes = || vec![
+            push("old", 100.0, ""),
+            push("dismissed", 9000.0, ", \"dismissed\": true"),
+            push("link", 9100.0, ", \"type\": \"link\", \"url\": \"https://example.com\""),
+            push("phone1", 9200.0, ", \"source_device_iden\": \"phone\""),
+            push("phone2", 9300.0, ", \"source_device_iden\": \"phone\""),
+            push("human", 50.0, ", \"sender_email\": \"Ann@Example.com\""),
+        ];
+
+        assert!(select(Policy::new(), pushes()).is_empty());
+        assert_eq!(select(Policy::new().older_than(Duration::from_secs(3600)).dismissed(), pushes()),
+                   vec![("dismissed".to_string(), Reason::Dismissed), ("old".to_string(), Reason::Expired), ("human".to_string(), Reason::Expired)]);
+        assert_eq!(select(Policy::new().older_than(Duration::from_secs(5000)).sender("alerts@example.com"), pushes()),
+                   vec![("old".to_string(), Reason::Expired)]);
+        assert_eq!(select(Policy::new().max_per_device(1), pushes()),
+                   vec![("phone1".to_string(), Reason::Overflow), ("dismissed".to_string(), Reason::Overflow),
+                        ("old".to_string(), Reason::Overflow), ("human".to_string(), Reason::Overflow)]);
+        assert_eq!(select(Policy::new().typ("link"), pushes()), vec![("link".to_string(), Reason::Matched)]);
+    }
+
+    #[test]
+    fn test_janitor_run() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        for i in 0..6 {
+            api.send(&PushMsg::new(TargetIden::CurrentUser).body(format!("alert {}", i))).unwrap();
+        }
+        let keep = api.send(&PushMsg::link(TargetIden::CurrentUser, Url::parse("https://example.com").unwrap())).unwrap();
+        let policy = Policy::new().typ("note").max_per_device(2);
+
+        let report = Janitor::new(policy.clone()).dry_run().run(&mut api).unwrap();
+        assert_eq!((report.scanned, report.candidates.len(), report.deleted), (7, 4, 0));
+        assert_eq!(api.load_all::<Push>().unwrap().iter().filter(|p| p.active).count(), 7);
+
+        server.fail_next(StatusCode::TooManyRequests);
+        server.fail_next(StatusCode::TooManyRequests);
+        let report = Janitor::new(policy.clone()).concurrency(3).backoff(Duration::from_millis(10), 3).run(&mut api).unwrap();
+        assert_eq!((report.candidates.len(), report.deleted, report.failed.len()), (4, 4, 0));
+
+        let left: Vec<Push> = api.load_all::<Push>().unwrap().into_iter().filter(|p| p.active).collect();
+        assert_eq!(left.len(), 3);
+        assert!(left.iter().any(|p| p.iden == keep.iden));
+        assert!(left.iter().any(|p| p.body == Some("alert 5".to_string())));
+
+        assert_eq!(Janitor::new(policy).run(&mut api).unwrap().candidates.len(), 0);
+    }
+}
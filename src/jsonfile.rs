@@ -0,0 +1,84 @@
+//! State kept in JSON files between runs.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use rustc_serialize::{json, Decodable, Encodable};
+use uuid::Uuid;
+
+use api::PbResult;
+
+/// Locks are only held while a file is read and written back,
+/// older ones were left by crashed processes.
+static STALE_LOCK_SECS: u64 = 30;
+
+/// Reads a value from `path`, `None` if the file doesn't exist.
+pub fn load<T: Decodable>(path: &Path) -> PbResult<Option<T>> {
+    match File::open(path) {
+        Ok(mut file) => {
+            let mut data = String::new();
+            try!(file.read_to_string(&mut data));
+            Ok(Some(try!(json::decode(&*data))))
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(From::from(e))
+    }
+}
+
+/// Writes `value` into `path`.
+pub fn save<T: Encodable>(path: &Path, value: &T) -> PbResult<()> {
+    // write a new copy and replace the old one, so a crash never leaves a broken file
+    let data = try!(json::encode(value));
+    let tmp = path.with_extension("tmp");
+    try!(File::create(&tmp).and_then(|mut f| f.write_all(data.as_bytes())));
+    try!(fs::rename(&tmp, path));
+    Ok(())
+}
+
+/// Exclusive lock of a state file, so that processes changing it
+/// at the same time don't lose each other's changes. Released on drop.
+pub struct Lock {
+    path: PathBuf,
+}
+
+impl Lock {
+    /// Waits until `path` isn't locked by other processes and locks it.
+    pub fn acquire(path: &Path) -> PbResult<Lock> {
+        let lock = path.with_extension("lock");
+        loop {
+            match OpenOptions::new().write(true).create_new(true).open(&lock) {
+                Ok(_) => return Ok(Lock { path: lock }),
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    if is_stale(&lock) {
+                        // moved away first, so that of processes finding it stale at the same time
+                        // only one removes it, and a lock taken by another one meanwhile is given back
+                        let taken = lock.with_extension(format!("lock.{}", Uuid::new_v4().simple()));
+                        if fs::rename(&lock, &taken).is_ok() {
+                            if !is_stale(&taken) {
+                                let _ = fs::hard_link(&taken, &lock);
+                            }
+                            let _ = fs::remove_file(&taken);
+                        }
+                    } else {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                },
+                Err(e) => return Err(From::from(e))
+            }
+        }
+    }
+}
+
+fn is_stale(lock: &Path) -> bool {
+    fs::metadata(lock).and_then(|m| m.modified()).ok().and_then(|t| t.elapsed().ok())
+        .map_or(false, |age| age > Duration::from_secs(STALE_LOCK_SECS))
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
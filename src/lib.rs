@@ -7,12 +7,42 @@ extern crate hyper;
 //extern crate websocket;
 extern crate url;
 extern crate rustc_serialize;
+extern crate uuid;
+#[cfg(feature = "sqlite")]
+extern crate rusqlite;
 
-pub use objects::{Iden, Cursor, Timestamp, Envelope, Push, PushData, Account, Device, Contact, Client, Channel, ChannelInfo, Subscription, Grant, ListItem, Error};
-pub use messages::{TargetIden, PushMsg, DeviceMsg, ContactMsg};
-pub use api::{PbAPI, PbError, PbResult, PbVec};
+pub use objects::{Resource, Listable, Creatable, Updatable, Deletable, Iden, PushIden, DeviceIden, ChatIden, ChannelIden, SubscriptionIden, ClientIden, GrantIden, TextIden, Cursor, Timestamp, Envelope, Push, PushData, FileInfo, Account, Device, DeviceIcon, Contact, Chat, ChatWith, Client, Channel, ChannelInfo, Subscription, Grant, Text, TextData, ListItem, Error};
+pub use messages::{TargetIden, PushMsg, DeviceMsg, ContactMsg, ChatMsg, SubscriptionMsg, ChannelMsg, TextMsg};
+pub use api::{PbAPI, PbError, PbResult, PbVec, RetryPolicy};
+pub use cassette::Cassette;
+pub use config::{Config, Profile};
+pub use store::{Store, Storable};
+pub use search::{PushIndex, Query};
+pub use export::{Export, ExportSummary, Format, ImportSummary};
+pub use backup::{Snapshot, Plan, Change, RestoreSummary};
+pub use janitor::{Janitor, Policy};
+pub use escalate::{Escalator, Recipient, Outcome};
+pub use throttle::Throttle;
+pub use digest::Digest;
+pub use schedule::{Scheduler, Tick, Recurrence, Cron, Missed};
+pub use events::{Event, EventStream};
 
 pub mod objects;
 pub mod events;
 pub mod messages;
 pub mod api;
+pub mod cassette;
+pub mod config;
+pub mod store;
+pub mod search;
+pub mod export;
+pub mod backup;
+pub mod janitor;
+pub mod escalate;
+pub mod throttle;
+pub mod digest;
+pub mod schedule;
+mod jsonfile;
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
@@ -1,32 +1,82 @@
 
 use std::borrow::Cow;
 use rustc_serialize::{Encodable, Encoder};
-use objects::{Iden, PushData};
+use url::Url;
+use objects::{DeviceIden, ClientIden, PushData, DeviceIcon, ListItem, FileInfo, TextData};
 
 #[cfg(test)]
 use rustc_serialize::json;
 
-pub trait PbMsg : Encodable {
-    type Obj: super::objects::PbObj;
+pub trait PbMsg : Encodable + Sized {
+    type Obj: super::objects::Creatable + super::objects::Listable;
+
+    fn guid(&self) -> Option<&str> { None }
+
+    /// Returns a copy of the message tagged with given guid,
+    /// or `None` if the message kind doesn't support idempotent sends.
+    #[allow(unused_variables)]
+    fn with_guid(&self, guid: String) -> Option<Self> { None }
 }
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Debug, Clone)]
 pub enum TargetIden {
     CurrentUser,
-    DeviceIden(Iden),
+    DeviceIden(DeviceIden),
     ContactEmail(String),
     ChannelTag(String),
-    ClientIden(Iden),
+    ClientIden(ClientIden),
 }
 
-#[derive(PartialEq, Debug)]
+impl TargetIden {
+    /// Checks whether both targets address the same recipient,
+    /// e.g. the same email written in different case.
+    pub fn is_same(&self, other: &TargetIden) -> bool {
+        match (self, other) {
+            (&TargetIden::ContactEmail(ref a), &TargetIden::ContactEmail(ref b)) => a.trim().to_lowercase() == b.trim().to_lowercase(),
+            (a, b) => a == b
+        }
+    }
+
+    /// Short string form of a target, e.g. `device:ujpah72o0`, for keeping it in files.
+    pub fn key(&self) -> String {
+        match *self {
+            TargetIden::CurrentUser => "user".to_string(),
+            TargetIden::DeviceIden(ref iden) => format!("device:{}", iden),
+            TargetIden::ContactEmail(ref email) => format!("email:{}", email.to_lowercase()),
+            TargetIden::ChannelTag(ref tag) => format!("channel:{}", tag),
+            TargetIden::ClientIden(ref iden) => format!("client:{}", iden),
+        }
+    }
+
+    /// Parses `key` form of a target, `None` if it's malformed.
+    pub fn from_key(key: &str) -> Option<TargetIden> {
+        let (kind, value) = match key.find(':') {
+            Some(idx) => (&key[..idx], &key[idx + 1..]),
+            None if key == "user" => return Some(TargetIden::CurrentUser),
+            None => return None
+        };
+        if value.is_empty() {
+            return None;
+        }
+        match kind {
+            "device" => Some(TargetIden::DeviceIden(DeviceIden::from(value))),
+            "email" => Some(TargetIden::ContactEmail(value.to_string())),
+            "channel" => Some(TargetIden::ChannelTag(value.to_string())),
+            "client" => Some(TargetIden::ClientIden(ClientIden::from(value))),
+            _ => None
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, Clone)]
 pub struct PushMsg<'a> {
     pub title: Option<Cow<'a, str>>,
     pub body: Option<Cow<'a, str>>,
 
     pub target: TargetIden,
     pub data: PushData,
-    pub source_device_iden: Option<Iden>,
+    pub source_device_iden: Option<DeviceIden>,
+    pub guid: Option<Cow<'a, str>>,
 }
 
 impl<'a> PushMsg<'a> {
@@ -36,7 +86,8 @@ impl<'a> PushMsg<'a> {
             body: None,
             target: target,
             data: PushData::Note,
-            source_device_iden: None
+            source_device_iden: None,
+            guid: None
         }
     }
 
@@ -46,10 +97,27 @@ impl<'a> PushMsg<'a> {
             body: body.map(Into::into),
             target: target,
             data: PushData::Note,
-            source_device_iden: None
+            source_device_iden: None,
+            guid: None
         }
     }
 
+    pub fn link(target: TargetIden, url: Url) -> PushMsg<'a> {
+        PushMsg::new(target).data(PushData::Link(Some(url)))
+    }
+
+    pub fn list(target: TargetIden, items: Vec<ListItem>) -> PushMsg<'a> {
+        PushMsg::new(target).data(PushData::List(items))
+    }
+
+    pub fn file(target: TargetIden, file: FileInfo) -> PushMsg<'a> {
+        PushMsg::new(target).data(file.into())
+    }
+
+    pub fn address<A: Into<String>>(target: TargetIden, address: A) -> PushMsg<'a> {
+        PushMsg::new(target).data(PushData::Address(address.into()))
+    }
+
     pub fn title<T: Into<Cow<'a, str>>>(mut self, title: T) -> PushMsg<'a> {
         self.title = Some(title.into());
         self
@@ -60,7 +128,7 @@ impl<'a> PushMsg<'a> {
         self
     }
 
-    pub fn source(mut self, source: Iden) -> PushMsg<'a> {
+    pub fn source(mut self, source: DeviceIden) -> PushMsg<'a> {
         self.source_device_iden = Some(source);
         self
     }
@@ -69,10 +137,26 @@ impl<'a> PushMsg<'a> {
         self.data = data;
         self
     }
+
+    pub fn target(mut self, target: TargetIden) -> PushMsg<'a> {
+        self.target = target;
+        self
+    }
+
+    pub fn guid<T: Into<Cow<'a, str>>>(mut self, guid: T) -> PushMsg<'a> {
+        self.guid = Some(guid.into());
+        self
+    }
 }
 
 impl<'a> PbMsg for PushMsg<'a> {
     type Obj = super::objects::Push;
+
+    fn guid(&self) -> Option<&str> { self.guid.as_ref().map(|s| &**s) }
+
+    fn with_guid(&self, guid: String) -> Option<PushMsg<'a>> {
+        Some(self.clone().guid(guid))
+    }
 }
 
 impl<'a> Encodable for PushMsg<'a> {
@@ -80,7 +164,9 @@ impl<'a> Encodable for PushMsg<'a> {
         encoder.emit_struct("PushMsg", 5, |e| {
             try!(e.emit_struct_field("title", 0, |e| self.title.encode(e)));
             try!(e.emit_struct_field("body", 1, |e| self.body.encode(e)));
-            try!(e.emit_struct_field("source_device_iden", 2, |e| self.source_device_iden.encode(e)));
+            if let Some(ref iden) = self.source_device_iden {
+                try!(e.emit_struct_field("source_device_iden", 2, |e| iden.encode(e)));
+            }
             try!(match self.target {
                 TargetIden::CurrentUser => Ok(()),
                 TargetIden::DeviceIden(ref iden) => e.emit_struct_field("device_iden", 3, |e| e.emit_str(&**iden)),
@@ -88,6 +174,9 @@ impl<'a> Encodable for PushMsg<'a> {
                 TargetIden::ChannelTag(ref tag) => e.emit_struct_field("channel_tag", 3, |e| e.emit_str(&**tag)),
                 TargetIden::ClientIden(ref iden) => e.emit_struct_field("client_iden", 3, |e| e.emit_str(&**iden)),
             });
+            if let Some(ref guid) = self.guid {
+                try!(e.emit_struct_field("guid", 4, |e| guid.encode(e)));
+            }
             try!(self.data.encode(e));
             Ok(())
         })
@@ -97,7 +186,63 @@ impl<'a> Encodable for PushMsg<'a> {
 #[derive(PartialEq, Debug)]
 pub struct DeviceMsg<'a> {
     pub nickname: Cow<'a, str>,
-    pub typ: Cow<'a, str>,
+    pub typ: Option<Cow<'a, str>>,
+    pub model: Option<Cow<'a, str>>,
+    pub manufacturer: Option<Cow<'a, str>>,
+    pub push_token: Option<Cow<'a, str>>,
+    pub app_version: Option<usize>,
+    pub icon: Option<DeviceIcon>,
+    pub has_sms: Option<bool>,
+}
+
+impl<'a> DeviceMsg<'a> {
+    pub fn new<T: Into<Cow<'a, str>>>(nickname: T) -> DeviceMsg<'a> {
+        DeviceMsg {
+            nickname: nickname.into(),
+            typ: None,
+            model: None,
+            manufacturer: None,
+            push_token: None,
+            app_version: None,
+            icon: None,
+            has_sms: None
+        }
+    }
+
+    pub fn typ<T: Into<Cow<'a, str>>>(mut self, typ: T) -> DeviceMsg<'a> {
+        self.typ = Some(typ.into());
+        self
+    }
+
+    pub fn model<T: Into<Cow<'a, str>>>(mut self, model: T) -> DeviceMsg<'a> {
+        self.model = Some(model.into());
+        self
+    }
+
+    pub fn manufacturer<T: Into<Cow<'a, str>>>(mut self, manufacturer: T) -> DeviceMsg<'a> {
+        self.manufacturer = Some(manufacturer.into());
+        self
+    }
+
+    pub fn push_token<T: Into<Cow<'a, str>>>(mut self, push_token: T) -> DeviceMsg<'a> {
+        self.push_token = Some(push_token.into());
+        self
+    }
+
+    pub fn app_version(mut self, app_version: usize) -> DeviceMsg<'a> {
+        self.app_version = Some(app_version);
+        self
+    }
+
+    pub fn icon(mut self, icon: DeviceIcon) -> DeviceMsg<'a> {
+        self.icon = Some(icon);
+        self
+    }
+
+    pub fn has_sms(mut self, has_sms: bool) -> DeviceMsg<'a> {
+        self.has_sms = Some(has_sms);
+        self
+    }
 }
 
 impl<'a> PbMsg for DeviceMsg<'a> {
@@ -106,9 +251,29 @@ impl<'a> PbMsg for DeviceMsg<'a> {
 
 impl<'a> Encodable for DeviceMsg<'a> {
     fn encode<S: Encoder>(&self, encoder: &mut S) -> Result<(), S::Error> {
-        encoder.emit_struct("DeviceMsg", 2, |e| {
+        encoder.emit_struct("DeviceMsg", 8, |e| {
             try!(e.emit_struct_field("nickname", 0, |e| self.nickname.encode(e)));
-            try!(e.emit_struct_field("type", 1, |e| self.typ.encode(e)));
+            if let Some(ref typ) = self.typ {
+                try!(e.emit_struct_field("type", 1, |e| typ.encode(e)));
+            }
+            if let Some(ref model) = self.model {
+                try!(e.emit_struct_field("model", 2, |e| model.encode(e)));
+            }
+            if let Some(ref manufacturer) = self.manufacturer {
+                try!(e.emit_struct_field("manufacturer", 3, |e| manufacturer.encode(e)));
+            }
+            if let Some(ref push_token) = self.push_token {
+                try!(e.emit_struct_field("push_token", 4, |e| push_token.encode(e)));
+            }
+            if let Some(app_version) = self.app_version {
+                try!(e.emit_struct_field("app_version", 5, |e| app_version.encode(e)));
+            }
+            if let Some(ref icon) = self.icon {
+                try!(e.emit_struct_field("icon", 6, |e| icon.encode(e)));
+            }
+            if let Some(has_sms) = self.has_sms {
+                try!(e.emit_struct_field("has_sms", 7, |e| has_sms.encode(e)));
+            }
             Ok(())
         })
     }
@@ -124,31 +289,200 @@ impl<'a> PbMsg for ContactMsg<'a> {
     type Obj = super::objects::Contact;
 }
 
+#[derive(PartialEq, Debug, RustcEncodable)]
+pub struct ChatMsg<'a> {
+    pub email: Cow<'a, str>,
+}
+
+impl<'a> PbMsg for ChatMsg<'a> {
+    type Obj = super::objects::Chat;
+}
+
+/// SMS to send from a phone, see `PbAPI::send_text`.
+#[derive(PartialEq, Debug, Clone, RustcEncodable)]
+pub struct TextMsg {
+    pub data: TextData,
+}
+
+impl TextMsg {
+    pub fn new<M: Into<String>>(device: DeviceIden, addresses: Vec<String>, message: M) -> TextMsg {
+        TextMsg {
+            data: TextData {
+                target_device_iden: device,
+                addresses: addresses,
+                message: message.into(),
+            }
+        }
+    }
+}
+
+#[derive(PartialEq, Debug, RustcEncodable)]
+pub struct SubscriptionMsg<'a> {
+    pub channel_tag: Cow<'a, str>,
+}
+
+impl<'a> SubscriptionMsg<'a> {
+    pub fn new<T: Into<Cow<'a, str>>>(channel_tag: T) -> SubscriptionMsg<'a> {
+        SubscriptionMsg { channel_tag: channel_tag.into() }
+    }
+}
+
+impl<'a> PbMsg for SubscriptionMsg<'a> {
+    type Obj = super::objects::Subscription;
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct ChannelMsg<'a> {
+    pub tag: Cow<'a, str>,
+    pub name: Cow<'a, str>,
+    pub description: Cow<'a, str>,
+    pub image_url: Option<Url>,
+    pub website_url: Option<Url>,
+    pub feed_url: Option<Url>,
+}
+
+impl<'a> ChannelMsg<'a> {
+    pub fn new<T: Into<Cow<'a, str>>, N: Into<Cow<'a, str>>, D: Into<Cow<'a, str>>>(tag: T, name: N, description: D) -> ChannelMsg<'a> {
+        ChannelMsg {
+            tag: tag.into(),
+            name: name.into(),
+            description: description.into(),
+            image_url: None,
+            website_url: None,
+            feed_url: None,
+        }
+    }
+
+    pub fn image_url(mut self, url: Url) -> ChannelMsg<'a> {
+        self.image_url = Some(url);
+        self
+    }
+
+    pub fn website_url(mut self, url: Url) -> ChannelMsg<'a> {
+        self.website_url = Some(url);
+        self
+    }
+
+    pub fn feed_url(mut self, url: Url) -> ChannelMsg<'a> {
+        self.feed_url = Some(url);
+        self
+    }
+}
+
+impl<'a> PbMsg for ChannelMsg<'a> {
+    type Obj = super::objects::Channel;
+}
+
+impl<'a> Encodable for ChannelMsg<'a> {
+    fn encode<S: Encoder>(&self, encoder: &mut S) -> Result<(), S::Error> {
+        encoder.emit_struct("ChannelMsg", 6, |e| {
+            try!(e.emit_struct_field("tag", 0, |e| self.tag.encode(e)));
+            try!(e.emit_struct_field("name", 1, |e| self.name.encode(e)));
+            try!(e.emit_struct_field("description", 2, |e| self.description.encode(e)));
+            if let Some(ref url) = self.image_url {
+                try!(e.emit_struct_field("image_url", 3, |e| url.encode(e)));
+            }
+            if let Some(ref url) = self.website_url {
+                try!(e.emit_struct_field("website_url", 4, |e| url.encode(e)));
+            }
+            if let Some(ref url) = self.feed_url {
+                try!(e.emit_struct_field("feed_url", 5, |e| url.encode(e)));
+            }
+            Ok(())
+        })
+    }
+}
+
 #[test]
 fn test_push_msg_encode() {
     let push = PushMsg {
         title: Some("Note Title".into()),
         body: Some("Note Body".into()),
 
-        target: TargetIden::DeviceIden("udx234acsdc".to_string()),
+        target: TargetIden::DeviceIden(DeviceIden::from("udx234acsdc")),
         data: PushData::Note,
         source_device_iden: None,
+        guid: None,
     };
-    assert_eq!(&*json::encode(&push).unwrap(), "{\"title\":\"Note Title\",\"body\":\"Note Body\",\"source_device_iden\":null,\"device_iden\":\"udx234acsdc\",\"type\":\"note\"}");
+    assert_eq!(&*json::encode(&push).unwrap(), "{\"title\":\"Note Title\",\"body\":\"Note Body\",\"device_iden\":\"udx234acsdc\",\"type\":\"note\"}");
 }
 
 #[test]
 fn test_device_msg_encode() {
-    let device = DeviceMsg {
-        nickname: "Nickname".into(),
-        typ: "stream".into()
-    };
+    let device = DeviceMsg::new("Nickname").typ("stream");
     assert_eq!(&*json::encode(&device).unwrap(), "{\"nickname\":\"Nickname\",\"type\":\"stream\"}");
 }
 
+#[test]
+fn test_build_device_msg() {
+    let device = DeviceMsg::new("build-01")
+        .model("PowerEdge R630")
+        .manufacturer("Dell")
+        .app_version(8623)
+        .icon(DeviceIcon::System)
+        .has_sms(false);
+    assert_eq!(&*json::encode(&device).unwrap(), "{\"nickname\":\"build-01\",\"model\":\"PowerEdge R630\",\"manufacturer\":\"Dell\",\"app_version\":8623,\"icon\":\"system\",\"has_sms\":false}");
+}
+
+#[test]
+fn test_channel_msg_encode() {
+    let channel = ChannelMsg::new("releases", "Releases", "New versions")
+        .website_url(Url::parse("https://github.com/kstep/rust-pb").unwrap());
+    assert_eq!(&*json::encode(&channel).unwrap(), "{\"tag\":\"releases\",\"name\":\"Releases\",\"description\":\"New versions\",\"website_url\":\"https://github.com/kstep/rust-pb\"}");
+    assert_eq!(&*json::encode(&SubscriptionMsg::new("releases")).unwrap(), "{\"channel_tag\":\"releases\"}");
+}
+
 #[test]
 fn test_build_msg_push() {
-    let push = PushMsg::new(TargetIden::DeviceIden("udx111asdf".to_string()))
+    let push = PushMsg::new(TargetIden::DeviceIden(DeviceIden::from("udx111asdf")))
         .body("Hello, world").title("Title");
-    assert_eq!(&*json::encode(&push).unwrap(), "{\"title\":\"Title\",\"body\":\"Hello, world\",\"source_device_iden\":null,\"device_iden\":\"udx111asdf\",\"type\":\"note\"}");
+    assert_eq!(&*json::encode(&push).unwrap(), "{\"title\":\"Title\",\"body\":\"Hello, world\",\"device_iden\":\"udx111asdf\",\"type\":\"note\"}");
+}
+
+#[test]
+fn test_target_iden_is_same() {
+    assert!(TargetIden::ContactEmail("Ops@Example.com".to_string()).is_same(&TargetIden::ContactEmail("ops@example.com".to_string())));
+    assert!(TargetIden::CurrentUser.is_same(&TargetIden::CurrentUser));
+    assert!(!TargetIden::DeviceIden(DeviceIden::from("udx1")).is_same(&TargetIden::DeviceIden(DeviceIden::from("UDX1"))));
+    assert!(!TargetIden::ChannelTag("alerts".to_string()).is_same(&TargetIden::ContactEmail("alerts".to_string())));
+}
+
+#[test]
+fn test_target_iden_key() {
+    for target in vec![TargetIden::CurrentUser, TargetIden::DeviceIden(DeviceIden::from("udx1")),
+                       TargetIden::ContactEmail("ops@example.com".to_string()), TargetIden::ChannelTag("ops".to_string())] {
+        assert_eq!(TargetIden::from_key(&*target.key()), Some(target));
+    }
+    for key in &["", "users", "phone:udx1", "device:"] {
+        assert_eq!(TargetIden::from_key(key), None);
+    }
+}
+
+#[test]
+fn test_push_msg_guid_encode() {
+    let push = PushMsg::note(TargetIden::CurrentUser, Some("Disk full"), None::<&str>)
+        .guid("0b3d6f2a-8c1e-4a57-9e2f-6d4c8b1a7f30");
+    assert_eq!(PbMsg::guid(&push), Some("0b3d6f2a-8c1e-4a57-9e2f-6d4c8b1a7f30"));
+    assert_eq!(&*json::encode(&push).unwrap(), "{\"title\":\"Disk full\",\"body\":null,\"guid\":\"0b3d6f2a-8c1e-4a57-9e2f-6d4c8b1a7f30\",\"type\":\"note\"}");
+}
+
+#[test]
+fn test_build_typed_pushes() {
+    let link = PushMsg::link(TargetIden::ChannelTag("releases".to_string()), Url::parse("https://github.com/kstep/rust-pb").unwrap())
+        .title("rust-pb");
+    assert_eq!(&*json::encode(&link).unwrap(), "{\"title\":\"rust-pb\",\"body\":null,\"channel_tag\":\"releases\",\"type\":\"link\",\"url\":\"https://github.com/kstep/rust-pb\"}");
+
+    let list = PushMsg::list(TargetIden::CurrentUser, vec![ListItem::new("milk", false), ListItem::new("eggs", true)]);
+    assert_eq!(&*json::encode(&list).unwrap(), "{\"title\":null,\"body\":null,\"type\":\"list\",\"items\":[{\"checked\":false,\"text\":\"milk\"},{\"checked\":true,\"text\":\"eggs\"}]}");
+
+    let file = PushMsg::file(TargetIden::CurrentUser, FileInfo {
+        file_name: "report.pdf".to_string(),
+        file_type: "application/pdf".to_string(),
+        file_url: Url::parse("https://dl.pushbulletusercontent.com/abc/report.pdf").unwrap(),
+        image_url: None,
+    }).source(DeviceIden::from("udx234acsdc"));
+    assert_eq!(&*json::encode(&file).unwrap(), "{\"title\":null,\"body\":null,\"source_device_iden\":\"udx234acsdc\",\"type\":\"file\",\"file_name\":\"report.pdf\",\"file_type\":\"application/pdf\",\"file_url\":\"https://dl.pushbulletusercontent.com/abc/report.pdf\"}");
+
+    let address = PushMsg::address(TargetIden::CurrentUser, "221B Baker St, London");
+    assert_eq!(address.data, PushData::Address("221B Baker St, London".to_string()));
 }
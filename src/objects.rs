@@ -1,17 +1,292 @@
 use std::str::FromStr;
 use url::Url;
 use rustc_serialize::{Encodable, Decodable, Encoder, Decoder};
+use rustc_serialize::json::{Json, ToJson};
+use std::cmp::Ordering;
 use std::error;
 use std::fmt;
-use std::ops::Deref;
+use std::hash::{Hash, Hasher};
+use std::ops::{Add, Sub, Deref};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
+/// Untyped iden, used for users and where object kind is not known.
 pub type Iden = String;
 pub type Cursor = String;
-pub type Timestamp = f64;
+
+macro_rules! iden_types {
+    ($($(#[$attr:meta])* $name:ident),+) => {
+        $(
+            $(#[$attr])*
+            #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+            pub struct $name(String);
+
+            impl $name {
+                pub fn new<S: Into<String>>(iden: S) -> $name { $name(iden.into()) }
+                pub fn as_str(&self) -> &str { &*self.0 }
+                pub fn into_string(self) -> String { self.0 }
+            }
+
+            impl From<String> for $name {
+                fn from(iden: String) -> $name { $name(iden) }
+            }
+
+            impl<'a> From<&'a str> for $name {
+                fn from(iden: &'a str) -> $name { $name(iden.to_string()) }
+            }
+
+            impl Deref for $name {
+                type Target = str;
+                fn deref(&self) -> &str { &*self.0 }
+            }
+
+            impl PartialEq<str> for $name {
+                fn eq(&self, other: &str) -> bool { self.0 == other }
+            }
+
+            impl fmt::Display for $name {
+                fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> { fmt.write_str(&*self.0) }
+            }
+
+            impl Encodable for $name {
+                fn encode<S: Encoder>(&self, encoder: &mut S) -> Result<(), S::Error> { encoder.emit_str(&*self.0) }
+            }
+
+            impl Decodable for $name {
+                fn decode<S: Decoder>(decoder: &mut S) -> Result<$name, S::Error> { decoder.read_str().map($name) }
+            }
+        )+
+    }
+}
+
+iden_types! {
+    PushIden,
+    DeviceIden,
+    /// Iden of a chat, listed by API as a contact.
+    ChatIden,
+    ChannelIden,
+    SubscriptionIden,
+    ClientIden,
+    GrantIden,
+    TextIden
+}
+
+/// Point in time as seconds since Unix epoch, the way API sends it.
+///
+/// Kept as the `f64` parsed from server response, which formats back to the same
+/// number for API timestamps (microsecond precision), e.g. for `modified_after`.
+/// Timestamps are totally ordered.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timestamp(f64);
+
+fn duration_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + d.subsec_nanos() as f64 / 1e9
+}
+
+impl Timestamp {
+    pub fn now() -> Timestamp {
+        Timestamp::from(SystemTime::now())
+    }
+
+    pub fn as_f64(&self) -> f64 {
+        self.0
+    }
+
+    /// Start of the given minute in UTC.
+    pub fn from_utc(year: i64, month: u32, day: u32, hour: u32, minute: u32) -> Timestamp {
+        Timestamp((days_from_civil(year, month, day) * 86400 + hour as i64 * 3600 + minute as i64 * 60) as f64)
+    }
+
+    /// Formats as `YYYY-MM-DD HH:MM:SS UTC`.
+    pub fn format_utc(&self) -> String {
+        let secs = self.0.floor() as i64;
+        let (days, time) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+        let (year, month, day) = civil_from_days(days);
+        format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC", year, month, day, time / 3600, time / 60 % 60, time % 60)
+    }
+
+    /// Key with the same ordering as IEEE 754 `totalOrder`.
+    fn key(&self) -> i64 {
+        let bits = self.0.to_bits() as i64;
+        bits ^ (((bits >> 63) as u64) >> 1) as i64
+    }
+}
+
+/// Year, month and day of a day counted from Unix epoch,
+/// see http://howardhinnant.github.io/date_algorithms.html
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month as u32, day as u32)
+}
+
+/// Days since Unix epoch of a date, the inverse of `civil_from_days`.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+impl From<f64> for Timestamp {
+    fn from(secs: f64) -> Timestamp { Timestamp(secs) }
+}
+
+impl From<SystemTime> for Timestamp {
+    fn from(time: SystemTime) -> Timestamp {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => Timestamp(duration_secs(d)),
+            Err(e) => Timestamp(-duration_secs(e.duration()))
+        }
+    }
+}
+
+impl From<Timestamp> for SystemTime {
+    fn from(ts: Timestamp) -> SystemTime {
+        let d = Duration::new(ts.0.abs().trunc() as u64, (ts.0.abs().fract() * 1e9) as u32);
+        if ts.0 < 0.0 { UNIX_EPOCH - d } else { UNIX_EPOCH + d }
+    }
+}
+
+impl Add<Duration> for Timestamp {
+    type Output = Timestamp;
+    fn add(self, d: Duration) -> Timestamp { Timestamp(self.0 + duration_secs(d)) }
+}
+
+impl Sub<Duration> for Timestamp {
+    type Output = Timestamp;
+    fn sub(self, d: Duration) -> Timestamp { Timestamp(self.0 - duration_secs(d)) }
+}
+
+impl PartialEq for Timestamp {
+    fn eq(&self, other: &Timestamp) -> bool { self.key() == other.key() }
+}
+
+impl Eq for Timestamp {}
+
+impl PartialOrd for Timestamp {
+    fn partial_cmp(&self, other: &Timestamp) -> Option<Ordering> { Some(self.cmp(other)) }
+}
+
+impl Ord for Timestamp {
+    fn cmp(&self, other: &Timestamp) -> Ordering { self.key().cmp(&other.key()) }
+}
+
+impl Hash for Timestamp {
+    fn hash<H: Hasher>(&self, state: &mut H) { self.key().hash(state) }
+}
+
+/// Formats the shortest decimal which parses back into the same timestamp.
+impl fmt::Display for Timestamp {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt::Display::fmt(&self.0, fmt)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TimestampParseError;
+
+impl error::Error for TimestampParseError {
+    fn description(&self) -> &str { "invalid timestamp" }
+}
+
+impl fmt::Display for TimestampParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(error::Error::description(self))
+    }
+}
+
+impl FromStr for Timestamp {
+    type Err = TimestampParseError;
+    fn from_str(s: &str) -> Result<Timestamp, TimestampParseError> {
+        match s.trim().parse::<f64>() {
+            Ok(secs) if secs.is_finite() => Ok(Timestamp(secs)),
+            _ => Err(TimestampParseError)
+        }
+    }
+}
+
+impl Encodable for Timestamp {
+    fn encode<S: Encoder>(&self, encoder: &mut S) -> Result<(), S::Error> {
+        encoder.emit_f64(self.0)
+    }
+}
+
+impl Decodable for Timestamp {
+    fn decode<S: Decoder>(decoder: &mut S) -> Result<Timestamp, S::Error> {
+        let secs = try!(decoder.read_f64());
+        if secs.is_finite() { Ok(Timestamp(secs)) } else { Err(decoder.error("invalid timestamp")) }
+    }
+}
+
+impl ToJson for Timestamp {
+    fn to_json(&self) -> Json { Json::F64(self.0) }
+}
 
 pub trait PbObj : Decodable + Sized {
-    //fn uri(&self) -> String { format!("{}/{}", PbObj::root_uri(None::<Self>), self.iden()) }
+    /// Iden type of this kind of objects.
+    type Iden: fmt::Display;
+
     fn root_uri() -> &'static str;
+    fn guid(&self) -> Option<&str> { None }
+}
+
+/// Fields common to all stored objects, for code generic over object kinds.
+pub trait Resource : PbObj {
+    fn iden(&self) -> &Self::Iden;
+    fn created(&self) -> Timestamp;
+    fn modified(&self) -> Timestamp;
+    fn active(&self) -> bool;
+
+    /// Object path relative to API base URL.
+    fn uri(&self) -> String { format!("{}/{}", Self::root_uri(), self.iden()) }
+}
+
+/// Objects which can be listed and loaded with `PbAPI::load*` methods.
+///
+/// ```compile_fail
+/// # let mut api = pb::PbAPI::new("key");
+/// api.load::<pb::Client>(); // OAuth clients can't be listed
+/// ```
+pub trait Listable : PbObj + FromEnvelope {}
+
+/// Objects which can be created with `PbAPI::send`.
+pub trait Creatable : PbObj {}
+
+/// Objects which can be changed with `PbAPI::update`.
+pub trait Updatable : PbObj {}
+
+/// Objects which can be deleted with `PbAPI::remove`.
+///
+/// ```compile_fail
+/// # let mut api = pb::PbAPI::new("key");
+/// api.remove::<pb::Channel>(pb::ChannelIden::from("abc"));
+/// ```
+pub trait Deletable : PbObj {}
+
+macro_rules! capability_impl {
+    ($cap:ident: $($t:ty),+) => {
+        $(impl $cap for $t {})+
+    }
+}
+
+macro_rules! resource_impl {
+    ($($t:ty),+) => {
+        $(impl Resource for $t {
+            #[inline] fn iden(&self) -> &<$t as PbObj>::Iden { &self.iden }
+            #[inline] fn created(&self) -> Timestamp { self.created }
+            #[inline] fn modified(&self) -> Timestamp { self.modified }
+            #[inline] fn active(&self) -> bool { self.active }
+        })+
+    }
 }
 
 #[derive(Debug, PartialEq, RustcDecodable, RustcEncodable)]
@@ -34,10 +309,16 @@ pub struct Account {
     api_key: String
 }
 
-//impl PbObj for Account {
-    //fn uri(&self) -> String { "users/me".to_string() }
-    //fn iden<'a>(&'a self) -> &'a Iden { &self.iden }
-//}
+impl Account {
+    pub fn iden(&self) -> &Iden { &self.iden }
+    pub fn created(&self) -> Timestamp { self.created }
+    pub fn modified(&self) -> Timestamp { self.modified }
+    pub fn email(&self) -> &str { &*self.email }
+    pub fn email_normalized(&self) -> &str { &*self.email_normalized }
+    pub fn name(&self) -> &str { &*self.name }
+    pub fn image_url(&self) -> &Url { &self.image_url }
+    pub fn api_key(&self) -> &str { &*self.api_key }
+}
 
 #[derive(Debug, PartialEq)]
 pub struct Device {
@@ -46,7 +327,7 @@ pub struct Device {
     modified: Timestamp,
     active: bool,
     pushable: bool,
-    iden: Iden,
+    iden: DeviceIden,
     push_token: Option<String>,
     fingerprint: Option<String>,
     nickname: String,
@@ -54,11 +335,97 @@ pub struct Device {
     model: Option<String>,
     kind: String,
     typ: String, // type
+    icon: Option<DeviceIcon>,
+    has_sms: Option<bool>,
+}
+
+impl Device {
+    pub fn iden(&self) -> &DeviceIden { &self.iden }
+    pub fn nickname(&self) -> &str { &*self.nickname }
+    pub fn created(&self) -> Timestamp { self.created }
+    pub fn modified(&self) -> Timestamp { self.modified }
+    pub fn is_active(&self) -> bool { self.active }
+    pub fn is_pushable(&self) -> bool { self.pushable }
+    pub fn app_version(&self) -> Option<usize> { self.app_version }
+    pub fn push_token(&self) -> Option<&str> { self.push_token.as_ref().map(|s| &**s) }
+    pub fn fingerprint(&self) -> Option<&str> { self.fingerprint.as_ref().map(|s| &**s) }
+    pub fn manufacturer(&self) -> Option<&str> { self.manufacturer.as_ref().map(|s| &**s) }
+    pub fn model(&self) -> Option<&str> { self.model.as_ref().map(|s| &**s) }
+    pub fn kind(&self) -> &str { &*self.kind }
+    pub fn typ(&self) -> &str { &*self.typ }
+    pub fn icon(&self) -> Option<DeviceIcon> { self.icon }
+    pub fn has_sms(&self) -> bool { self.has_sms.unwrap_or(false) }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DeviceIcon {
+    Desktop,
+    Browser,
+    Website,
+    Laptop,
+    Tablet,
+    Phone,
+    Watch,
+    System,
+    Ipad,
+    Iphone,
+}
+
+impl DeviceIcon {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            DeviceIcon::Desktop => "desktop",
+            DeviceIcon::Browser => "browser",
+            DeviceIcon::Website => "website",
+            DeviceIcon::Laptop => "laptop",
+            DeviceIcon::Tablet => "tablet",
+            DeviceIcon::Phone => "phone",
+            DeviceIcon::Watch => "watch",
+            DeviceIcon::System => "system",
+            DeviceIcon::Ipad => "ipad",
+            DeviceIcon::Iphone => "iphone",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceIconParseError;
+
+impl FromStr for DeviceIcon {
+    type Err = DeviceIconParseError;
+    fn from_str(s: &str) -> Result<DeviceIcon, DeviceIconParseError> {
+        Ok(match s {
+            "desktop" => DeviceIcon::Desktop,
+            "browser" => DeviceIcon::Browser,
+            "website" => DeviceIcon::Website,
+            "laptop" => DeviceIcon::Laptop,
+            "tablet" => DeviceIcon::Tablet,
+            "phone" => DeviceIcon::Phone,
+            "watch" => DeviceIcon::Watch,
+            "system" => DeviceIcon::System,
+            "ipad" => DeviceIcon::Ipad,
+            "iphone" => DeviceIcon::Iphone,
+            _ => return Err(DeviceIconParseError)
+        })
+    }
+}
+
+impl Encodable for DeviceIcon {
+    fn encode<S: Encoder>(&self, encoder: &mut S) -> Result<(), S::Error> {
+        encoder.emit_str(self.as_str())
+    }
+}
+
+impl Decodable for DeviceIcon {
+    fn decode<S: Decoder>(decoder: &mut S) -> Result<DeviceIcon, S::Error> {
+        let icon = try!(decoder.read_str());
+        icon.parse().map_err(|_| decoder.error(&*format!("Unknown device icon: {:?}", icon)))
+    }
 }
 
 impl Encodable for Device {
     fn encode<S: Encoder>(&self, encoder: &mut S) -> Result<(), S::Error> {
-        encoder.emit_struct("Device", 13, |e| {
+        encoder.emit_struct("Device", 15, |e| {
             try!(e.emit_struct_field("app_version", 0, |e| self.app_version.encode(e)));
             try!(e.emit_struct_field("created", 1, |e| self.created.encode(e)));
             try!(e.emit_struct_field("modified", 2, |e| self.modified.encode(e)));
@@ -72,6 +439,8 @@ impl Encodable for Device {
             try!(e.emit_struct_field("model", 10, |e| self.model.encode(e)));
             try!(e.emit_struct_field("kind", 11, |e| self.kind.encode(e)));
             try!(e.emit_struct_field("type", 12, |e| self.typ.encode(e)));
+            try!(e.emit_struct_field("icon", 13, |e| self.icon.encode(e)));
+            try!(e.emit_struct_field("has_sms", 14, |e| self.has_sms.encode(e)));
             Ok(())
         })
     }
@@ -79,7 +448,7 @@ impl Encodable for Device {
 
 impl Decodable for Device {
     fn decode<S: Decoder>(decoder: &mut S) -> Result<Device, S::Error> {
-        decoder.read_struct("Device", 13, |d| {
+        decoder.read_struct("Device", 15, |d| {
             Ok(Device {
                 app_version: try!(d.read_struct_field("app_version", 0, |d| Decodable::decode(d))),
                 created: try!(d.read_struct_field("created", 0, |d| Decodable::decode(d))),
@@ -94,6 +463,9 @@ impl Decodable for Device {
                 model: try!(d.read_struct_field("model", 0, |d| Decodable::decode(d))),
                 kind: try!(d.read_struct_field("kind", 0, |d| Decodable::decode(d))),
                 typ: try!(d.read_struct_field("type", 0, |d| Decodable::decode(d))),
+                // icons of device types added later are not an error
+                icon: try!(d.read_struct_field("icon", 0, |d| <Option<String>>::decode(d))).and_then(|i| i.parse().ok()),
+                has_sms: try!(d.read_struct_field("has_sms", 0, |d| Decodable::decode(d))),
             })
         })
     }
@@ -106,31 +478,68 @@ pub struct Contact {
     pub modified: Timestamp,
     pub email: String,
     pub email_normalized: String,
-    pub iden: Iden,
+    pub iden: ChatIden,
     pub name: String,
     pub status: String,
 }
 
+/// Conversation with another user, the newer form of a contact.
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq)]
+pub struct Chat {
+    pub iden: ChatIden,
+    pub active: bool,
+    pub created: Timestamp,
+    pub modified: Timestamp,
+    pub muted: bool,
+    pub with: ChatWith,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq)]
+pub struct ChatWith {
+    pub name: String,
+    pub email: String,
+    pub email_normalized: String,
+    pub image_url: Option<Url>,
+}
+
 #[derive(RustcEncodable, RustcDecodable, Debug, PartialEq)]
 pub struct Grant {
-    pub iden: Iden,
+    pub iden: GrantIden,
     pub active: bool,
     pub created: Timestamp,
     pub modified: Timestamp,
     pub client: Option<Client>,
 }
 
+/// SMS sent from a phone via the texts endpoint.
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq)]
+pub struct Text {
+    pub iden: TextIden,
+    pub active: bool,
+    pub created: Timestamp,
+    pub modified: Timestamp,
+    pub data: TextData,
+}
+
+#[derive(RustcEncodable, RustcDecodable, Debug, PartialEq, Clone)]
+pub struct TextData {
+    pub target_device_iden: DeviceIden,
+    pub addresses: Vec<String>,
+    pub message: String,
+}
+
 #[derive(RustcEncodable, RustcDecodable, Debug, PartialEq)]
 pub struct Client {
-    pub iden: Iden,
+    pub iden: ClientIden,
     pub image_url: Url,
     pub name: String,
     pub website_url: Url,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Push {
-    pub iden: Iden,
+    pub iden: PushIden,
+    pub guid: Option<String>,
     pub active: bool,
     pub dismissed: bool,
     pub created: Timestamp,
@@ -149,18 +558,19 @@ pub struct Push {
     pub sender_email_normalized: Option<String>,
     pub sender_iden: Option<Iden>,
 
-    pub source_device_iden: Option<Iden>,
-    pub target_device_iden: Option<Iden>,
-    pub channel_iden: Option<Iden>,
+    pub source_device_iden: Option<DeviceIden>,
+    pub target_device_iden: Option<DeviceIden>,
+    pub channel_iden: Option<ChannelIden>,
 
     pub data: PushData,
 }
 
 impl Decodable for Push {
     fn decode<S: Decoder>(decoder: &mut S) -> Result<Push, S::Error> {
-        decoder.read_struct("Push", 18, |d| {
+        decoder.read_struct("Push", 19, |d| {
             Ok(Push {
                 iden: try!(d.read_struct_field("iden", 0, |d| Decodable::decode(d))),
+                guid: try!(d.read_struct_field("guid", 0, |d| Decodable::decode(d))),
                 active: try!(d.read_struct_field("active", 0, |d| Decodable::decode(d))),
                 dismissed: try!(d.read_struct_field("dismissed", 0, |d| Decodable::decode(d))),
                 created: try!(d.read_struct_field("created", 0, |d| Decodable::decode(d))),
@@ -191,7 +601,7 @@ impl Decodable for Push {
 
 impl Encodable for Push {
     fn encode<S: Encoder>(&self, encoder: &mut S) -> Result<(), S::Error> {
-        encoder.emit_struct("Push", 18, |e| {
+        encoder.emit_struct("Push", 19, |e| {
             try!(e.emit_struct_field("iden", 0, |e| self.iden.encode(e)));
             try!(e.emit_struct_field("active", 1, |e| self.active.encode(e)));
             try!(e.emit_struct_field("dismissed", 2, |e| self.dismissed.encode(e)));
@@ -209,6 +619,8 @@ impl Encodable for Push {
             try!(e.emit_struct_field("sender_iden", 14, |e| self.sender_iden.encode(e)));
             try!(e.emit_struct_field("target_device_iden", 15, |e| self.target_device_iden.encode(e)));
             try!(e.emit_struct_field("source_device_iden", 16, |e| self.source_device_iden.encode(e)));
+            try!(e.emit_struct_field("guid", 17, |e| self.guid.encode(e)));
+            try!(e.emit_struct_field("channel_iden", 18, |e| self.channel_iden.encode(e)));
 
             try!(self.data.encode(e));
 
@@ -218,26 +630,42 @@ impl Encodable for Push {
 }
 
 impl PbObj for Push {
+    type Iden = PushIden;
     fn root_uri() -> &'static str { "pushes" }
+    fn guid(&self) -> Option<&str> { self.guid.as_ref().map(|s| &**s) }
 }
 
 impl PbObj for Device {
+    type Iden = DeviceIden;
     fn root_uri() -> &'static str { "devices" }
 }
 
 impl PbObj for Contact {
+    type Iden = ChatIden;
     fn root_uri() -> &'static str { "contacts" }
 }
 
+impl PbObj for Chat {
+    type Iden = ChatIden;
+    fn root_uri() -> &'static str { "chats" }
+}
+
 impl PbObj for Grant {
+    type Iden = GrantIden;
     fn root_uri() -> &'static str { "grants" }
 }
 
+impl PbObj for Text {
+    type Iden = TextIden;
+    fn root_uri() -> &'static str { "texts" }
+}
+
 impl PbObj for Client {
+    type Iden = ClientIden;
     fn root_uri() -> &'static str { "clients" }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct ListItem(bool, String);
 
 #[derive(Clone, Debug)]
@@ -312,18 +740,57 @@ impl Decodable for ListItem {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum PushData {
     Empty,
     Note,
     Link(Option<Url>),
-    File(String, String, Url, Option<Url>),  // name, type, url, image
+    File {
+        file_name: String,
+        file_type: String,
+        file_url: Url,
+        image_url: Option<Url>,
+    },
     List(Vec<ListItem>),
     Address(String),
     Dismissal,
     Mirror,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+pub struct FileInfo {
+    pub file_name: String,
+    pub file_type: String,
+    pub file_url: Url,
+    pub image_url: Option<Url>,
+}
+
+impl From<FileInfo> for PushData {
+    fn from(info: FileInfo) -> PushData {
+        PushData::File {
+            file_name: info.file_name,
+            file_type: info.file_type,
+            file_url: info.file_url,
+            image_url: info.image_url,
+        }
+    }
+}
+
+impl PushData {
+    pub fn type_name(&self) -> &'static str {
+        match *self {
+            PushData::Empty => "",
+            PushData::Note => "note",
+            PushData::Link(_) => "link",
+            PushData::File { .. } => "file",
+            PushData::List(_) => "list",
+            PushData::Address(_) => "address",
+            PushData::Dismissal => "dismissal",
+            PushData::Mirror => "mirror",
+        }
+    }
+}
+
 impl Encodable for PushData {
     fn encode<S: Encoder>(&self, encoder: &mut S) -> Result<(), S::Error> {
         match *self {
@@ -335,12 +802,14 @@ impl Encodable for PushData {
                 try!(encoder.emit_struct_field("type", 100, |e| e.emit_str("link")));
                 try!(encoder.emit_struct_field("url", 101, |e| url.encode(e)));
             },
-            PushData::File(ref name, ref mime, ref url, ref img) => {
+            PushData::File { ref file_name, ref file_type, ref file_url, ref image_url } => {
                 try!(encoder.emit_struct_field("type", 100, |e| e.emit_str("file")));
-                try!(encoder.emit_struct_field("file_name", 101, |e| name.encode(e)));
-                try!(encoder.emit_struct_field("file_type", 102, |e| mime.encode(e)));
-                try!(encoder.emit_struct_field("file_url", 103, |e| url.encode(e)));
-                try!(encoder.emit_struct_field("image_url", 104, |e| img.encode(e)));
+                try!(encoder.emit_struct_field("file_name", 101, |e| file_name.encode(e)));
+                try!(encoder.emit_struct_field("file_type", 102, |e| file_type.encode(e)));
+                try!(encoder.emit_struct_field("file_url", 103, |e| file_url.encode(e)));
+                if let Some(ref image_url) = *image_url {
+                    try!(encoder.emit_struct_field("image_url", 104, |e| image_url.encode(e)));
+                }
             },
             PushData::List(ref items) => {
                 try!(encoder.emit_struct_field("type", 100, |e| e.emit_str("list")));
@@ -363,12 +832,12 @@ impl Decodable for PushData {
             Some(ref t) => match &**t {
                 "note" => PushData::Note,
                 "link" => PushData::Link(try!(decoder.read_struct_field("url", 0, |d| Decodable::decode(d)))),
-                "file" => PushData::File(
-                    try!(decoder.read_struct_field("file_name", 0, |d| Decodable::decode(d))),
-                    try!(decoder.read_struct_field("file_type", 0, |d| Decodable::decode(d))),
-                    try!(decoder.read_struct_field("file_url", 0, |d| Decodable::decode(d))),
-                    try!(decoder.read_struct_field("image_url", 0, |d| Decodable::decode(d))),
-                    ),
+                "file" => PushData::File {
+                    file_name: try!(decoder.read_struct_field("file_name", 0, |d| Decodable::decode(d))),
+                    file_type: try!(decoder.read_struct_field("file_type", 0, |d| Decodable::decode(d))),
+                    file_url: try!(decoder.read_struct_field("file_url", 0, |d| Decodable::decode(d))),
+                    image_url: try!(decoder.read_struct_field("image_url", 0, |d| Decodable::decode(d))),
+                },
                 "list" => PushData::List(try!(decoder.read_struct_field("items", 0, |d| Decodable::decode(d)))),
                 "address" => PushData::Address(try!(decoder.read_struct_field("address", 0, |d| Decodable::decode(d)))),
                 "mirror" => PushData::Mirror,
@@ -380,9 +849,9 @@ impl Decodable for PushData {
     }
 }
 
-#[derive(Debug, PartialEq, RustcDecodable)]
+#[derive(Debug, PartialEq, RustcDecodable, RustcEncodable)]
 pub struct Channel {
-    pub iden: Iden,
+    pub iden: ChannelIden,
     pub active: bool,
     pub created: Timestamp,
     pub modified: Timestamp,
@@ -395,12 +864,13 @@ pub struct Channel {
 }
 
 impl PbObj for Channel {
+    type Iden = ChannelIden;
     fn root_uri() -> &'static str { "channels" }
 }
 
-#[derive(Debug, PartialEq, RustcDecodable)]
+#[derive(Debug, PartialEq, RustcDecodable, RustcEncodable)]
 pub struct ChannelInfo {
-    pub iden: Iden,
+    pub iden: ChannelIden,
     pub tag: String,
     pub name: String,
     pub description: String,
@@ -408,9 +878,9 @@ pub struct ChannelInfo {
     pub website_url: Option<Url>,
 }
 
-#[derive(Debug, PartialEq, RustcDecodable)]
+#[derive(Debug, PartialEq, RustcDecodable, RustcEncodable)]
 pub struct Subscription {
-    pub iden: Iden,
+    pub iden: SubscriptionIden,
     pub active: bool,
     pub created: Timestamp,
     pub modified: Timestamp,
@@ -418,9 +888,17 @@ pub struct Subscription {
 }
 
 impl PbObj for Subscription {
+    type Iden = SubscriptionIden;
     fn root_uri() -> &'static str { "subscriptions" }
 }
 
+resource_impl! { Push, Device, Contact, Chat, Channel, Subscription, Grant }
+
+capability_impl! { Listable: Push, Device, Contact, Chat, Channel, Subscription, Grant }
+capability_impl! { Creatable: Push, Device, Contact, Chat, Channel, Subscription }
+capability_impl! { Updatable: Push, Device, Contact, Chat, Subscription }
+capability_impl! { Deletable: Push, Device, Contact, Chat, Subscription, Grant }
+
 #[derive(Debug, PartialEq, RustcDecodable)]
 pub struct Envelope {
     //aliases: Vec<Alias>,
@@ -430,19 +908,33 @@ pub struct Envelope {
     pub grants: Option<Vec<Grant>>,
     pub pushes: Option<Vec<Push>>,
     pub contacts: Option<Vec<Contact>>,
+    pub chats: Option<Vec<Chat>>,
     pub subscriptions: Option<Vec<Subscription>>,
     pub cursor: Option<Cursor>,
     pub error: Option<Error>,
 }
 
 pub trait FromEnvelope : Sized {
+    /// Name of envelope field with objects of this kind.
+    fn collection() -> &'static str;
+
     #[allow(unused_variables)]
     fn from_env(env: Envelope) -> Option<(Vec<Self>, Option<Cursor>)> { None }
 }
 
+fn merge<T>(into: &mut Option<Vec<T>>, from: Option<Vec<T>>) {
+    match (into.as_mut(), from) {
+        (Some(objs), Some(more)) => objs.extend(more),
+        (None, more) => *into = more,
+        (_, None) => ()
+    }
+}
+
 macro_rules! from_envelope_impl {
     ($(($t:ty, $f:ident)),+) => {
         $(impl FromEnvelope for $t {
+            #[inline] fn collection() -> &'static str { stringify!($f) }
+
             #[inline] fn from_env(env: Envelope) -> Option<(Vec<$t>, Option<Cursor>)> {
                 match env.$f {
                     Some(v) => Some((v, env.cursor)),
@@ -460,6 +952,7 @@ from_envelope_impl! {
     (Grant, grants),
     (Push, pushes),
     (Contact, contacts),
+    (Chat, chats),
     (Subscription, subscriptions)
 }
 
@@ -472,6 +965,7 @@ impl Envelope {
             grants: None,
             pushes: None,
             contacts: None,
+            chats: None,
             subscriptions: None,
             cursor: None,
             error: None,
@@ -484,10 +978,28 @@ impl Envelope {
         self.error.is_some()
     }
 
-    pub fn get<T: FromEnvelope>(self) -> Result<(Vec<T>, Option<Cursor>), Error> {
-        match self.error {
-            Some(_) => Err(self.error.unwrap()),
-            None => Ok(FromEnvelope::from_env(self).unwrap())
+    /// Takes objects of one kind. Fails if response has an error or no such collection.
+    pub fn get<T: FromEnvelope>(mut self) -> Result<(Vec<T>, Option<Cursor>), Error> {
+        if let Some(error) = self.error.take() {
+            return Err(error);
+        }
+        FromEnvelope::from_env(self).ok_or_else(|| Error::new("invalid_response",
+            &*format!("response has no {} collection", T::collection())))
+    }
+
+    /// Appends objects from the next page, taking its cursor.
+    pub fn extend(&mut self, page: Envelope) {
+        merge(&mut self.channels, page.channels);
+        merge(&mut self.clients, page.clients);
+        merge(&mut self.devices, page.devices);
+        merge(&mut self.grants, page.grants);
+        merge(&mut self.pushes, page.pushes);
+        merge(&mut self.contacts, page.contacts);
+        merge(&mut self.chats, page.chats);
+        merge(&mut self.subscriptions, page.subscriptions);
+        self.cursor = page.cursor;
+        if self.error.is_none() {
+            self.error = page.error;
         }
     }
 }
@@ -499,6 +1011,18 @@ pub struct Error {
     cat: String,
 }
 
+impl Error {
+    pub fn new(typ: &str, message: &str) -> Error {
+        Error {
+            message: message.to_string(),
+            typ: typ.to_string(),
+            cat: String::new(),
+        }
+    }
+
+    pub fn typ(&self) -> &str { &*self.typ }
+}
+
 impl error::Error for Error {
     fn description(&self) -> &str { "PushBuller error" }
 }
@@ -522,9 +1046,39 @@ impl Decodable for Error {
 #[cfg(test)]
 mod tests {
     use rustc_serialize::json;
-    use super::{Error, Envelope, Account, PushData, ListItem, Push};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use super::{Error, Envelope, Account, PushData, ListItem, Push, PushIden, Device, DeviceIcon, Timestamp, Resource, civil_from_days, days_from_civil};
     use url::Url;
 
+    #[test]
+    fn test_timestamp() {
+        for s in &["1411595195.1267679", "1399253701.9744401", "1409046718.1501", "1.5"] {
+            let ts = s.parse::<Timestamp>().unwrap();
+            assert_eq!(ts.to_string().parse::<Timestamp>(), Ok(ts));
+            assert_eq!(json::decode::<Timestamp>(&*json::encode(&ts).unwrap()).unwrap(), ts);
+        }
+        assert!("NaN".parse::<Timestamp>().is_err());
+        assert!("yesterday".parse::<Timestamp>().is_err());
+
+        let a = Timestamp::from(1411595195.1267679);
+        let b = Timestamp::from(1411595195.1267681);
+        assert!(a < b && b > a && a != b);
+        assert_eq!(vec![b, a].into_iter().max(), Some(b));
+        assert_eq!(a + Duration::from_secs(60) - Duration::from_secs(60), a);
+        assert_eq!(a.format_utc(), "2014-09-24 21:46:35 UTC");
+        assert_eq!(Timestamp::from(951782400.0).format_utc(), "2000-02-29 00:00:00 UTC");
+        assert_eq!(days_from_civil(2000, 2, 29), 11016);
+        for &days in &[-719468, -1, 0, 11016, 20000, 47541] {
+            let (y, m, d) = civil_from_days(days);
+            assert_eq!(days_from_civil(y, m, d), days);
+        }
+        assert_eq!(Timestamp::from_utc(2014, 9, 24, 21, 46).as_f64(), 1411595160.0);
+
+        let time = UNIX_EPOCH + Duration::new(1411595195, 500000000);
+        assert_eq!(Timestamp::from(time), Timestamp::from(1411595195.5));
+        assert_eq!(SystemTime::from(Timestamp::from(1411595195.5)), time);
+    }
+
     #[test]
     fn test_note_push_decode() {
         let example = "{
@@ -546,11 +1100,12 @@ mod tests {
         let push: Result<Push, _> = json::decode(example);
         match push {
             Ok(ref p) => assert_eq!(*p, Push {
-                iden: "ubdpj29aOK0sKG".to_string(),
+                iden: PushIden::from("ubdpj29aOK0sKG"),
+                guid: None,
                 active: true,
                 dismissed: false,
-                created: 1399253701.9744401,
-                modified: 1399253701.9746201,
+                created: Timestamp::from(1399253701.9744401),
+                modified: Timestamp::from(1399253701.9746201),
 
                 title: Some("Note Title".to_string()),
                 body: Some("Note Body".to_string()),
@@ -596,11 +1151,12 @@ mod tests {
         let push: Result<Push, _> = json::decode(example);
         match push {
             Ok(ref p) => assert_eq!(*p, Push {
-                iden: "ubdpjAkaGXvUl2".to_string(),
+                iden: PushIden::from("ubdpjAkaGXvUl2"),
+                guid: None,
                 active: true,
                 dismissed: false,
-                created: 1411595195.1267679,
-                modified: 1411699878.2501802,
+                created: Timestamp::from(1411595195.1267679),
+                modified: Timestamp::from(1411699878.2501802),
 
                 title: Some("List Title".to_string()),
                 body: None,
@@ -628,6 +1184,61 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_resource() {
+        fn latest<R: Resource>(objs: &[R]) -> Option<String> {
+            objs.iter().filter(|o| o.active()).max_by_key(|o| o.modified()).map(|o| o.uri())
+        }
+
+        let devices: Vec<Device> = json::decode("[
+            {\"iden\": \"udx1\", \"nickname\": \"Phone\", \"created\": 10.5, \"modified\": 20.5, \"active\": true, \"pushable\": true, \"kind\": \"android\", \"type\": \"android\"},
+            {\"iden\": \"udx2\", \"nickname\": \"Laptop\", \"created\": 11.5, \"modified\": 30.5, \"active\": true, \"pushable\": true, \"kind\": \"chrome\", \"type\": \"chrome\"},
+            {\"iden\": \"udx3\", \"nickname\": \"Old\", \"created\": 12.5, \"modified\": 40.5, \"active\": false, \"pushable\": false, \"kind\": \"ios\", \"type\": \"ios\"}
+        ]").unwrap();
+
+        assert_eq!(latest(&devices), Some("devices/udx2".to_string()));
+        assert_eq!(Resource::created(&devices[0]), Timestamp::from(10.5));
+        assert_eq!(latest::<Push>(&[]), None);
+    }
+
+    #[test]
+    fn test_device_decode() {
+        let example = "{
+            \"active\": true,
+            \"iden\": \"ujpah72o0sjAoRtnM0jc\",
+            \"created\": 1412047948.579029,
+            \"modified\": 1412047948.579031,
+            \"type\": \"stream\",
+            \"kind\": \"stream\",
+            \"nickname\": \"Stream Device\",
+            \"manufacturer\": \"Apple\",
+            \"model\": \"iPhone 5s (GSM)\",
+            \"app_version\": 8623,
+            \"icon\": \"phone\",
+            \"has_sms\": true,
+            \"pushable\": true
+        }";
+        let device: Result<Device, _> = json::decode(example);
+        match device {
+            Ok(ref d) => {
+                assert_eq!(d.iden(), "ujpah72o0sjAoRtnM0jc");
+                assert_eq!(d.nickname(), "Stream Device");
+                assert_eq!(d.manufacturer(), Some("Apple"));
+                assert_eq!(d.model(), Some("iPhone 5s (GSM)"));
+                assert_eq!(d.app_version(), Some(8623));
+                assert_eq!(d.push_token(), None);
+                assert_eq!(d.icon(), Some(DeviceIcon::Phone));
+                assert_eq!(d.has_sms(), true);
+                assert_eq!(d.is_active(), true);
+                assert_eq!(d.is_pushable(), true);
+            },
+            Err(e) => panic!("Error: {:?}", e)
+        }
+
+        let device: Device = json::decode(&*example.replace("\"phone\"", "\"fridge\"")).unwrap();
+        assert_eq!(device.icon(), None);
+    }
+
     #[test]
     fn test_account_decode() {
         let example = "{
@@ -651,8 +1262,8 @@ mod tests {
         let account: Result<Account, _> = json::decode(example);
         let expected = Account {
             iden: "udx234acsdc".to_string(),
-            created: 1398342586.00574,
-            modified: 1409046718.1501,
+            created: Timestamp::from(1398342586.00574),
+            modified: Timestamp::from(1409046718.1501),
             email: "me@kstep.me".to_string(),
             email_normalized: "me@kstep.me".to_string(),
             name: "Konstantin Stepanov".to_string(),
@@ -663,8 +1274,8 @@ mod tests {
         match account {
             Ok(ref a) => {
                 assert_eq!(a.iden, expected.iden);
-                assert!((a.created - expected.created).abs() < 0.0001);
-                assert!((a.modified - expected.modified).abs() < 0.0001);
+                assert_eq!(a.created, expected.created);
+                assert_eq!(a.modified, expected.modified);
                 assert_eq!(a.email, expected.email);
                 assert_eq!(a.email_normalized, expected.email_normalized);
                 assert_eq!(a.name, expected.name);
@@ -696,6 +1307,7 @@ mod tests {
                     devices: None,
                     pushes: None,
                     contacts: None,
+                    chats: None,
                     channels: None,
                     subscriptions: None,
                     clients: None,
@@ -714,6 +1326,15 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_envelope_get_missing() {
+        let env: Envelope = json::decode("{\"devices\": []}").unwrap();
+        match env.get::<Push>() {
+            Err(e) => assert_eq!(e.to_string(), "response has no pushes collection"),
+            Ok(_) => panic!("Unexpected success")
+        }
+    }
+
     #[test]
     fn test_decode_ok_result() {
         let envelope = "{
@@ -730,6 +1351,7 @@ mod tests {
                     grants: Some(vec![]),
                     pushes: Some(vec![]),
                     contacts: Some(vec![]),
+                    chats: None,
                     channels: None,
                     clients: None,
                     subscriptions: None,
@@ -0,0 +1,718 @@
+//! Scheduled and recurring pushes, kept in a local file and sent with `PbAPI::send`.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use uuid::Uuid;
+
+use api::{PbAPI, PbError, PbResult};
+use jsonfile::{self, Lock};
+use messages::{PushMsg, TargetIden};
+use objects::{civil_from_days, Error, Push, PushData, Timestamp};
+
+static DEFAULT_GRACE_SECS: u64 = 300;
+static MAX_IDLE_SECS: u64 = 60;
+/// Delay before jobs which failed to send are tried again.
+static RETRY_SECS: u64 = 30;
+// Allowance for clock difference with server when looking for runs sent before
+static CLOCK_SKEW_SECS: u64 = 60;
+/// Limit of missed runs sent after downtime with `Missed::RunAll`, so that
+/// `* * * * *` after a month off doesn't send forty thousand pushes.
+static MAX_MISSED: usize = 100;
+/// How far to look for the next run of a cron schedule,
+/// long enough for any February 29.
+static MAX_CRON_DAYS: i64 = 366 * 9;
+
+static MONTHS: &'static [&'static str] = &["jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec"];
+static WEEKDAYS: &'static [&'static str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+#[derive(Debug, PartialEq)]
+pub struct CronParseError(String);
+
+impl error::Error for CronParseError {
+    fn description(&self) -> &str { &*self.0 }
+}
+
+impl fmt::Display for CronParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(&*self.0)
+    }
+}
+
+/// Cron schedule with five fields: minute, hour, day of month, month and
+/// day of week, in UTC. Fields are `*`, numbers, ranges `a-b` and steps `*/n`
+/// or `a-b/n`, separated by commas; months and week days can be names (`jan`, `mon`).
+/// As in cron, when both day fields are restricted, either of them matching is enough.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cron {
+    expr: String,
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    any_day: bool,
+    any_weekday: bool,
+}
+
+fn parse_value(value: &str, names: &[&str], offset: u32) -> Option<u32> {
+    value.parse::<u32>().ok()
+        .or_else(|| names.iter().position(|n| *n == &*value.to_lowercase()).map(|i| i as u32 + offset))
+}
+
+/// Bit set of values allowed by a cron field.
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, CronParseError> {
+    let invalid = || CronParseError(format!("invalid cron field {:?}", field));
+    let mut bits = 0u64;
+    for part in field.split(',') {
+        let (range, step) = match part.find('/') {
+            Some(idx) => (&part[..idx], try!(part[idx + 1..].parse::<u32>().map_err(|_| invalid()))),
+            None => (part, 1)
+        };
+        let (from, to) = if range == "*" {
+            (min, max)
+        } else {
+            match range.find('-') {
+                Some(idx) => (try!(parse_value(&range[..idx], names, min).ok_or_else(&invalid)),
+                              try!(parse_value(&range[idx + 1..], names, min).ok_or_else(&invalid))),
+                None => {
+                    let value = try!(parse_value(range, names, min).ok_or_else(&invalid));
+                    (value, if step > 1 { max } else { value })
+                }
+            }
+        };
+        if step == 0 || from < min || to > max || from > to {
+            return Err(invalid());
+        }
+        for value in (from..to + 1).filter(|v| (v - from) % step == 0) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for Cron {
+    type Err = CronParseError;
+    fn from_str(expr: &str) -> Result<Cron, CronParseError> {
+        let fields: Vec<&str> = expr.split_whitespace().collect();
+        if fields.len() != 5 {
+            return Err(CronParseError(format!("cron schedule needs 5 fields, got {}", fields.len())));
+        }
+
+        let weekdays = try!(parse_field(fields[4], 0, 7, WEEKDAYS));
+        Ok(Cron {
+            expr: fields.join(" "),
+            minutes: try!(parse_field(fields[0], 0, 59, &[])),
+            hours: try!(parse_field(fields[1], 0, 23, &[])),
+            days: try!(parse_field(fields[2], 1, 31, &[])),
+            months: try!(parse_field(fields[3], 1, 12, MONTHS)),
+            // both 0 and 7 are Sunday
+            weekdays: (weekdays | weekdays >> 7) & 0x7f,
+            any_day: fields[2].starts_with('*'),
+            any_weekday: fields[4].starts_with('*'),
+        })
+    }
+}
+
+impl fmt::Display for Cron {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(&*self.expr)
+    }
+}
+
+impl Cron {
+    fn matches_day(&self, days: i64) -> bool {
+        let (_, month, day) = civil_from_days(days);
+        // 1970-01-01 was Thursday
+        let weekday = (days + 4).rem_euclid(7);
+        let day_ok = self.days & 1 << day != 0;
+        let weekday_ok = self.weekdays & 1 << weekday != 0;
+
+        self.months & 1 << month != 0 && match (self.any_day, self.any_weekday) {
+            (true, true) => true,
+            (false, true) => day_ok,
+            (true, false) => weekday_ok,
+            (false, false) => day_ok || weekday_ok,
+        }
+    }
+
+    /// First run strictly after `time`.
+    pub fn next_after(&self, time: Timestamp) -> Option<Timestamp> {
+        let start = (time.as_f64() / 60.0).floor() as i64 * 60 + 60;
+        let first_day = start.div_euclid(86400);
+        for days in first_day..first_day + MAX_CRON_DAYS {
+            if !self.matches_day(days) {
+                continue;
+            }
+            for hour in (0..24).filter(|h| self.hours & 1 << h != 0) {
+                for minute in (0..60).filter(|m| self.minutes & 1 << m != 0) {
+                    let secs = days * 86400 + hour * 3600 + minute * 60;
+                    if secs >= start {
+                        return Some(Timestamp::from(secs as f64));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// When a job runs.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Recurrence {
+    Once(Timestamp),
+    Cron(Cron),
+}
+
+impl Recurrence {
+    pub fn next_after(&self, time: Timestamp) -> Option<Timestamp> {
+        match *self {
+            Recurrence::Once(at) => if at > time { Some(at) } else { None },
+            Recurrence::Cron(ref cron) => cron.next_after(time),
+        }
+    }
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            Recurrence::Once(at) => write!(fmt, "once at {}", at.format_utc()),
+            Recurrence::Cron(ref cron) => write!(fmt, "cron {}", cron),
+        }
+    }
+}
+
+/// What to do with runs missed while the scheduler wasn't running
+/// (more than grace period late).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Missed {
+    /// Forget them.
+    Skip,
+    /// Send the push once for all of them.
+    RunOnce,
+    /// Send the push for every missed run.
+    RunAll,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct MissedParseError;
+
+impl error::Error for MissedParseError {
+    fn description(&self) -> &str { "invalid missed runs policy, expected skip, once or all" }
+}
+
+impl fmt::Display for MissedParseError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(error::Error::description(self))
+    }
+}
+
+impl FromStr for Missed {
+    type Err = MissedParseError;
+    fn from_str(s: &str) -> Result<Missed, MissedParseError> {
+        match s {
+            "skip" => Ok(Missed::Skip),
+            "once" => Ok(Missed::RunOnce),
+            "all" => Ok(Missed::RunAll),
+            _ => Err(MissedParseError)
+        }
+    }
+}
+
+impl fmt::Display for Missed {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str(match *self {
+            Missed::Skip => "skip",
+            Missed::RunOnce => "once",
+            Missed::RunAll => "all",
+        })
+    }
+}
+
+/// A push waiting to be sent.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Job {
+    pub id: String,
+    pub target: TargetIden,
+    pub title: Option<String>,
+    pub body: Option<String>,
+    pub data: PushData,
+    pub recurrence: Recurrence,
+    pub missed: Missed,
+    /// Next time to send the push.
+    pub next: Timestamp,
+}
+
+impl Job {
+    pub fn msg(&self) -> PushMsg<'static> {
+        let msg = PushMsg::new(self.target.clone()).data(self.data.clone());
+        let msg = match self.title { Some(ref title) => msg.title(title.clone()), None => msg };
+        match self.body { Some(ref body) => msg.body(body.clone()), None => msg }
+    }
+
+    /// Guid of the push sent for the run at `at`, the same for every attempt to send it.
+    pub fn guid(&self, at: Timestamp) -> String {
+        format!("{}-{}", self.id, at)
+    }
+
+    /// Times of runs to send at `now` and the next run after it.
+    fn runs(&self, now: Timestamp, grace: Duration) -> (Vec<Timestamp>, Option<Timestamp>) {
+        let cutoff = now - grace;
+        let late = self.next < cutoff;
+
+        let mut runs = match self.missed {
+            Missed::RunAll if late => {
+                let mut runs = vec![self.next];
+                while runs.len() < MAX_MISSED {
+                    match self.recurrence.next_after(runs[runs.len() - 1]) {
+                        Some(t) if t < cutoff => runs.push(t),
+                        _ => break
+                    }
+                }
+                runs
+            },
+            _ => Vec::new()
+        };
+
+        let mut next = if late { self.recurrence.next_after(cutoff - Duration::from_secs(1)) } else { Some(self.next) };
+        let mut on_time = Vec::new();
+        while let Some(t) = next {
+            if t > now || on_time.len() == MAX_MISSED {
+                break;
+            }
+            if t >= cutoff {
+                on_time.push(t);
+            }
+            next = self.recurrence.next_after(t);
+        }
+
+        if self.missed == Missed::RunOnce && late && on_time.is_empty() {
+            runs.extend(self.last_run_before(cutoff));
+        }
+        runs.extend(on_time);
+        (runs, self.recurrence.next_after(now))
+    }
+
+    /// The last run earlier than `time`, `next` or later. Looks back further and further
+    /// from `time`, so that it doesn't go through all the runs missed since `next`.
+    fn last_run_before(&self, time: Timestamp) -> Option<Timestamp> {
+        let mut lookback = Duration::from_secs(60);
+        loop {
+            let from = time - lookback;
+            let first = if from <= self.next {
+                Some(self.next)
+            } else {
+                self.recurrence.next_after(from)
+            };
+            if let Some(mut last) = first.and_then(|t| if t < time { Some(t) } else { None }) {
+                while let Some(t) = self.recurrence.next_after(last) {
+                    if t >= time {
+                        break;
+                    }
+                    last = t;
+                }
+                return Some(last);
+            }
+            if from <= self.next {
+                return None;
+            }
+            lookback = lookback * 2;
+        }
+    }
+}
+
+impl Encodable for Job {
+    fn encode<S: Encoder>(&self, encoder: &mut S) -> Result<(), S::Error> {
+        encoder.emit_struct("Job", 9, |e| {
+            try!(e.emit_struct_field("id", 0, |e| self.id.encode(e)));
+            try!(e.emit_struct_field("target", 1, |e| self.target.key().encode(e)));
+            try!(e.emit_struct_field("title", 2, |e| self.title.encode(e)));
+            try!(e.emit_struct_field("body", 3, |e| self.body.encode(e)));
+            try!(match self.recurrence {
+                Recurrence::Once(ref at) => e.emit_struct_field("at", 4, |e| at.encode(e)),
+                Recurrence::Cron(ref cron) => e.emit_struct_field("cron", 4, |e| cron.expr.encode(e)),
+            });
+            try!(e.emit_struct_field("missed", 5, |e| self.missed.to_string().encode(e)));
+            try!(e.emit_struct_field("next", 6, |e| self.next.encode(e)));
+            try!(self.data.encode(e));
+            Ok(())
+        })
+    }
+}
+
+impl Decodable for Job {
+    fn decode<S: Decoder>(decoder: &mut S) -> Result<Job, S::Error> {
+        decoder.read_struct("Job", 9, |d| {
+            let at: Option<Timestamp> = try!(d.read_struct_field("at", 0, |d| Decodable::decode(d)));
+            let cron: Option<String> = try!(d.read_struct_field("cron", 0, |d| Decodable::decode(d)));
+            let recurrence = match (at, cron) {
+                (Some(at), _) => Recurrence::Once(at),
+                (None, Some(cron)) => Recurrence::Cron(try!(cron.parse().map_err(|e: CronParseError| d.error(&*e.0)))),
+                (None, None) => return Err(d.error("job has neither at nor cron"))
+            };
+            let missed: String = try!(d.read_struct_field("missed", 0, |d| Decodable::decode(d)));
+            let target: String = try!(d.read_struct_field("target", 0, |d| Decodable::decode(d)));
+            let target = try!(TargetIden::from_key(&*target).ok_or_else(|| d.error(&*format!("invalid target {:?}", target))));
+
+            Ok(Job {
+                id: try!(d.read_struct_field("id", 0, |d| Decodable::decode(d))),
+                target: target,
+                title: try!(d.read_struct_field("title", 0, |d| Decodable::decode(d))),
+                body: try!(d.read_struct_field("body", 0, |d| Decodable::decode(d))),
+                data: try!(Decodable::decode(d)),
+                recurrence: recurrence,
+                missed: try!(missed.parse().map_err(|_| d.error("invalid missed runs policy"))),
+                next: try!(d.read_struct_field("next", 0, |d| Decodable::decode(d))),
+            })
+        })
+    }
+}
+
+/// Outcome of `Scheduler::tick`.
+#[derive(Debug, Default)]
+pub struct Tick {
+    pub sent: Vec<Push>,
+    /// Jobs which couldn't be sent, by id. They stay due and are tried again later.
+    pub failed: Vec<(String, PbError)>,
+}
+
+/// Pushes to send later, once or repeatedly. Jobs are kept in memory,
+/// or in a JSON file so that they survive restarts. Several processes
+/// can use the same file: changes are merged with its current contents.
+///
+/// Runs are sent by `tick`, or by `run` which keeps calling it. A run
+/// more than grace period late (5 minutes by default) is handled
+/// according to its job's `Missed` policy. Every run is sent with its own
+/// guid (see `Job::guid`), and isn't sent again if a push with it exists,
+/// e.g. after a crash before the file was updated.
+#[derive(Debug)]
+pub struct Scheduler {
+    path: Option<PathBuf>,
+    grace: Duration,
+    jobs: BTreeMap<String, Job>,
+}
+
+impl Scheduler {
+    pub fn in_memory() -> Scheduler {
+        Scheduler {
+            path: None,
+            grace: Duration::from_secs(DEFAULT_GRACE_SECS),
+            jobs: BTreeMap::new(),
+        }
+    }
+
+    /// Keeps jobs in `path`, loading them if the file exists.
+    pub fn open<P: AsRef<Path>>(path: P) -> PbResult<Scheduler> {
+        let mut scheduler = Scheduler::in_memory();
+        let jobs: Vec<Job> = try!(jsonfile::load(path.as_ref())).unwrap_or_default();
+        scheduler.jobs = jobs.into_iter().map(|j| (j.id.clone(), j)).collect();
+        scheduler.path = Some(path.as_ref().to_path_buf());
+        Ok(scheduler)
+    }
+
+    pub fn grace(mut self, grace: Duration) -> Scheduler {
+        self.grace = grace;
+        self
+    }
+
+    /// Schedules `msg`, returns id of the new job.
+    pub fn add(&mut self, msg: &PushMsg, recurrence: Recurrence, missed: Missed) -> PbResult<String> {
+        let next = match recurrence.next_after(Timestamp::now()) {
+            Some(next) => next,
+            None => return Err(From::from(Error::new("invalid_schedule", "schedule has no runs in the future")))
+        };
+
+        let id = Uuid::new_v4().simple().to_string()[..8].to_string();
+        let job = Job {
+            id: id.clone(),
+            target: msg.target.clone(),
+            title: msg.title.as_ref().map(|t| t.to_string()),
+            body: msg.body.as_ref().map(|b| b.to_string()),
+            data: msg.data.clone(),
+            recurrence: recurrence,
+            missed: missed,
+            next: next,
+        };
+        try!(self.update(|jobs| jobs.insert(id.clone(), job)));
+        Ok(id)
+    }
+
+    /// Removes a job, returns it if it existed.
+    pub fn remove(&mut self, id: &str) -> PbResult<Option<Job>> {
+        self.update(|jobs| jobs.remove(id))
+    }
+
+    /// Jobs in order of their next runs.
+    pub fn jobs(&self) -> Vec<&Job> {
+        let mut jobs: Vec<&Job> = self.jobs.values().collect();
+        jobs.sort_by_key(|j| j.next);
+        jobs
+    }
+
+    /// Sends pushes of due jobs. A job which fails doesn't stop the others.
+    pub fn tick(&mut self, api: &mut PbAPI) -> PbResult<Tick> {
+        self.tick_at(api, Timestamp::now())
+    }
+
+    fn tick_at(&mut self, api: &mut PbAPI, now: Timestamp) -> PbResult<Tick> {
+        try!(self.reload());
+        let due: Vec<Job> = self.jobs.values().filter(|j| j.next <= now).cloned().collect();
+        let mut tick = Tick::default();
+        let mut done = Vec::new();
+        for job in due {
+            let (runs, next) = job.runs(now, self.grace);
+            match send_runs(api, &job, &runs) {
+                Ok(sent) => {
+                    tick.sent.extend(sent);
+                    done.push((job, next));
+                },
+                Err(e) => tick.failed.push((job.id, e))
+            }
+        }
+
+        // jobs changed or removed by others in the meantime are left as they are
+        try!(self.update(|jobs| for (job, next) in done {
+            if jobs.get(&job.id).map_or(false, |j| *j == job) {
+                match next {
+                    Some(next) => jobs.get_mut(&job.id).unwrap().next = next,
+                    None => { jobs.remove(&job.id); }
+                }
+            }
+        }));
+        Ok(tick)
+    }
+
+    /// Keeps sending due pushes, blocking current thread.
+    /// `report` is called with the outcome of every tick.
+    pub fn run<F: FnMut(&Tick)>(&mut self, api: &mut PbAPI, mut report: F) -> PbResult<()> {
+        loop {
+            let tick = try!(self.tick(api));
+            report(&tick);
+
+            let now = Timestamp::now();
+            let retry = now + Duration::from_secs(RETRY_SECS);
+            let idle = Duration::from_secs(MAX_IDLE_SECS);
+            let wake = self.jobs.values()
+                .map(|j| if tick.failed.iter().any(|&(ref id, _)| *id == j.id) { retry } else { j.next })
+                .min();
+            let wait = match wake {
+                Some(next) if next - idle < now => Duration::from_millis(((next.as_f64() - now.as_f64()).max(0.0) * 1000.0) as u64),
+                _ => idle
+            };
+            thread::sleep(wait);
+        }
+    }
+
+    /// Replaces jobs in memory with the ones in the file.
+    fn reload(&mut self) -> PbResult<()> {
+        if let Some(ref path) = self.path {
+            let jobs: Vec<Job> = try!(jsonfile::load(path)).unwrap_or_default();
+            self.jobs = jobs.into_iter().map(|j| (j.id.clone(), j)).collect();
+        }
+        Ok(())
+    }
+
+    /// Applies `f` to the current jobs in the file and saves them,
+    /// holding the file locked in between.
+    fn update<T, F: FnOnce(&mut BTreeMap<String, Job>) -> T>(&mut self, f: F) -> PbResult<T> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(f(&mut self.jobs))
+        };
+        let _lock = try!(Lock::acquire(&path));
+        try!(self.reload());
+        let result = f(&mut self.jobs);
+        try!(jsonfile::save(&path, &self.jobs()));
+        Ok(result)
+    }
+}
+
+/// Sends pushes for `runs` of `job`, except ones sent before.
+fn send_runs(api: &mut PbAPI, job: &Job, runs: &[Timestamp]) -> PbResult<Vec<Push>> {
+    let mut sent = Vec::new();
+    for &at in runs {
+        let guid = job.guid(at);
+        if try!(api.find_by_guid::<Push>(&*guid, at - Duration::from_secs(CLOCK_SKEW_SECS))).is_none() {
+            sent.push(try!(api.send(&job.msg().guid(guid))));
+        }
+    }
+    Ok(sent)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::Write;
+    use std::time::Duration;
+    use hyper::status::StatusCode;
+    use rustc_serialize::json;
+    use uuid::Uuid;
+    use testing::MockServer;
+    use objects::Timestamp;
+    use messages::{PushMsg, TargetIden};
+    use super::{Cron, Missed, Recurrence, Scheduler};
+
+    fn utc(year: i64, month: u32, day: u32, hour: u32, minute: u32) -> Timestamp {
+        Timestamp::from_utc(year, month, day, hour, minute)
+    }
+
+    fn cron(expr: &str) -> Cron {
+        expr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cron_next() {
+        // 2024-03-15 was Friday
+        let now = utc(2024, 3, 15, 9, 30);
+        assert_eq!(cron("45 9 * * mon-fri").next_after(now), Some(utc(2024, 3, 15, 9, 45)));
+        assert_eq!(cron("0 9 * * MON-FRI").next_after(now), Some(utc(2024, 3, 18, 9, 0)));
+        assert_eq!(cron("*/20 * * * *").next_after(now), Some(utc(2024, 3, 15, 9, 40)));
+        assert_eq!(cron("30 9 * * *").next_after(now), Some(utc(2024, 3, 16, 9, 30)));
+        assert_eq!(cron("0 0 1 jan,jul *").next_after(now), Some(utc(2024, 7, 1, 0, 0)));
+        assert_eq!(cron("0 12 29 2 *").next_after(now), Some(utc(2028, 2, 29, 12, 0)));
+        assert_eq!(cron("0 12 13 * 5").next_after(now), Some(utc(2024, 3, 15, 12, 0)));
+        assert_eq!(cron("0 0 * * 7").next_after(now), Some(utc(2024, 3, 17, 0, 0)));
+        assert_eq!(cron("0 0 31 2 *").next_after(now), None);
+
+        for expr in &["* * * *", "60 * * * *", "* * * * 8", "5-1 * * * *", "*/0 * * * *", "* * * foo *"] {
+            assert!(expr.parse::<Cron>().is_err(), "{} should be invalid", expr);
+        }
+    }
+
+    #[test]
+    fn test_missed_runs() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let mut scheduler = Scheduler::in_memory().grace(Duration::from_secs(60));
+        let standup = PushMsg::new(TargetIden::CurrentUser).title("Standup");
+
+        let mut ids = Vec::new();
+        for &missed in &[Missed::Skip, Missed::RunOnce, Missed::RunAll] {
+            let id = scheduler.add(&standup, Recurrence::Cron(cron("0 9 * * *")), missed).unwrap();
+            scheduler.jobs.get_mut(&id).unwrap().next = utc(2024, 3, 13, 9, 0);
+            ids.push(id);
+        }
+        let once = scheduler.add(&standup, Recurrence::Once(Timestamp::now() + Duration::from_secs(3600)), Missed::Skip).unwrap();
+        {
+            let job = scheduler.jobs.get_mut(&once).unwrap();
+            job.recurrence = Recurrence::Once(utc(2024, 3, 15, 9, 0));
+            job.next = utc(2024, 3, 15, 9, 0);
+        }
+
+        // back after three days of downtime, the last run is on time
+        let tick = scheduler.tick_at(&mut api, utc(2024, 3, 15, 9, 0)).unwrap();
+        assert_eq!(tick.sent.len(), 1 + 1 + 3 + 1);
+        assert!(!scheduler.jobs.contains_key(&once));
+
+        // a run not sent yet (the ones above are never sent again)
+        for id in &ids {
+            scheduler.jobs.get_mut(id).unwrap().next = utc(2024, 3, 15, 10, 0);
+        }
+        // too late for all of them, skipped by the first job
+        assert_eq!(scheduler.tick_at(&mut api, utc(2024, 3, 16, 8, 0)).unwrap().sent.len(), 2);
+        assert!(scheduler.jobs().iter().all(|j| j.next == utc(2024, 3, 16, 9, 0)));
+        assert!(scheduler.tick_at(&mut api, utc(2024, 3, 16, 8, 30)).unwrap().sent.is_empty());
+    }
+
+    #[test]
+    fn test_runs_after_long_downtime() {
+        let mut scheduler = Scheduler::in_memory();
+        let standup = PushMsg::new(TargetIden::CurrentUser).title("Standup");
+        let grace = Duration::from_secs(60);
+
+        let mut jobs = Vec::new();
+        for &missed in &[Missed::Skip, Missed::RunOnce, Missed::RunAll] {
+            let id = scheduler.add(&standup, Recurrence::Cron(cron("0 9 * * *")), missed).unwrap();
+            let mut job = scheduler.jobs.remove(&id).unwrap();
+            job.next = utc(2023, 3, 1, 9, 0);
+            jobs.push(job);
+        }
+
+        // a year off, far more runs missed than are ever sent
+        let (runs, next) = jobs[0].runs(utc(2024, 3, 15, 8, 0), grace);
+        assert!(runs.is_empty());
+        assert_eq!(next, Some(utc(2024, 3, 15, 9, 0)));
+        assert_eq!(jobs[1].runs(utc(2024, 3, 15, 8, 0), grace).0, vec![utc(2024, 3, 14, 9, 0)]);
+        let all = jobs[2].runs(utc(2024, 3, 15, 8, 0), grace).0;
+        assert_eq!((all.len(), all[0], all[99]), (100, utc(2023, 3, 1, 9, 0), utc(2023, 6, 8, 9, 0)));
+
+        // the run on time is sent by every policy
+        for job in &jobs {
+            assert_eq!(job.runs(utc(2024, 3, 15, 9, 0), grace).0.last(), Some(&utc(2024, 3, 15, 9, 0)));
+        }
+        assert_eq!(jobs[1].runs(utc(2024, 3, 15, 9, 0), grace).0, vec![utc(2024, 3, 15, 9, 0)]);
+    }
+
+    #[test]
+    fn test_tick_failures() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let mut scheduler = Scheduler::in_memory();
+        let at = utc(2024, 3, 15, 9, 0);
+
+        let mut ids = Vec::new();
+        for title in &["Standup", "Lunch"] {
+            let id = scheduler.add(&PushMsg::new(TargetIden::CurrentUser).title(*title), Recurrence::Cron(cron("0 9 * * *")), Missed::Skip).unwrap();
+            scheduler.jobs.get_mut(&id).unwrap().next = at;
+            ids.push(id);
+        }
+        ids.sort();
+        let (first, second) = (ids[0].clone(), ids[1].clone());
+
+        // the second run was sent just before a crash
+        api.send(&scheduler.jobs[&second].msg().guid(scheduler.jobs[&second].guid(at))).unwrap();
+        server.fail_next(StatusCode::BadRequest);
+
+        let tick = scheduler.tick_at(&mut api, at).unwrap();
+        assert!(tick.sent.is_empty());
+        assert_eq!(tick.failed.iter().map(|f| f.0.clone()).collect::<Vec<_>>(), vec![first.clone()]);
+        assert_eq!(scheduler.jobs[&first].next, at);
+        assert_eq!(scheduler.jobs[&second].next, utc(2024, 3, 16, 9, 0));
+        assert_eq!(server.objects("pushes").len(), 1);
+
+        // the failed job is sent on the next tick
+        let tick = scheduler.tick_at(&mut api, at + Duration::from_secs(30)).unwrap();
+        assert_eq!((tick.sent.len(), tick.failed.len()), (1, 0));
+        assert_eq!(tick.sent[0].guid, Some(scheduler.jobs[&first].guid(at)));
+        assert_eq!(server.objects("pushes").len(), 2);
+    }
+
+    #[test]
+    fn test_scheduler_file() {
+        let path = env::temp_dir().join(format!("pb-test-schedule-{}.json", Uuid::new_v4().simple()));
+        let _ = fs::remove_file(&path);
+
+        let mut scheduler = Scheduler::open(&path).unwrap();
+        let link = PushMsg::link(TargetIden::ChannelTag("ops".to_string()), "https://example.com/handoff".parse().unwrap()).title("Handoff");
+        let id = scheduler.add(&link, Recurrence::Cron(cron("0 17 * * fri")), Missed::RunOnce).unwrap();
+        scheduler.add(&PushMsg::new(TargetIden::CurrentUser).body("Tea"), Recurrence::Once(Timestamp::now() + Duration::from_secs(60)), Missed::Skip).unwrap();
+        assert!(scheduler.add(&link, Recurrence::Once(utc(2020, 1, 1, 0, 0)), Missed::Skip).is_err());
+
+        let reopened = Scheduler::open(&path).unwrap();
+        assert_eq!(reopened.jobs(), scheduler.jobs());
+        assert_eq!(reopened.jobs()[1].msg(), link);
+
+        let mut reopened = reopened;
+        assert!(reopened.remove(&id).unwrap().is_some());
+        assert_eq!(Scheduler::open(&path).unwrap().jobs().len(), 1);
+
+        // changes made through another scheduler are kept
+        let tea = scheduler.jobs()[0].id.clone();
+        let cake = scheduler.add(&PushMsg::new(TargetIden::CurrentUser).body("Cake"), Recurrence::Cron(cron("0 15 * * *")), Missed::Skip).unwrap();
+        assert!(reopened.remove(&cake).unwrap().is_some());
+        assert_eq!(Scheduler::open(&path).unwrap().jobs().iter().map(|j| j.id.clone()).collect::<Vec<_>>(), vec![tea]);
+
+        // a job for an unknown target isn't sent to the user instead
+        let data = json::encode(&Scheduler::open(&path).unwrap().jobs()).unwrap().replace("\"user\"", "\"phone:udx1\"");
+        File::create(&path).unwrap().write_all(data.as_bytes()).unwrap();
+        assert!(Scheduler::open(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,278 @@
+//! Full-text search over locally cached pushes.
+
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use objects::{Push, PushData, PushIden, Timestamp};
+
+static TITLE_WEIGHT: f64 = 3.0;
+static URL_WEIGHT: f64 = 2.0;
+static FILE_NAME_WEIGHT: f64 = 2.0;
+static BODY_WEIGHT: f64 = 1.0;
+static ITEM_WEIGHT: f64 = 1.0;
+static SENDER_WEIGHT: f64 = 1.0;
+
+/// Splits text into lowercase alphanumeric words.
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(|w| w.to_lowercase())
+        .collect()
+}
+
+/// Searchable texts of a push with their weights.
+fn fields(push: &Push) -> Vec<(f64, String)> {
+    let mut fields: Vec<(f64, String)> = Vec::new();
+    for &(weight, ref text) in &[(TITLE_WEIGHT, &push.title), (BODY_WEIGHT, &push.body),
+                                 (SENDER_WEIGHT, &push.sender_name), (SENDER_WEIGHT, &push.sender_email)] {
+        if let Some(ref text) = **text {
+            fields.push((weight, text.clone()));
+        }
+    }
+    match push.data {
+        PushData::Link(Some(ref url)) => fields.push((URL_WEIGHT, url.serialize())),
+        PushData::File { ref file_name, .. } => fields.push((FILE_NAME_WEIGHT, file_name.clone())),
+        PushData::List(ref items) => fields.extend(items.iter().map(|i| (ITEM_WEIGHT, i.to_string()))),
+        PushData::Address(ref address) => fields.push((BODY_WEIGHT, address.clone())),
+        _ => ()
+    }
+    fields
+}
+
+/// Push search query: words to look for and filters.
+/// Every word must be found in a push (as a word prefix) for it to match.
+#[derive(Debug, Clone, Default)]
+pub struct Query {
+    words: Vec<String>,
+    types: Vec<String>,
+    sender_email: Option<String>,
+    created_after: Option<Timestamp>,
+    created_before: Option<Timestamp>,
+    dismissed: Option<bool>,
+    limit: Option<usize>,
+}
+
+impl Query {
+    pub fn new(text: &str) -> Query {
+        Query { words: tokenize(text), ..Query::default() }
+    }
+
+    /// Only pushes of given type (`note`, `link`, `file`...), may be given several times.
+    pub fn typ(mut self, typ: &str) -> Query {
+        self.types.push(typ.to_string());
+        self
+    }
+
+    pub fn sender_email(mut self, email: &str) -> Query {
+        self.sender_email = Some(email.trim().to_lowercase());
+        self
+    }
+
+    pub fn created_after(mut self, since: Timestamp) -> Query {
+        self.created_after = Some(since);
+        self
+    }
+
+    pub fn created_before(mut self, until: Timestamp) -> Query {
+        self.created_before = Some(until);
+        self
+    }
+
+    pub fn dismissed(mut self, dismissed: bool) -> Query {
+        self.dismissed = Some(dismissed);
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Query {
+        self.limit = Some(limit);
+        self
+    }
+
+    fn accepts(&self, push: &Push) -> bool {
+        (self.types.is_empty() || self.types.iter().any(|t| t == push.data.type_name())) &&
+        self.sender_email.as_ref().map_or(true, |email| {
+            push.sender_email_normalized.as_ref().or(push.sender_email.as_ref())
+                .map_or(false, |e| e.to_lowercase() == *email)
+        }) &&
+        self.created_after.map_or(true, |t| push.created >= t) &&
+        self.created_before.map_or(true, |t| push.created < t) &&
+        self.dismissed.map_or(true, |d| push.dismissed == d)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Hit<'a> {
+    pub push: &'a Push,
+    pub score: f64,
+}
+
+/// Inverted index of pushes, ranking matches by the fields words are found in
+/// (title over link and file name over body, list items and sender).
+#[derive(Debug, Default)]
+pub struct PushIndex {
+    pushes: BTreeMap<PushIden, Push>,
+    words: BTreeMap<String, BTreeMap<PushIden, f64>>,
+}
+
+impl PushIndex {
+    pub fn new() -> PushIndex {
+        PushIndex::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pushes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pushes.is_empty()
+    }
+
+    /// Adds or replaces a push. Inactive pushes are removed from index.
+    pub fn insert(&mut self, push: Push) {
+        self.remove(&push.iden);
+        if !push.active {
+            return;
+        }
+
+        for (weight, text) in fields(&push) {
+            for word in tokenize(&*text) {
+                *self.words.entry(word).or_insert_with(BTreeMap::new).entry(push.iden.clone()).or_insert(0.0) += weight;
+            }
+        }
+        self.pushes.insert(push.iden.clone(), push);
+    }
+
+    pub fn remove(&mut self, iden: &PushIden) -> Option<Push> {
+        let push = self.pushes.remove(iden);
+        if let Some(ref push) = push {
+            // only words of the push itself have its postings
+            for (_, text) in fields(push) {
+                for word in tokenize(&*text) {
+                    let empty = match self.words.get_mut(&word) {
+                        Some(postings) => {
+                            postings.remove(iden);
+                            postings.is_empty()
+                        },
+                        None => false
+                    };
+                    if empty {
+                        self.words.remove(&word);
+                    }
+                }
+            }
+        }
+        push
+    }
+
+    /// Scores of pushes containing a word starting with `prefix`.
+    fn lookup(&self, prefix: &str) -> BTreeMap<&PushIden, f64> {
+        let mut scores = BTreeMap::new();
+        for (word, postings) in self.words.range(prefix.to_string()..) {
+            if !word.starts_with(prefix) {
+                break;
+            }
+            // exact word matches rank above prefix matches
+            let boost = if word.len() == prefix.len() { 1.0 } else { 0.5 };
+            for (iden, weight) in postings {
+                *scores.entry(iden).or_insert(0.0) += weight * boost;
+            }
+        }
+        scores
+    }
+
+    /// Returns matching pushes, best matches first, newer first among equally good ones.
+    pub fn search(&self, query: &Query) -> Vec<Hit> {
+        let mut hits: Vec<Hit> = if query.words.is_empty() {
+            self.pushes.values().filter(|p| query.accepts(p)).map(|p| Hit { push: p, score: 0.0 }).collect()
+        } else {
+            let mut matches = self.lookup(&*query.words[0]);
+            for word in &query.words[1..] {
+                let more = self.lookup(&**word);
+                matches = matches.into_iter()
+                    .filter_map(|(iden, score)| more.get(iden).map(|s| (iden, score + s)))
+                    .collect();
+            }
+            matches.into_iter()
+                .map(|(iden, score)| Hit { push: &self.pushes[iden], score: score })
+                .filter(|h| query.accepts(h.push))
+                .collect()
+        };
+
+        hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal).then(b.push.created.cmp(&a.push.created)));
+        if let Some(limit) = query.limit {
+            hits.truncate(limit);
+        }
+        hits
+    }
+}
+
+impl Extend<Push> for PushIndex {
+    fn extend<I: IntoIterator<Item=Push>>(&mut self, pushes: I) {
+        for push in pushes {
+            self.insert(push);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rustc_serialize::json;
+    use objects::{Push, PushIden, Timestamp};
+    use super::{PushIndex, Query};
+
+    fn push(iden: &str, created: f64, extra: &str) -> Push {
+        json::decode(&*format!("{{
+            \"iden\": \"{}\", \"created\": {}, \"modified\": {}, \"active\": true, \"dismissed\": false,
+            \"sender_email\": \"ryan@pushbullet.com\", \"sender_name\": \"Ryan Oldenburg\", {}
+        }}", iden, created, created, extra)).unwrap()
+    }
+
+    fn index() -> PushIndex {
+        let mut index = PushIndex::new();
+        index.extend(vec![
+            push("p1", 100.0, "\"type\": \"note\", \"title\": \"Deploy notes\", \"body\": \"rust release is out\""),
+            push("p2", 200.0, "\"type\": \"link\", \"title\": \"Rust blog\", \"url\": \"https://blog.rust-lang.org/releases\""),
+            push("p3", 300.0, "\"type\": \"file\", \"file_name\": \"release-notes.pdf\", \"file_type\": \"application/pdf\", \"file_url\": \"https://dl.example.com/release-notes.pdf\""),
+            push("p4", 400.0, "\"type\": \"list\", \"title\": \"Groceries\", \"items\": [{\"checked\": false, \"text\": \"rustic bread\"}]"),
+            Push { active: false, ..push("p5", 500.0, "\"type\": \"note\", \"title\": \"Gone\", \"body\": \"rust\"") },
+        ]);
+        index
+    }
+
+    fn idens(hits: Vec<super::Hit>) -> Vec<String> {
+        hits.into_iter().map(|h| h.push.iden.to_string()).collect()
+    }
+
+    #[test]
+    fn test_search_ranking() {
+        let index = index();
+        assert_eq!(index.len(), 4);
+
+        // title match beats url and body, exact word beats prefix
+        assert_eq!(idens(index.search(&Query::new("rust"))), vec!["p2", "p1", "p4"]);
+        assert_eq!(idens(index.search(&Query::new("release notes"))), vec!["p3", "p1"]);
+        assert_eq!(idens(index.search(&Query::new("rust-lang.org"))), vec!["p2"]);
+        assert_eq!(idens(index.search(&Query::new("ryan bread"))), vec!["p4"]);
+        assert!(index.search(&Query::new("python")).is_empty());
+    }
+
+    #[test]
+    fn test_search_filters() {
+        let mut index = index();
+        assert_eq!(idens(index.search(&Query::new("rust").typ("note").typ("list"))), vec!["p1", "p4"]);
+        assert_eq!(idens(index.search(&Query::new("").created_after(Timestamp::from(200.0)).created_before(Timestamp::from(400.0)))), vec!["p3", "p2"]);
+        assert_eq!(idens(index.search(&Query::new("").sender_email("Ryan@Pushbullet.com").limit(1))), vec!["p4"]);
+        assert!(index.search(&Query::new("").sender_email("someone@example.com")).is_empty());
+        assert!(index.search(&Query::new("rust").dismissed(true)).is_empty());
+
+        index.remove(&PushIden::from("p2"));
+        assert_eq!(idens(index.search(&Query::new("rust"))), vec!["p1", "p4"]);
+        assert!(index.search(&Query::new("blog")).is_empty());
+        assert!(!index.words.contains_key("blog") && index.words.contains_key("rust"));
+
+        // re-indexed push loses its old words
+        index.insert(push("p1", 100.0, "\"type\": \"note\", \"title\": \"Deploy log\""));
+        assert!(!idens(index.search(&Query::new("release"))).contains(&"p1".to_string()));
+        assert_eq!(idens(index.search(&Query::new("deploy log"))), vec!["p1"]);
+    }
+}
@@ -0,0 +1,379 @@
+//! Local copy of account objects, updated incrementally with `modified_after`
+//! instead of downloading everything on every start.
+//!
+//! Objects are kept as JSON by iden in a `Backend`: `MemoryBackend` is always
+//! available, `SqliteBackend` needs `sqlite` cargo feature.
+
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+
+use rustc_serialize::{json, Encodable};
+
+use api::{PbAPI, PbResult};
+use objects::{Timestamp, Resource, Listable, Push, Device, Contact, Subscription, Channel};
+
+#[cfg(feature = "sqlite")]
+use std::path::Path;
+#[cfg(feature = "sqlite")]
+use rusqlite::{self, Connection, OptionalExtension};
+
+/// Objects which can be kept in a `Store`.
+pub trait Storable : Resource + Listable + Encodable {}
+
+impl Storable for Push {}
+impl Storable for Device {}
+impl Storable for Contact {}
+impl Storable for Subscription {}
+impl Storable for Channel {}
+
+#[derive(Debug)]
+pub enum StoreError {
+    Encode(json::EncoderError),
+    Decode(json::DecoderError),
+    #[cfg(feature = "sqlite")]
+    Sqlite(rusqlite::Error),
+}
+
+impl From<json::EncoderError> for StoreError {
+    fn from(e: json::EncoderError) -> StoreError { StoreError::Encode(e) }
+}
+
+impl From<json::DecoderError> for StoreError {
+    fn from(e: json::DecoderError) -> StoreError { StoreError::Decode(e) }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<rusqlite::Error> for StoreError {
+    fn from(e: rusqlite::Error) -> StoreError { StoreError::Sqlite(e) }
+}
+
+impl error::Error for StoreError {
+    fn description(&self) -> &str {
+        match *self {
+            StoreError::Encode(ref e) => e.description(),
+            StoreError::Decode(ref e) => e.description(),
+            #[cfg(feature = "sqlite")]
+            StoreError::Sqlite(ref e) => e.description(),
+        }
+    }
+
+    fn cause<'a>(&'a self) -> Option<&'a error::Error> {
+        match *self {
+            StoreError::Encode(ref e) => Some(e as &error::Error),
+            StoreError::Decode(ref e) => Some(e as &error::Error),
+            #[cfg(feature = "sqlite")]
+            StoreError::Sqlite(ref e) => Some(e as &error::Error),
+        }
+    }
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match *self {
+            StoreError::Encode(ref e) => e.fmt(fmt),
+            StoreError::Decode(ref e) => e.fmt(fmt),
+            #[cfg(feature = "sqlite")]
+            StoreError::Sqlite(ref e) => e.fmt(fmt),
+        }
+    }
+}
+
+pub type StoreResult<T> = Result<T, StoreError>;
+
+/// Storage of JSON encoded objects, grouped by kind (`root_uri` of object type).
+pub trait Backend {
+    fn get(&self, kind: &str, iden: &str) -> StoreResult<Option<String>>;
+    /// Returns all objects of a kind, most recently modified first.
+    fn all(&self, kind: &str) -> StoreResult<Vec<String>>;
+    fn put(&mut self, kind: &str, iden: &str, modified: Timestamp, data: &str) -> StoreResult<()>;
+    fn remove(&mut self, kind: &str, iden: &str) -> StoreResult<()>;
+    fn synced(&self, kind: &str) -> StoreResult<Option<Timestamp>>;
+    fn set_synced(&mut self, kind: &str, modified: Timestamp) -> StoreResult<()>;
+}
+
+#[derive(Debug, Default)]
+pub struct MemoryBackend {
+    objects: BTreeMap<(String, String), (Timestamp, String)>,
+    synced: BTreeMap<String, Timestamp>,
+}
+
+impl Backend for MemoryBackend {
+    fn get(&self, kind: &str, iden: &str) -> StoreResult<Option<String>> {
+        Ok(self.objects.get(&(kind.to_string(), iden.to_string())).map(|&(_, ref data)| data.clone()))
+    }
+
+    fn all(&self, kind: &str) -> StoreResult<Vec<String>> {
+        let mut objs: Vec<&(Timestamp, String)> = self.objects.iter().filter(|&(k, _)| k.0 == kind).map(|(_, v)| v).collect();
+        objs.sort_by(|a, b| b.0.cmp(&a.0));
+        Ok(objs.into_iter().map(|&(_, ref data)| data.clone()).collect())
+    }
+
+    fn put(&mut self, kind: &str, iden: &str, modified: Timestamp, data: &str) -> StoreResult<()> {
+        self.objects.insert((kind.to_string(), iden.to_string()), (modified, data.to_string()));
+        Ok(())
+    }
+
+    fn remove(&mut self, kind: &str, iden: &str) -> StoreResult<()> {
+        self.objects.remove(&(kind.to_string(), iden.to_string()));
+        Ok(())
+    }
+
+    fn synced(&self, kind: &str) -> StoreResult<Option<Timestamp>> {
+        Ok(self.synced.get(kind).cloned())
+    }
+
+    fn set_synced(&mut self, kind: &str, modified: Timestamp) -> StoreResult<()> {
+        self.synced.insert(kind.to_string(), modified);
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+pub struct SqliteBackend {
+    conn: Connection,
+}
+
+#[cfg(feature = "sqlite")]
+impl SqliteBackend {
+    pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<SqliteBackend> {
+        SqliteBackend::init(try!(Connection::open(path)))
+    }
+
+    pub fn open_in_memory() -> StoreResult<SqliteBackend> {
+        SqliteBackend::init(try!(Connection::open_in_memory()))
+    }
+
+    fn init(conn: Connection) -> StoreResult<SqliteBackend> {
+        try!(conn.execute_batch("
+            CREATE TABLE IF NOT EXISTS objects (
+                kind TEXT NOT NULL,
+                iden TEXT NOT NULL,
+                modified REAL NOT NULL,
+                data TEXT NOT NULL,
+                PRIMARY KEY (kind, iden)
+            );
+            CREATE INDEX IF NOT EXISTS objects_modified ON objects (kind, modified);
+            CREATE TABLE IF NOT EXISTS sync_state (
+                kind TEXT NOT NULL PRIMARY KEY,
+                modified REAL NOT NULL
+            );
+        "));
+        Ok(SqliteBackend { conn: conn })
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Backend for SqliteBackend {
+    fn get(&self, kind: &str, iden: &str) -> StoreResult<Option<String>> {
+        Ok(try!(self.conn.query_row("SELECT data FROM objects WHERE kind = ?1 AND iden = ?2",
+                                    &[kind, iden], |row| row.get(0)).optional()))
+    }
+
+    fn all(&self, kind: &str) -> StoreResult<Vec<String>> {
+        let mut stmt = try!(self.conn.prepare("SELECT data FROM objects WHERE kind = ?1 ORDER BY modified DESC"));
+        let rows = try!(stmt.query_map(&[kind], |row| row.get(0)));
+        let mut objs = Vec::new();
+        for row in rows {
+            objs.push(try!(row));
+        }
+        Ok(objs)
+    }
+
+    fn put(&mut self, kind: &str, iden: &str, modified: Timestamp, data: &str) -> StoreResult<()> {
+        try!(self.conn.execute("INSERT OR REPLACE INTO objects (kind, iden, modified, data) VALUES (?1, ?2, ?3, ?4)",
+                               &[&kind as &rusqlite::ToSql, &iden, &modified.as_f64(), &data]));
+        Ok(())
+    }
+
+    fn remove(&mut self, kind: &str, iden: &str) -> StoreResult<()> {
+        try!(self.conn.execute("DELETE FROM objects WHERE kind = ?1 AND iden = ?2", &[kind, iden]));
+        Ok(())
+    }
+
+    fn synced(&self, kind: &str) -> StoreResult<Option<Timestamp>> {
+        let modified: Option<f64> = try!(self.conn.query_row("SELECT modified FROM sync_state WHERE kind = ?1",
+                                                             &[kind], |row| row.get(0)).optional());
+        Ok(modified.map(Timestamp::from))
+    }
+
+    fn set_synced(&mut self, kind: &str, modified: Timestamp) -> StoreResult<()> {
+        try!(self.conn.execute("INSERT OR REPLACE INTO sync_state (kind, modified) VALUES (?1, ?2)",
+                               &[&kind as &rusqlite::ToSql, &modified.as_f64()]));
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl fmt::Debug for SqliteBackend {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        fmt.write_str("SqliteBackend")
+    }
+}
+
+#[derive(Debug)]
+pub struct Store<B: Backend> {
+    backend: B,
+}
+
+impl Store<MemoryBackend> {
+    pub fn in_memory() -> Store<MemoryBackend> {
+        Store::new(MemoryBackend::default())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl Store<SqliteBackend> {
+    pub fn open<P: AsRef<Path>>(path: P) -> StoreResult<Store<SqliteBackend>> {
+        SqliteBackend::open(path).map(Store::new)
+    }
+}
+
+impl<B: Backend> Store<B> {
+    pub fn new(backend: B) -> Store<B> {
+        Store { backend: backend }
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Saves an object, replacing older version with the same iden.
+    /// Inactive objects are kept as tombstones, so that `get` tells
+    /// objects deleted on server from never seen ones.
+    pub fn upsert<R: Storable>(&mut self, obj: &R) -> StoreResult<()> {
+        self.backend.put(R::root_uri(), &*obj.iden().to_string(), obj.modified(), &*try!(json::encode(obj)))
+    }
+
+    /// Returns stored object, inactive if it was deleted on server.
+    pub fn get<R: Storable>(&self, iden: &R::Iden) -> StoreResult<Option<R>> {
+        match try!(self.backend.get(R::root_uri(), &*iden.to_string())) {
+            Some(data) => Ok(Some(try!(json::decode(&*data)))),
+            None => Ok(None)
+        }
+    }
+
+    /// Returns all active objects of a kind, most recently modified first.
+    pub fn all<R: Storable>(&self) -> StoreResult<Vec<R>> {
+        let mut objs = Vec::new();
+        for data in try!(self.backend.all(R::root_uri())) {
+            let obj: R = try!(json::decode(&*data));
+            if obj.active() {
+                objs.push(obj);
+            }
+        }
+        Ok(objs)
+    }
+
+    /// The newest `modified` timestamp seen in server responses for a kind.
+    pub fn last_synced<R: Storable>(&self) -> StoreResult<Option<Timestamp>> {
+        self.backend.synced(R::root_uri())
+    }
+
+    /// Applies a batch of changes from server and advances sync position.
+    pub fn apply<R: Storable>(&mut self, objs: &[R]) -> StoreResult<()> {
+        let mut synced = try!(self.last_synced::<R>());
+        for obj in objs {
+            try!(self.upsert(obj));
+            synced = Some(synced.map_or(obj.modified(), |s| s.max(obj.modified())));
+        }
+        match synced {
+            Some(modified) => self.backend.set_synced(R::root_uri(), modified),
+            None => Ok(())
+        }
+    }
+
+    /// Fetches objects changed since the last sync, returning the number of changes.
+    pub fn sync<R: Storable>(&mut self, api: &mut PbAPI) -> PbResult<usize> {
+        let since = try!(self.last_synced::<R>());
+        let (mut objs, mut cursor) = match since {
+            Some(since) => try!(api.load_since::<R>(since)),
+            None => try!(api.load::<R>())
+        };
+        while let Some(c) = cursor {
+            let (next, c) = try!(api.load_page::<R>(None, since, false, Some(c)));
+            objs.extend(next);
+            cursor = c;
+        }
+
+        try!(self.apply(&*objs));
+        Ok(objs.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Store, Backend};
+    use testing::MockServer;
+    use objects::{Push, Device};
+    use messages::{PushMsg, TargetIden};
+
+    fn check_sync<B: Backend>(mut store: Store<B>) {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let first = api.send(&PushMsg::new(TargetIden::CurrentUser).title("first")).unwrap();
+        let second = api.send(&PushMsg::new(TargetIden::CurrentUser).title("second")).unwrap();
+
+        assert_eq!(store.last_synced::<Push>().unwrap(), None);
+        assert_eq!(store.sync::<Push>(&mut api).unwrap(), 2);
+        assert_eq!(store.last_synced::<Push>().unwrap(), Some(second.modified));
+        assert_eq!(store.all::<Push>().unwrap(), vec![second.clone(), first.clone()]);
+
+        // nothing changed, nothing downloaded
+        assert_eq!(store.sync::<Push>(&mut api).unwrap(), 0);
+
+        api.remove::<Push>(first.iden.clone()).unwrap();
+        let third = api.send(&PushMsg::new(TargetIden::CurrentUser).title("third")).unwrap();
+        assert_eq!(store.sync::<Push>(&mut api).unwrap(), 2);
+        assert!(!store.get::<Push>(&first.iden).unwrap().unwrap().active);
+        assert_eq!(store.get::<Push>(&"ujmissing".into()).unwrap(), None);
+        assert_eq!(store.get::<Push>(&third.iden).unwrap(), Some(third.clone()));
+        assert_eq!(store.all::<Push>().unwrap(), vec![third, second]);
+
+        let device = api.ensure_device("store").unwrap();
+        assert_eq!(store.sync::<Device>(&mut api).unwrap(), 1);
+        assert_eq!(store.all::<Device>().unwrap(), vec![device]);
+    }
+
+    #[test]
+    fn test_memory_store_sync() {
+        check_sync(Store::in_memory());
+    }
+
+    #[test]
+    fn test_sync_pages() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = server.api();
+        let mut store = Store::in_memory();
+        server.page_size(2);
+
+        for title in &["a", "b", "c"] {
+            api.send(&PushMsg::new(TargetIden::CurrentUser).title(*title)).unwrap();
+        }
+        assert_eq!(store.sync::<Push>(&mut api).unwrap(), 3);
+
+        // pushes older than the last sync stay out of every page
+        for title in &["d", "e", "f"] {
+            api.send(&PushMsg::new(TargetIden::CurrentUser).title(*title)).unwrap();
+        }
+        assert_eq!(store.sync::<Push>(&mut api).unwrap(), 3);
+        let titles: Vec<String> = store.all::<Push>().unwrap().into_iter().filter_map(|p| p.title).collect();
+        assert_eq!(titles, vec!["f", "e", "d", "c", "b", "a"]);
+    }
+
+    #[cfg(feature = "sqlite")]
+    #[test]
+    fn test_sqlite_store_sync() {
+        use std::env;
+        use std::fs;
+        use uuid::Uuid;
+
+        let path = env::temp_dir().join(format!("pb-test-{}.db", Uuid::new_v4().simple()));
+        check_sync(Store::open(&path).unwrap());
+
+        let store = Store::open(&path).unwrap();
+        assert_eq!(store.all::<Push>().unwrap().len(), 2);
+        assert!(store.last_synced::<Push>().unwrap().is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
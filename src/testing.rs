@@ -0,0 +1,841 @@
+//! In-process mock of Pushbullet REST API and event stream,
+//! so that code using `PbAPI` can be tested offline.
+
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write, BufRead, BufReader};
+use std::net::{TcpListener, TcpStream, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Sender};
+use std::thread;
+use std::time::Duration;
+
+use hyper::server::{Server, Handler, Request, Response, Listening};
+use hyper::header::{Authorization, Basic, ContentType};
+use hyper::status::StatusCode;
+use hyper::method::Method;
+use hyper::uri::RequestUri;
+use hyper::error::Result as HttpResult;
+
+use rustc_serialize::json::{self, Json, Object, ToJson};
+use rustc_serialize::base64::{ToBase64, STANDARD};
+use url::form_urlencoded;
+
+use api::PbAPI;
+use objects::Timestamp;
+
+static COLLECTIONS: &'static [&'static str] = &["pushes", "devices", "contacts", "chats", "subscriptions", "channels", "grants", "texts"];
+static PUSH_TYPES: &'static [&'static str] = &["note", "link", "file", "list", "address"];
+static DEFAULT_LIMIT: usize = 500;
+static WS_GUID: &'static str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+pub struct MockServer {
+    state: Arc<Mutex<State>>,
+    http: Listening,
+    stream: SocketAddr,
+}
+
+impl MockServer {
+    pub fn start(api_key: &str) -> HttpResult<MockServer> {
+        let state = Arc::new(Mutex::new(State::new(api_key)));
+
+        let mut server = try!(Server::http("127.0.0.1:0"));
+        server.keep_alive(None);
+        let http = try!(server.handle(MockHandler(state.clone())));
+
+        let listener = try!(TcpListener::bind("127.0.0.1:0"));
+        let stream = try!(listener.local_addr());
+        {
+            let state = state.clone();
+            thread::spawn(move || serve_stream(listener, state));
+        }
+
+        state.lock().unwrap().base_url = format!("http://{}/v2/", http.socket);
+
+        Ok(MockServer {
+            state: state,
+            http: http,
+            stream: stream,
+        })
+    }
+
+    pub fn base_url(&self) -> String {
+        self.state.lock().unwrap().base_url.clone()
+    }
+
+    pub fn stream_url(&self) -> String {
+        format!("ws://{}/websocket/{}", self.stream, self.state.lock().unwrap().api_key)
+    }
+
+    /// Returns a client talking to this server.
+    pub fn api(&self) -> PbAPI {
+        let state = self.state.lock().unwrap();
+        PbAPI::new(&*state.api_key)
+            .base_url(&*state.base_url)
+            .stream_url(&*format!("ws://{}/websocket/", self.stream))
+    }
+
+    /// Creates an object the same way `POST` to `root_uri` would.
+    pub fn insert(&self, root_uri: &str, obj: Json) -> Result<Json, Json> {
+        match self.state.lock().unwrap().create(root_uri, &*obj.to_string()) {
+            (StatusCode::Ok, obj) => Ok(obj),
+            (_, err) => Err(err)
+        }
+    }
+
+    /// Returns all stored objects, including deleted ones, oldest first.
+    pub fn objects(&self, root_uri: &str) -> Vec<Json> {
+        self.state.lock().unwrap().collections.get(root_uri)
+            .map(|objs| objs.iter().cloned().map(Json::Object).collect())
+            .unwrap_or(Vec::new())
+    }
+
+    /// Limits the number of objects returned per page, to exercise cursors.
+    pub fn page_size(&self, limit: usize) {
+        self.state.lock().unwrap().page_size = limit;
+    }
+
+    /// Makes the next request fail with given status.
+    pub fn fail_next(&self, status: StatusCode) {
+        self.state.lock().unwrap().failures.push(Some(status));
+    }
+
+    /// Lets `count` requests through and makes the one after them fail with given status.
+    pub fn fail_after(&self, count: usize, status: StatusCode) {
+        let mut state = self.state.lock().unwrap();
+        for _ in 0..count {
+            state.failures.push(None);
+        }
+        state.failures.push(Some(status));
+    }
+
+    /// Handles the next request, but replies to it with given status,
+    /// as if the response was lost on its way back.
+    pub fn fail_next_reply(&self, status: StatusCode) {
+        self.state.lock().unwrap().lost_replies.push(status);
+    }
+
+    pub fn tickle(&self, subtype: &str) {
+        self.state.lock().unwrap().tickle(subtype);
+    }
+
+    /// Sends an ephemeral with given `push` object to event streams.
+    pub fn ephemeral(&self, push: Json) {
+        self.state.lock().unwrap().ephemeral(push);
+    }
+
+    /// Closes all open event streams.
+    pub fn close_streams(&self) {
+        self.state.lock().unwrap().listeners.clear();
+    }
+
+    /// Number of event streams still open, as of the last message sent to them.
+    pub fn open_streams(&self) -> usize {
+        self.state.lock().unwrap().listeners.len()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        // detaches server thread, so it isn't joined on drop
+        let _ = self.http.close();
+    }
+}
+
+type Reply = (StatusCode, Json);
+
+struct State {
+    api_key: String,
+    base_url: String,
+    serial: usize,
+    clock: Timestamp,
+    page_size: usize,
+    user: Object,
+    collections: BTreeMap<&'static str, Vec<Object>>,
+    failures: Vec<Option<StatusCode>>,
+    lost_replies: Vec<StatusCode>,
+    listeners: Vec<Sender<String>>,
+    files: BTreeMap<String, (String, Option<Vec<u8>>)>,
+}
+
+macro_rules! obj {
+    {$($name:expr => $value:expr),*} => {{
+        let mut o = Object::new();
+        $(o.insert($name.to_string(), ($value).to_json());)*
+        o
+    }}
+}
+
+fn error(status: StatusCode, typ: &str, message: &str) -> Reply {
+    (status, Json::Object(obj! {
+        "error" => Json::Object(obj! {
+            "type" => typ,
+            "message" => message,
+            "cat" => "~(=^‥^)"
+        }),
+        "error_code" => typ
+    }))
+}
+
+fn not_found() -> Reply {
+    error(StatusCode::NotFound, "invalid_request", "The resource could not be found.")
+}
+
+fn bad_request(message: &str) -> Reply {
+    error(StatusCode::BadRequest, "invalid_request", message)
+}
+
+fn get_str<'a>(obj: &'a Object, key: &str) -> Option<&'a str> {
+    obj.get(key).and_then(|v| v.as_string())
+}
+
+fn get_timestamp(obj: &Object, key: &str) -> Timestamp {
+    Timestamp::from(obj.get(key).and_then(|v| v.as_f64()).unwrap_or(0.0))
+}
+
+fn is_active(obj: &Object) -> bool {
+    obj.get("active").and_then(|v| v.as_boolean()).unwrap_or(false)
+}
+
+impl State {
+    fn new(api_key: &str) -> State {
+        let created = Timestamp::now();
+        State {
+            api_key: api_key.to_string(),
+            base_url: String::new(),
+            serial: 0,
+            clock: created,
+            page_size: DEFAULT_LIMIT,
+            user: obj! {
+                "iden" => "ujmockuser",
+                "created" => created,
+                "modified" => created,
+                "email" => "mock@example.com",
+                "email_normalized" => "mock@example.com",
+                "name" => "Mock User",
+                "image_url" => "https://static.pushbullet.com/missing-image.png",
+                "api_key" => api_key
+            },
+            collections: COLLECTIONS.iter().map(|&c| (c, Vec::new())).collect(),
+            failures: Vec::new(),
+            lost_replies: Vec::new(),
+            listeners: Vec::new(),
+            files: BTreeMap::new(),
+        }
+    }
+
+    fn tick(&mut self) -> Timestamp {
+        // keep timestamps strictly increasing, so that modified_after never skips changes
+        self.clock = Timestamp::now().max(self.clock + Duration::from_millis(1));
+        self.clock
+    }
+
+    fn next_iden(&mut self, root_uri: &str) -> String {
+        self.serial += 1;
+        format!("ujmock{}{:06}", &root_uri[..1], self.serial)
+    }
+
+    fn tickle(&mut self, subtype: &str) {
+        let msg = Json::Object(obj! { "type" => "tickle", "subtype" => subtype }).to_string();
+        self.listeners.retain(|l| l.send(msg.clone()).is_ok());
+    }
+
+    fn ephemeral(&mut self, push: Json) {
+        let msg = Json::Object(obj! { "type" => "push", "push" => push }).to_string();
+        self.listeners.retain(|l| l.send(msg.clone()).is_ok());
+    }
+
+    fn collection(&self, root_uri: &str) -> Option<&'static str> {
+        COLLECTIONS.iter().find(|&&c| c == root_uri).cloned()
+    }
+
+    fn dispatch(&mut self, method: &Method, uri: &str, api_key: Option<String>, body: &str) -> Reply {
+        if api_key.as_ref().map(|k| &**k) != Some(&*self.api_key) {
+            return error(StatusCode::Unauthorized, "invalid_access_token", "Access token is missing or invalid.");
+        }
+
+        if !self.failures.is_empty() {
+            if let Some(status) = self.failures.remove(0) {
+                return error(status, "server_error", "Injected failure.");
+            }
+        }
+        if !self.lost_replies.is_empty() {
+            let status = self.lost_replies.remove(0);
+            self.dispatch(method, uri, api_key, body);
+            return error(status, "server_error", "Injected failure.");
+        }
+
+        let (path, query) = match uri.find('?') {
+            Some(idx) => (&uri[..idx], &uri[idx + 1..]),
+            None => (uri, "")
+        };
+        let params: BTreeMap<String, String> = form_urlencoded::parse(query.as_bytes()).into_iter().collect();
+
+        let parts: Vec<&str> = path.trim_matches('/').split('/').collect();
+        if parts.len() < 2 || parts.len() > 3 || parts[0] != "v2" {
+            return not_found();
+        }
+
+        match (method, parts[1], parts.get(2).cloned()) {
+            (&Method::Get, "users", Some("me")) => (StatusCode::Ok, Json::Object(self.user.clone())),
+            (&Method::Post, "upload-request", None) => self.upload_request(body),
+            (&Method::Get, "everything", None) => self.everything(&params),
+            (&Method::Get, coll, None) => self.list(coll, &params),
+            (&Method::Get, coll, Some(iden)) => self.get(coll, iden),
+            (&Method::Post, coll, None) => self.create(coll, body),
+            (&Method::Post, coll, Some(iden)) => self.update(coll, iden, body),
+            (&Method::Delete, coll, Some(iden)) => self.delete(coll, iden),
+            _ => not_found()
+        }
+    }
+
+    /// Returns objects of given collections matching query, newest first, and pagination offset and limit.
+    fn select(&self, colls: &[&'static str], params: &BTreeMap<String, String>) -> Result<(Vec<(&'static str, &Object)>, usize, usize), Reply> {
+        let since = match params.get("modified_after").map(|v| v.parse::<Timestamp>()) {
+            Some(Ok(v)) => v,
+            Some(Err(_)) => return Err(bad_request("Invalid modified_after parameter.")),
+            None => Timestamp::default()
+        };
+        let limit = match params.get("limit").map(|v| v.parse::<usize>()) {
+            Some(Ok(v)) if v > 0 => v.min(self.page_size),
+            Some(_) => return Err(bad_request("Invalid limit parameter.")),
+            None => self.page_size
+        };
+        let offset = match params.get("cursor").map(|v| v.parse::<usize>()) {
+            Some(Ok(v)) => v,
+            Some(Err(_)) => return Err(bad_request("Invalid cursor.")),
+            None => 0
+        };
+        let only_active = params.get("active").map(|v| v == "true").unwrap_or(false);
+
+        let mut objs: Vec<(&'static str, &Object)> = colls.iter()
+            .flat_map(|&c| self.collections[c].iter().map(move |o| (c, o)))
+            .filter(|&(_, o)| get_timestamp(o, "modified") > since && (!only_active || is_active(o)))
+            .collect();
+        objs.sort_by(|a, b| get_timestamp(b.1, "modified").cmp(&get_timestamp(a.1, "modified")));
+        Ok((objs, offset, limit))
+    }
+
+    fn page(&self, colls: &[&'static str], params: &BTreeMap<String, String>) -> Reply {
+        let (objs, offset, limit) = match self.select(colls, params) {
+            Ok(selected) => selected,
+            Err(reply) => return reply
+        };
+
+        let mut env: Object = colls.iter().map(|&c| (c.to_string(), Json::Array(Vec::new()))).collect();
+        for &(coll, obj) in objs.iter().skip(offset).take(limit) {
+            if let Some(&mut Json::Array(ref mut page)) = env.get_mut(coll) {
+                page.push(Json::Object(obj.clone()));
+            }
+        }
+        if offset + limit < objs.len() {
+            env.insert("cursor".to_string(), Json::String((offset + limit).to_string()));
+        }
+        (StatusCode::Ok, Json::Object(env))
+    }
+
+    fn list(&self, root_uri: &str, params: &BTreeMap<String, String>) -> Reply {
+        match self.collection(root_uri) {
+            Some(coll) => self.page(&[coll], params),
+            None => not_found()
+        }
+    }
+
+    fn everything(&self, params: &BTreeMap<String, String>) -> Reply {
+        self.page(COLLECTIONS, params)
+    }
+
+    fn find(&mut self, root_uri: &str, iden: &str) -> Option<&mut Object> {
+        self.collections.get_mut(root_uri)
+            .and_then(|objs| objs.iter_mut().find(|o| get_str(o, "iden") == Some(iden) && is_active(o)))
+    }
+
+    fn get(&mut self, root_uri: &str, iden: &str) -> Reply {
+        match self.find(root_uri, iden) {
+            Some(obj) => (StatusCode::Ok, Json::Object(obj.clone())),
+            None => not_found()
+        }
+    }
+
+    fn create(&mut self, root_uri: &str, body: &str) -> Reply {
+        let coll = match self.collection(root_uri) {
+            Some(c) => c,
+            None => return not_found()
+        };
+        let mut obj = match Json::from_str(body) {
+            Ok(Json::Object(o)) => o,
+            _ => return bad_request("Invalid JSON body.")
+        };
+
+        let created = self.tick();
+        let iden = self.next_iden(coll);
+        obj.insert("iden".to_string(), iden.to_json());
+        obj.insert("created".to_string(), created.to_json());
+        obj.insert("modified".to_string(), created.to_json());
+        obj.insert("active".to_string(), true.to_json());
+
+        let result = match coll {
+            "pushes" => self.new_push(&mut obj),
+            "devices" => self.new_device(&mut obj),
+            "contacts" => self.new_contact(&mut obj),
+            "chats" => self.new_chat(&mut obj),
+            "subscriptions" => self.new_subscription(&mut obj),
+            "channels" => self.new_channel(&mut obj),
+            "texts" => self.new_text(&mut obj),
+            _ => Ok(())
+        };
+        if let Err(reply) = result {
+            return reply;
+        }
+
+        self.collections.get_mut(coll).unwrap().push(obj.clone());
+        self.tickle_for(coll);
+        (StatusCode::Ok, Json::Object(obj))
+    }
+
+    fn new_push(&mut self, obj: &mut Object) -> Result<(), Reply> {
+        let typ = get_str(obj, "type").unwrap_or("note").to_string();
+        if !PUSH_TYPES.contains(&&*typ) {
+            return Err(bad_request("Invalid push type."));
+        }
+        obj.insert("type".to_string(), typ.to_json());
+        obj.insert("dismissed".to_string(), false.to_json());
+        obj.insert("direction".to_string(), "self".to_json());
+
+        for &(from, to) in &[("iden", "sender_iden"), ("email", "sender_email"), ("email_normalized", "sender_email_normalized"), ("name", "sender_name")] {
+            obj.insert(to.to_string(), self.user[from].clone());
+        }
+
+        if let Some(email) = obj.remove("email") {
+            let email = match email.as_string() {
+                Some(e) => e.to_string(),
+                None => return Err(bad_request("Invalid email."))
+            };
+            obj.insert("receiver_email_normalized".to_string(), email.to_lowercase().to_json());
+            obj.insert("receiver_email".to_string(), email.to_json());
+            obj.insert("direction".to_string(), "outgoing".to_json());
+        } else {
+            for &(from, to) in &[("iden", "receiver_iden"), ("email", "receiver_email"), ("email_normalized", "receiver_email_normalized")] {
+                obj.insert(to.to_string(), self.user[from].clone());
+            }
+        }
+
+        if let Some(device_iden) = obj.remove("device_iden") {
+            if self.find("devices", device_iden.as_string().unwrap_or("")).is_none() {
+                return Err(bad_request("Target device not found."));
+            }
+            obj.insert("target_device_iden".to_string(), device_iden);
+        }
+
+        if let Some(tag) = obj.remove("channel_tag") {
+            let channel = self.collections["channels"].iter()
+                .find(|c| is_active(c) && c.get("tag") == Some(&tag))
+                .and_then(|c| c.get("iden").cloned());
+            match channel {
+                Some(iden) => { obj.insert("channel_iden".to_string(), iden); },
+                None => return Err(bad_request("Channel not found."))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn new_device(&mut self, obj: &mut Object) -> Result<(), Reply> {
+        let typ = obj.get("type").cloned().unwrap_or("stream".to_json());
+        obj.entry("nickname".to_string()).or_insert("".to_json());
+        obj.entry("kind".to_string()).or_insert(typ.clone());
+        obj.insert("type".to_string(), typ);
+        obj.insert("pushable".to_string(), true.to_json());
+        Ok(())
+    }
+
+    fn new_contact(&mut self, obj: &mut Object) -> Result<(), Reply> {
+        let email = match get_str(obj, "email") {
+            Some(e) => e.to_string(),
+            None => return Err(bad_request("Missing email."))
+        };
+        obj.entry("name".to_string()).or_insert(email.to_json());
+        obj.insert("email_normalized".to_string(), email.to_lowercase().to_json());
+        obj.insert("status".to_string(), "user".to_json());
+        Ok(())
+    }
+
+    fn new_chat(&mut self, obj: &mut Object) -> Result<(), Reply> {
+        let email = match obj.remove("email").as_ref().and_then(|e| e.as_string()) {
+            Some(e) => e.to_string(),
+            None => return Err(bad_request("Missing email."))
+        };
+        obj.insert("muted".to_string(), false.to_json());
+        obj.insert("with".to_string(), Json::Object(obj! {
+            "type" => "email",
+            "name" => &*email,
+            "email_normalized" => email.to_lowercase(),
+            "email" => email
+        }));
+        Ok(())
+    }
+
+    fn new_subscription(&mut self, obj: &mut Object) -> Result<(), Reply> {
+        let tag = match obj.remove("channel_tag") {
+            Some(Json::String(tag)) => tag,
+            _ => return Err(bad_request("Missing channel_tag."))
+        };
+        let channel = match self.collections["channels"].iter().find(|c| is_active(c) && get_str(c, "tag") == Some(&*tag)) {
+            Some(c) => c,
+            None => return Err(bad_request("Channel not found."))
+        };
+        let subscribed = self.collections["subscriptions"].iter()
+            .any(|s| is_active(s) && s.get("channel").and_then(|c| c.find("tag")).and_then(|t| t.as_string()) == Some(&*tag));
+        if subscribed {
+            return Err(bad_request("Already subscribed to this channel."));
+        }
+
+        let mut info = Object::new();
+        for key in &["iden", "tag", "name", "description", "image_url", "website_url"] {
+            if let Some(value) = channel.get(*key) {
+                info.insert(key.to_string(), value.clone());
+            }
+        }
+        obj.insert("channel".to_string(), Json::Object(info));
+        Ok(())
+    }
+
+    fn new_channel(&mut self, obj: &mut Object) -> Result<(), Reply> {
+        for key in &["tag", "name", "description"] {
+            if get_str(obj, key).is_none() {
+                return Err(bad_request(&*format!("Missing {}.", key)));
+            }
+        }
+        let taken = self.collections["channels"].iter().any(|c| is_active(c) && c.get("tag") == obj.get("tag"));
+        if taken {
+            return Err(bad_request("Channel tag is already taken."));
+        }
+        Ok(())
+    }
+
+    fn new_text(&mut self, obj: &mut Object) -> Result<(), Reply> {
+        let device = obj.get("data").and_then(|d| d.find("target_device_iden")).and_then(|i| i.as_string()).unwrap_or("").to_string();
+        if self.find("devices", &*device).is_none() {
+            return Err(bad_request("Target device not found."));
+        }
+        let has_addresses = obj.get("data").and_then(|d| d.find("addresses")).and_then(|a| a.as_array()).map_or(false, |a| !a.is_empty());
+        if !has_addresses {
+            return Err(bad_request("Missing addresses."));
+        }
+        Ok(())
+    }
+
+    fn update(&mut self, root_uri: &str, iden: &str, body: &str) -> Reply {
+        let changes = match Json::from_str(body) {
+            Ok(Json::Object(o)) => o,
+            _ => return bad_request("Invalid JSON body.")
+        };
+        let modified = self.tick();
+        let obj = match self.find(root_uri, iden) {
+            Some(obj) => {
+                for (key, value) in changes {
+                    match &*key {
+                        "iden" | "created" | "modified" | "active" => (),
+                        _ => { obj.insert(key, value); }
+                    }
+                }
+                obj.insert("modified".to_string(), modified.to_json());
+                obj.clone()
+            },
+            None => return not_found()
+        };
+        self.tickle_for(root_uri);
+        (StatusCode::Ok, Json::Object(obj))
+    }
+
+    fn delete(&mut self, root_uri: &str, iden: &str) -> Reply {
+        let modified = self.tick();
+        match self.find(root_uri, iden) {
+            Some(obj) => {
+                obj.insert("active".to_string(), false.to_json());
+                obj.insert("modified".to_string(), modified.to_json());
+            },
+            None => return not_found()
+        }
+        self.tickle_for(root_uri);
+        (StatusCode::Ok, Json::Object(Object::new()))
+    }
+
+    fn tickle_for(&mut self, root_uri: &str) {
+        match root_uri {
+            "pushes" => self.tickle("push"),
+            "devices" => self.tickle("device"),
+            _ => ()
+        }
+    }
+
+    fn upload_request(&mut self, body: &str) -> Reply {
+        let req = match Json::from_str(body) {
+            Ok(Json::Object(o)) => o,
+            _ => return bad_request("Invalid JSON body.")
+        };
+        let file_name = match get_str(&req, "file_name") {
+            Some(name) => name.to_string(),
+            None => return bad_request("Missing file_name.")
+        };
+        let file_type = get_str(&req, "file_type").unwrap_or("application/octet-stream").to_string();
+        self.serial += 1;
+        let root = self.base_url.trim_end_matches("v2/").to_string();
+        let file_url = format!("{}files/{}/{}", root, self.serial, file_name);
+        let upload_url = format!("{}upload/{}", root, self.serial);
+        self.files.insert(self.serial.to_string(), (file_type.clone(), None));
+
+        (StatusCode::Ok, Json::Object(obj! {
+            "file_name" => file_name,
+            "file_type" => file_type,
+            "file_url" => file_url,
+            "upload_url" => upload_url,
+            "data" => Json::Object(Object::new())
+        }))
+    }
+}
+
+impl State {
+    /// Accepts `multipart/form-data` upload of a file requested with `upload-request`.
+    fn upload(&mut self, id: &str, content_type: &str, body: &[u8]) -> StatusCode {
+        let boundary = match content_type.split("boundary=").nth(1) {
+            Some(b) => format!("--{}", b.trim_matches('"')),
+            None => return StatusCode::BadRequest
+        };
+        let start = match find_bytes(body, b"\r\n\r\n") {
+            Some(idx) => idx + 4,
+            None => return StatusCode::BadRequest
+        };
+        let end = match find_bytes(&body[start..], format!("\r\n{}", boundary).as_bytes()) {
+            Some(idx) => start + idx,
+            None => return StatusCode::BadRequest
+        };
+        match self.files.get_mut(id) {
+            Some(file) => {
+                file.1 = Some(body[start..end].to_vec());
+                StatusCode::NoContent
+            },
+            None => StatusCode::NotFound
+        }
+    }
+
+    fn download(&self, id: &str) -> Option<(String, Vec<u8>)> {
+        match self.files.get(id) {
+            Some(&(ref typ, Some(ref content))) => Some((typ.clone(), content.clone())),
+            _ => None
+        }
+    }
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+struct MockHandler(Arc<Mutex<State>>);
+
+impl Handler for MockHandler {
+    fn handle(&self, mut req: Request, mut res: Response) {
+        let mut body = Vec::new();
+        let _ = req.read_to_end(&mut body);
+
+        let uri = match req.uri {
+            RequestUri::AbsolutePath(ref path) => path.clone(),
+            _ => String::new()
+        };
+
+        // uploaded files are served outside of API and don't need authentication
+        if uri.starts_with("/upload/") {
+            let content_type = req.headers.get::<ContentType>().map(|c| c.to_string()).unwrap_or(String::new());
+            *res.status_mut() = self.0.lock().unwrap().upload(&uri["/upload/".len()..], &*content_type, &*body);
+            let _ = res.send(b"");
+            return;
+        }
+        if uri.starts_with("/files/") {
+            let download = self.0.lock().unwrap().download(uri["/files/".len()..].split('/').next().unwrap_or(""));
+            match download {
+                Some((typ, content)) => {
+                    res.headers_mut().set(ContentType(typ.parse().unwrap()));
+                    let _ = res.send(&*content);
+                },
+                None => {
+                    *res.status_mut() = StatusCode::NotFound;
+                    let _ = res.send(b"");
+                }
+            }
+            return;
+        }
+
+        let api_key = req.headers.get::<Authorization<Basic>>().map(|a| a.username.clone());
+        let (status, reply) = self.0.lock().unwrap().dispatch(&req.method, &*uri, api_key, &*String::from_utf8_lossy(&*body));
+
+        *res.status_mut() = status;
+        res.headers_mut().set(ContentType("application/json".parse().unwrap()));
+        let _ = res.send(json::encode(&reply).unwrap_or(String::new()).as_bytes());
+    }
+}
+
+fn serve_stream(listener: TcpListener, state: Arc<Mutex<State>>) {
+    for conn in listener.incoming() {
+        if let Ok(stream) = conn {
+            let state = state.clone();
+            thread::spawn(move || stream_session(stream, state));
+        }
+    }
+}
+
+fn stream_session(mut stream: TcpStream, state: Arc<Mutex<State>>) -> io::Result<()> {
+    let (path, key) = {
+        let mut reader = BufReader::new(&stream);
+        let mut request = String::new();
+        try!(reader.read_line(&mut request));
+
+        let mut key = None;
+        loop {
+            let mut line = String::new();
+            if try!(reader.read_line(&mut line)) == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some(idx) = line.find(':') {
+                if line[..idx].trim().to_lowercase() == "sec-websocket-key" {
+                    key = Some(line[idx + 1..].trim().to_string());
+                }
+            }
+        }
+        (request.split(' ').nth(1).unwrap_or("").to_string(), key)
+    };
+
+    let api_key = state.lock().unwrap().api_key.clone();
+    let key = match key {
+        Some(ref key) if path == format!("/websocket/{}", api_key) => key.clone(),
+        _ => return stream.write_all(b"HTTP/1.1 401 Unauthorized\r\nContent-Length: 0\r\n\r\n")
+    };
+
+    try!(write!(stream, "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n", accept_key(&*key)));
+
+    let (tx, rx) = channel();
+    state.lock().unwrap().listeners.push(tx);
+    try!(write_frame(&mut stream, "{\"type\":\"nop\"}"));
+    for msg in rx {
+        try!(write_frame(&mut stream, &*msg));
+    }
+    Ok(())
+}
+
+fn write_frame<W: Write>(stream: &mut W, text: &str) -> io::Result<()> {
+    let len = text.len();
+    let mut header = vec![0x81u8];
+    if len < 126 {
+        header.push(len as u8);
+    } else if len < 65536 {
+        header.push(126);
+        header.push((len >> 8) as u8);
+        header.push(len as u8);
+    } else {
+        header.push(127);
+        for i in (0..8).rev() {
+            header.push((len as u64 >> (i * 8)) as u8);
+        }
+    }
+    try!(stream.write_all(&*header));
+    try!(stream.write_all(text.as_bytes()));
+    stream.flush()
+}
+
+fn accept_key(key: &str) -> String {
+    sha1(format!("{}{}", key, WS_GUID).as_bytes()).to_base64(STANDARD)
+}
+
+fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    let mut msg = data.to_vec();
+    let bits = (data.len() as u64) * 8;
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    for i in (0..8).rev() {
+        msg.push((bits >> (i * 8)) as u8);
+    }
+
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for i in 0..16 {
+            w[i] = (chunk[i * 4] as u32) << 24 | (chunk[i * 4 + 1] as u32) << 16 | (chunk[i * 4 + 2] as u32) << 8 | chunk[i * 4 + 3] as u32;
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let (mut a, mut b, mut c, mut d, mut e) = (h[0], h[1], h[2], h[3], h[4]);
+        for i in 0..80 {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6)
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k).wrapping_add(w[i]);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = t;
+        }
+
+        h[0] = h[0].wrapping_add(a);
+        h[1] = h[1].wrapping_add(b);
+        h[2] = h[2].wrapping_add(c);
+        h[3] = h[3].wrapping_add(d);
+        h[4] = h[4].wrapping_add(e);
+    }
+
+    let mut out = [0u8; 20];
+    for (i, word) in h.iter().enumerate() {
+        for j in 0..4 {
+            out[i * 4 + j] = (word >> (24 - j * 8)) as u8;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MockServer, accept_key};
+    use hyper::status::StatusCode;
+    use rustc_serialize::json::Json;
+    use api::PbAPI;
+    use events::Event;
+    use messages::{PushMsg, TargetIden};
+
+    #[test]
+    fn test_accept_key() {
+        // example from RFC 6455
+        assert_eq!(accept_key("dGhlIHNhbXBsZSBub25jZQ=="), "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+    }
+
+    #[test]
+    fn test_injected_failure_after_auth() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut api = PbAPI::new("wrong-key").base_url(&*server.base_url());
+        server.fail_next_reply(StatusCode::InternalServerError);
+        assert!(api.send(&PushMsg::new(TargetIden::CurrentUser).title("Sneaky")).is_err());
+        assert!(server.objects("pushes").is_empty());
+    }
+
+    #[test]
+    fn test_stream_tickles() {
+        let server = MockServer::start("mock-key").unwrap();
+        let mut stream = server.api().stream().unwrap();
+        assert_eq!(stream.next_event().unwrap(), Some(Event::Nop));
+
+        server.api().send(&PushMsg::new(TargetIden::CurrentUser).title("Hello")).unwrap();
+        assert_eq!(stream.next_event().unwrap(), Some(Event::PushTickle));
+
+        server.api().ensure_device("build-01").unwrap();
+        assert_eq!(stream.next_event().unwrap(), Some(Event::DeviceTickle));
+
+        let mirror = Json::from_str("{\"type\":\"mirror\",\"title\":\"Missed call\"}").unwrap();
+        server.ephemeral(mirror.clone());
+        assert_eq!(stream.next_event().unwrap(), Some(Event::Ephemeral(mirror)));
+    }
+}
@@ -0,0 +1,370 @@
+//! Deduplication and throttling of alert pushes.
+
+use std::collections::BTreeMap;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use api::{PbAPI, PbResult};
+use jsonfile::{self, Lock};
+use messages::{PushMsg, TargetIden};
+use objects::{Error, Push, Timestamp};
+
+/// Alerts with the same key seen recently.
+#[derive(Debug, Clone, PartialEq, RustcEncodable, RustcDecodable)]
+struct Entry {
+    target: String,
+    title: String,
+    /// When the last alert with this key was actually sent.
+    sent: Timestamp,
+    /// Repeats suppressed since the last digest.
+    suppressed: usize,
+}
+
+#[derive(Debug, Default, PartialEq, RustcEncodable, RustcDecodable)]
+struct State {
+    last_digest: Timestamp,
+    entries: BTreeMap<String, Entry>,
+}
+
+/// Suppresses repeated alerts: a push with the same target, title and caller key
+/// as one sent less than `window` ago is not sent. Numbers of suppressed repeats
+/// are sent instead as "N more like this" digest pushes, at most once per
+/// digest interval (the window by default).
+///
+/// State is kept in memory, or in a JSON file to survive restarts.
+#[derive(Debug)]
+pub struct Throttle {
+    window: Duration,
+    digest_interval: Duration,
+    path: Option<PathBuf>,
+    state: State,
+}
+
+impl State {
+    fn suppressed(&self) -> usize {
+        self.entries.values().map(|e| e.suppressed).sum()
+    }
+}
+
+/// Outcome of `Throttle::send`.
+#[derive(Debug)]
+pub struct Sent {
+    /// The alert, `None` if it repeated a recent one.
+    pub push: Option<Push>,
+    /// Digests sent before the alert, or why they failed. A failed digest
+    /// doesn't stop the alert, its repeats are reported by the next digest.
+    pub digests: PbResult<Vec<Push>>,
+}
+
+impl Throttle {
+    pub fn in_memory(window: Duration) -> Throttle {
+        Throttle {
+            window: window,
+            digest_interval: window,
+            path: None,
+            state: State::default(),
+        }
+    }
+
+    /// Keeps state in `path`, loading it if the file exists. The file may be
+    /// shared by several processes, each change is made to its current content.
+    pub fn open<P: AsRef<Path>>(path: P, window: Duration) -> PbResult<Throttle> {
+        let mut throttle = Throttle::in_memory(window);
+        throttle.state = try!(jsonfile::load(path.as_ref())).unwrap_or_default();
+        throttle.path = Some(path.as_ref().to_path_buf());
+        Ok(throttle)
+    }
+
+    pub fn digest_every(mut self, interval: Duration) -> Throttle {
+        self.digest_interval = interval;
+        self
+    }
+
+    /// Number of repeats suppressed and not yet reported in a digest.
+    pub fn suppressed(&self) -> usize {
+        self.state.suppressed()
+    }
+
+    /// Whether a digest of suppressed repeats should be sent.
+    pub fn is_due(&self) -> bool {
+        self.is_due_at(Timestamp::now())
+    }
+
+    fn is_due_at(&self, now: Timestamp) -> bool {
+        self.suppressed() > 0 && now - self.digest_interval >= self.state.last_digest
+    }
+
+    /// Sends digests if they are due, for callers flushing on a timer.
+    pub fn tick(&mut self, api: &mut PbAPI) -> PbResult<Vec<Push>> {
+        self.tick_at(api, Timestamp::now())
+    }
+
+    fn tick_at(&mut self, api: &mut PbAPI, now: Timestamp) -> PbResult<Vec<Push>> {
+        let interval = self.digest_interval;
+        self.digest_at(api, now, |state| state.suppressed() > 0 && now - interval >= state.last_digest)
+    }
+
+    /// Sends `msg` unless it repeats a recent alert. `key` tells apart alerts
+    /// with the same title, e.g. names of checks. Sends a digest first if one is due.
+    pub fn send(&mut self, api: &mut PbAPI, msg: &PushMsg, key: Option<&str>) -> PbResult<Sent> {
+        self.send_at(api, msg, key, Timestamp::now())
+    }
+
+    fn send_at(&mut self, api: &mut PbAPI, msg: &PushMsg, key: Option<&str>, now: Timestamp) -> PbResult<Sent> {
+        let interval = self.digest_interval;
+        let digests = self.digest_at(api, now, |state| now - interval >= state.last_digest);
+
+        let target = msg.target.key();
+        let title = msg.title.as_ref().map(|t| t.to_string()).unwrap_or(String::new());
+        let dedupe_key = format!("{}\n{}\n{}", target, title, key.unwrap_or(""));
+        let entry = Entry {
+            target: target,
+            title: title,
+            sent: now,
+            suppressed: 0,
+        };
+
+        // the alert is recorded as sent before sending it, so that other processes suppress it meanwhile
+        let window = self.window;
+        let claimed = try!(self.update(|state| match state.entries.get_mut(&dedupe_key) {
+            Some(ref mut entry) if now - window < entry.sent => {
+                entry.suppressed += 1;
+                None
+            },
+            Some(entry) => Some(mem::replace(&mut entry.sent, now)),
+            None => {
+                state.entries.insert(dedupe_key.clone(), entry);
+                Some(Timestamp::default())
+            }
+        }));
+        let previous = match claimed {
+            Some(previous) => previous,
+            None => return Ok(Sent { push: None, digests: digests })
+        };
+
+        match api.send(msg) {
+            Ok(push) => Ok(Sent { push: Some(push), digests: digests }),
+            Err(e) => {
+                try!(self.update(|state| if let Some(entry) = state.entries.get_mut(&dedupe_key) {
+                    if entry.sent == now {
+                        entry.sent = previous;
+                    }
+                }));
+                Err(e)
+            }
+        }
+    }
+
+    /// Sends digests of suppressed repeats right away, one push per target.
+    /// Repeats of digests which fail are kept for the next digest.
+    pub fn flush(&mut self, api: &mut PbAPI) -> PbResult<Vec<Push>> {
+        self.flush_at(api, Timestamp::now())
+    }
+
+    fn flush_at(&mut self, api: &mut PbAPI, now: Timestamp) -> PbResult<Vec<Push>> {
+        self.digest_at(api, now, |_| true)
+    }
+
+    /// Sends digests if `due` holds for the current state.
+    fn digest_at<F: FnOnce(&State) -> bool>(&mut self, api: &mut PbAPI, now: Timestamp, due: F) -> PbResult<Vec<Push>> {
+        // repeats are taken out of the state before sending, so that other processes don't report them too
+        let window = self.window;
+        let digests = try!(self.update(|state| {
+            let mut digests: BTreeMap<String, Vec<(String, Entry)>> = BTreeMap::new();
+            if !due(state) {
+                return digests;
+            }
+            for (key, entry) in state.entries.iter_mut().filter(|&(_, ref e)| e.suppressed > 0) {
+                digests.entry(entry.target.clone()).or_insert_with(Vec::new).push((key.clone(), entry.clone()));
+                entry.suppressed = 0;
+            }
+
+            // forget alerts which can't be suppressed anymore and have nothing to report
+            state.entries = mem::replace(&mut state.entries, BTreeMap::new()).into_iter()
+                .filter(|&(_, ref e)| now - window < e.sent)
+                .collect();
+            state.last_digest = now;
+            digests
+        }));
+
+        let mut sent = Vec::new();
+        let mut failed = Vec::new();
+        for (target, alerts) in digests {
+            match send_digest(api, &*target, &alerts) {
+                Ok(push) => sent.push(push),
+                Err(e) => failed.push((alerts, e))
+            }
+        }
+
+        let mut errors = Vec::new();
+        if !failed.is_empty() {
+            try!(self.update(|state| for (alerts, e) in failed {
+                for (key, entry) in alerts {
+                    let suppressed = entry.suppressed;
+                    state.entries.entry(key).or_insert(Entry { suppressed: 0, ..entry }).suppressed += suppressed;
+                }
+                errors.push(e);
+            }));
+        }
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(sent)
+        }
+    }
+
+    /// Applies `f` to the current state in the file and saves it,
+    /// holding the file locked in between.
+    fn update<T, F: FnOnce(&mut State) -> T>(&mut self, f: F) -> PbResult<T> {
+        let path = match self.path {
+            Some(ref path) => path.clone(),
+            None => return Ok(f(&mut self.state))
+        };
+        let _lock = try!(Lock::acquire(&path));
+        self.state = try!(jsonfile::load(&path)).unwrap_or_default();
+        let result = f(&mut self.state);
+        try!(jsonfile::save(&path, &self.state));
+        Ok(result)
+    }
+}
+
+fn send_digest(api: &mut PbAPI, target: &str, alerts: &[(String, Entry)]) -> PbResult<Push> {
+    let lines: Vec<(String, usize)> = alerts.iter().map(|&(_, ref e)| (e.title.clone(), e.suppressed)).collect();
+    let title = match lines.len() {
+        1 => digest_line(&lines[0]),
+        _ => format!("{} more alerts", lines.iter().map(|&(_, n)| n).sum::<usize>())
+    };
+    let body = lines.iter().map(digest_line).collect::<Vec<_>>().join("\n");
+    let to = try!(TargetIden::from_key(target)
+        .ok_or_else(|| Error::new("invalid_target", &*format!("invalid target {:?} in throttle state", target))));
+    api.send(&PushMsg::new(to).title(title).body(body))
+}
+
+fn digest_line(alert: &(String, usize)) -> String {
+    if alert.0.is_empty() {
+        format!("{} more untitled alerts", alert.1)
+    } else {
+        format!("{} more like \"{}\"", alert.1, alert.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+    use uuid::Uuid;
+    use testing::MockServer;
+    use objects::Timestamp;
+    use messages::{PushMsg, TargetIden};
+    use super::Throttle;
+
+    fn alert(title: &str) -> PushMsg<'static> {
+        PushMsg::new(TargetIden::ChannelTag("ops".to_string())).title(title.to_string()).body("flapping")
+    }
+
+    fn titles(server: &MockServer) -> Vec<String> {
+        server.objects("pushes").iter().map(|p| p["title"].as_string().unwrap().to_string()).collect()
+    }
+
+    #[test]
+    fn test_throttle() {
+        let server = MockServer::start("mock-key").unwrap();
+        server.api().send(&::messages::ChannelMsg::new("ops", "Ops", "Alerts")).unwrap();
+        let mut api = server.api();
+        let path = env::temp_dir().join(format!("pb-test-throttle-{}.json", Uuid::new_v4().simple()));
+        let t0 = Timestamp::now();
+        let at = |secs: u64| t0 + Duration::from_secs(secs);
+
+        let mut throttle = Throttle::open(&path, Duration::from_secs(600)).unwrap().digest_every(Duration::from_secs(3600));
+        assert!(throttle.send_at(&mut api, &alert("Disk full"), None, at(0)).unwrap().push.is_some());
+        assert!(throttle.send_at(&mut api, &alert("Disk full"), Some("db-02"), at(1)).unwrap().push.is_some());
+        for i in 0..5 {
+            assert!(throttle.send_at(&mut api, &alert("Disk full"), None, at(10 + i)).unwrap().push.is_none());
+        }
+        assert!(throttle.send_at(&mut api, &alert("Load high"), None, at(20)).unwrap().push.is_some());
+        assert!(throttle.send_at(&mut api, &alert("Load high"), None, at(30)).unwrap().push.is_none());
+        assert_eq!(throttle.suppressed(), 6);
+
+        // state survives restarts
+        let mut throttle = Throttle::open(&path, Duration::from_secs(600)).unwrap().digest_every(Duration::from_secs(3600));
+        assert!(throttle.send_at(&mut api, &alert("Disk full"), None, at(40)).unwrap().push.is_none());
+        // window has passed
+        assert!(throttle.send_at(&mut api, &alert("Disk full"), None, at(700)).unwrap().push.is_some());
+        assert_eq!(titles(&server).len(), 4);
+
+        // digest is due
+        assert!(throttle.send_at(&mut api, &alert("Load high"), None, at(3700)).unwrap().push.is_some());
+        assert_eq!(titles(&server)[4..].to_vec(), vec!["7 more alerts".to_string(), "Load high".to_string()]);
+        assert_eq!(server.objects("pushes")[4]["body"].as_string(), Some("6 more like \"Disk full\"\n1 more like \"Load high\""));
+        assert_eq!(throttle.suppressed(), 0);
+        assert!(throttle.flush(&mut api).unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_throttle_tick() {
+        let server = MockServer::start("mock-key").unwrap();
+        server.api().send(&::messages::ChannelMsg::new("ops", "Ops", "Alerts")).unwrap();
+        let mut api = server.api();
+        let t0 = Timestamp::now();
+        let at = |secs: u64| t0 + Duration::from_secs(secs);
+
+        let mut throttle = Throttle::in_memory(Duration::from_secs(600));
+        assert!(throttle.send_at(&mut api, &alert("Disk full"), None, at(0)).unwrap().push.is_some());
+        assert!(throttle.send_at(&mut api, &alert("Disk full"), None, at(10)).unwrap().push.is_none());
+        assert!(!throttle.is_due_at(at(599)));
+        assert!(throttle.tick_at(&mut api, at(599)).unwrap().is_empty());
+
+        // no more alerts come, but the digest still goes out
+        assert!(throttle.is_due_at(at(600)));
+        let digests = throttle.tick_at(&mut api, at(600)).unwrap();
+        assert_eq!(digests[0].title, Some("1 more like \"Disk full\"".to_string()));
+        assert!(!throttle.is_due_at(at(1300)));
+    }
+
+    #[test]
+    fn test_failed_digest() {
+        let server = MockServer::start("mock-key").unwrap();
+        server.api().send(&::messages::ChannelMsg::new("ops", "Ops", "Alerts")).unwrap();
+        let mut api = server.api();
+        let phone = api.send(&::messages::DeviceMsg::new("phone")).unwrap().iden().clone();
+        let disk_full = PushMsg::new(TargetIden::DeviceIden(phone.clone())).title("Disk full");
+        let t0 = Timestamp::now();
+        let at = |secs: u64| t0 + Duration::from_secs(secs);
+
+        let mut throttle = Throttle::in_memory(Duration::from_secs(600));
+        assert!(throttle.send_at(&mut api, &disk_full, None, at(0)).unwrap().push.is_some());
+        assert!(throttle.send_at(&mut api, &disk_full, None, at(10)).unwrap().push.is_none());
+
+        // the digest can't go to the deleted device, other alerts still do
+        api.remove::<::objects::Device>(phone).unwrap();
+        let sent = throttle.send_at(&mut api, &alert("Load high"), None, at(700)).unwrap();
+        assert!(sent.push.is_some());
+        assert!(sent.digests.is_err());
+        assert_eq!(throttle.suppressed(), 1);
+    }
+
+    #[test]
+    fn test_shared_state() {
+        let server = MockServer::start("mock-key").unwrap();
+        server.api().send(&::messages::ChannelMsg::new("ops", "Ops", "Alerts")).unwrap();
+        let mut api = server.api();
+        let path = env::temp_dir().join(format!("pb-test-throttle-{}.json", Uuid::new_v4().simple()));
+        let t0 = Timestamp::now();
+        let at = |secs: u64| t0 + Duration::from_secs(secs);
+
+        // both are open before either sends, yet the second one sees the first alert
+        let mut first = Throttle::open(&path, Duration::from_secs(600)).unwrap();
+        let mut second = Throttle::open(&path, Duration::from_secs(600)).unwrap();
+        assert!(first.send_at(&mut api, &alert("Disk full"), None, at(0)).unwrap().push.is_some());
+        assert!(second.send_at(&mut api, &alert("Disk full"), None, at(10)).unwrap().push.is_none());
+        assert!(first.send_at(&mut api, &alert("Disk full"), None, at(20)).unwrap().push.is_none());
+        assert_eq!(first.suppressed(), 2);
+
+        // a digest sent by one isn't sent again by the other
+        assert_eq!(first.flush_at(&mut api, at(30)).unwrap().len(), 1);
+        assert!(second.flush_at(&mut api, at(40)).unwrap().is_empty());
+        fs::remove_file(&path).unwrap();
+    }
+}